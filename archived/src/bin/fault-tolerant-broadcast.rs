@@ -37,7 +37,7 @@ struct BroadcastNode {
 }
 
 impl GossipingNode<BroadcastRequest, BroadcastResponse> for BroadcastNode {
-    fn gossip(&self, neighbours: &Vec<String>, output: &mut dyn Write) -> Result<(), anyhow::Error> {
+    fn gossip(&self, neighbours: &[String], output: &mut dyn Write) -> Result<(), anyhow::Error> {
         for node_id in neighbours {
             let known_by_node = &self.known_by_neighbours[node_id];
             let message: Message<BroadcastRequest, BroadcastResponse> = Message {
@@ -82,7 +82,7 @@ impl Node<(), BroadcastRequest, BroadcastResponse, Injected> for BroadcastNode {
             .context("couldn't parse node number from node_id")?;
 
         // node is the leader if it's the first in the chunk
-        let is_leader = node_no % neighbourhood_size == 0;
+        let is_leader = node_no.is_multiple_of(neighbourhood_size);
 
         let mut node_ids: Vec<String> = init.node_ids;
         node_ids.sort_by(|a, b| {
@@ -155,6 +155,8 @@ impl Node<(), BroadcastRequest, BroadcastResponse, Injected> for BroadcastNode {
                 // kinda ugly returning like this
                 return Ok(());
             }
+            // Gossip isn't acknowledged, so there are no requests to time out
+            Event::Timeout(_) => return Ok(()),
         };
         let Payload::Request(request) = msg.body.payload else {
             return Ok(())
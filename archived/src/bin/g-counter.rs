@@ -1,23 +1,19 @@
-use std::{
-    collections::{HashMap, HashSet},
-    io::{StdoutLock, Write},
-    time::Duration,
-};
+use std::io::Write;
 
-use anyhow::Context;
-use gossip_glomers::{Body, Event, Message, Node, Payload};
+use gossip_glomers::{
+    Body, Event, KvClient, KvError, KvService, Message, MessageId, Node, Payload, Rpc,
+};
+use log::{debug, info};
 use serde::{Deserialize, Serialize};
 
+static COUNTER_KEY: &str = "counter";
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 enum GOCounterRequest {
     Add { delta: usize },
     Read,
-    // #[serde(rename = "read_ok")]
-    // KVReadOk,
-    // #[serde(rename = "error")]
-    // KVError
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -28,22 +24,128 @@ enum GOCounterResponse {
     ReadOk { value: usize },
 }
 
-#[derive(Debug)]
 struct GOCounterNode {
     node_id: String,
+    rpc: Rpc<GOCounterNode, GOCounterRequest, GOCounterResponse>,
+    kv: KvClient,
+    /// How many times the node synced with seq-kv, which makes every sync write a new value
+    syncs: usize,
+}
+
+impl GOCounterNode {
+    fn reply(
+        &self,
+        dest: String,
+        in_reply_to: Option<MessageId>,
+        payload: GOCounterResponse,
+        output: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        let reply: Message<GOCounterRequest, GOCounterResponse> = Message {
+            src: self.node_id.clone(),
+            dest,
+            body: Body {
+                msg_id: None,
+                in_reply_to,
+                payload: Payload::Response(payload),
+            },
+        };
+        serde_json::to_writer(&mut *output, &reply)?;
+        output.write_all(b"\n")?;
+        Ok(())
+    }
+
+    // Reads the counter once seq-kv has ordered a write of ours before the read. seq-kv may
+    // serve a node an old value for as long as it likes, but not one older than the node's own
+    // writes, so writing a value nobody wrote before brings the node up to date.
+    fn read_counter<F>(&mut self, output: &mut dyn Write, callback: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut GOCounterNode, Result<usize, KvError>, &mut dyn Write) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    {
+        self.syncs += 1;
+        let sync_key = format!("sync/{}", self.node_id);
+        let kv = self.kv.clone();
+        kv.write(
+            &mut self.rpc,
+            sync_key,
+            self.syncs,
+            output,
+            move |node: &mut GOCounterNode, result, output| {
+                if let Err(e) = result {
+                    info!("couldn't sync with seq-kv: {}", e);
+                    return Ok(());
+                }
+                let kv = node.kv.clone();
+                kv.read(&mut node.rpc, COUNTER_KEY, output, callback)?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    // Reads the counter and tries to cas it to the new value, starting over if someone else
+    // changed it in between
+    fn add(
+        &mut self,
+        client: String,
+        in_reply_to: Option<MessageId>,
+        delta: usize,
+        output: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        self.read_counter(
+            output,
+            move |node: &mut GOCounterNode, current: Result<usize, KvError>, output| {
+                let current = match current {
+                    Ok(current) => current,
+                    Err(KvError::KeyDoesNotExist) => 0,
+                    Err(e) => {
+                        // We don't reply, the client will time out
+                        info!("couldn't read counter: {}", e);
+                        return Ok(());
+                    }
+                };
+                let kv = node.kv.clone();
+                kv.cas(
+                    &mut node.rpc,
+                    COUNTER_KEY,
+                    current,
+                    current + delta,
+                    true,
+                    output,
+                    move |node: &mut GOCounterNode, result, output| match result {
+                        Ok(()) => node.reply(client, in_reply_to, GOCounterResponse::AddOk, output),
+                        Err(KvError::PreconditionFailed(text)) => {
+                            debug!("retrying add: {}", text);
+                            node.add(client, in_reply_to, delta, output)
+                        }
+                        Err(e) => {
+                            info!("couldn't update counter: {}", e);
+                            Ok(())
+                        }
+                    },
+                )?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
 }
 
 impl Node<(), GOCounterRequest, GOCounterResponse> for GOCounterNode {
     fn from_init(
         _state: (),
         init: gossip_glomers::Init,
-        _tx: std::sync::mpsc::Sender<Event<GOCounterRequest, GOCounterResponse, ()>>,
+        tx: std::sync::mpsc::Sender<Event<GOCounterRequest, GOCounterResponse, ()>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
         let node = GOCounterNode {
-            node_id: init.node_id,
+            node_id: init.node_id.clone(),
+            rpc: Rpc::new(init.node_id, tx),
+            kv: KvClient::new(KvService::SeqKv),
+            syncs: 0,
         };
         Ok(node)
     }
@@ -53,45 +155,51 @@ impl Node<(), GOCounterRequest, GOCounterResponse> for GOCounterNode {
         msg: Event<GOCounterRequest, GOCounterResponse, ()>,
         output: &mut dyn Write,
     ) -> anyhow::Result<()> {
-        let kv_read_message = format!(r#"{{"src":"{}","dest":"seq-kv","body":{{"type":"read"}}}}"#, self.node_id);
-        output.write_all(kv_read_message.as_bytes())?;
-        eprintln!("sent kv read message");
         let msg = match msg {
             Event::Message(msg) => msg,
             Event::Injected(_) => {
                 panic!("Received unexpected injected message")
             }
+            // The client times out too, and retries if it wants to
+            Event::Timeout(msg_id) => {
+                info!("kv request {} timed out", msg_id);
+                return Ok(());
+            }
         };
         let Payload::Request(request) = msg.body.payload else {
             return Ok(())
         };
-        let reply_payload = match request {
-            GOCounterRequest::Add { .. } => {
-                None
-            }
+        match request {
+            GOCounterRequest::Add { delta } => self.add(msg.src, msg.body.msg_id, delta, output),
             GOCounterRequest::Read => {
-                // Send read request to the KV thingie
-                // but we need the response from the KV service to get back to the client
-                // and it will arrive to stdin just as the client calls
-                // it feels to me like we do need some kind of state to store the read requests to which we need to reply
-                // let message = format!(r#"{{"src":"{}","dest":"seq-kv","body":{{"type":"read"}}}}"#, self.node_id);
-                None
-            },
-        };
-        if let Some(reply_payload) = reply_payload {
-            let reply: Message<GOCounterRequest, GOCounterResponse> = Message {
-                src: self.node_id.clone(),
-                dest: msg.src,
-                body: Body {
-                    msg_id: None,
-                    in_reply_to: msg.body.msg_id, //This will not be there for the kv comms
-                    payload: reply_payload,
-                },
-            };
-            serde_json::to_writer(&mut *output, &reply)?;
-            output.write_all(b"\n")?;
+                let (client, in_reply_to) = (msg.src, msg.body.msg_id);
+                self.read_counter(
+                    output,
+                    move |node: &mut GOCounterNode, value: Result<usize, KvError>, output| {
+                        let value = match value {
+                            Ok(value) => value,
+                            // Nobody added anything yet
+                            Err(KvError::KeyDoesNotExist) => 0,
+                            Err(e) => {
+                                info!("couldn't read counter: {}", e);
+                                return Ok(());
+                            }
+                        };
+                        node.reply(
+                            client,
+                            in_reply_to,
+                            GOCounterResponse::ReadOk { value },
+                            output,
+                        )
+                    },
+                )?;
+                Ok(())
+            }
         }
-        Ok(())
+    }
+
+    fn rpc(&mut self) -> Option<&mut Rpc<Self, GOCounterRequest, GOCounterResponse>> {
+        Some(&mut self.rpc)
     }
 }
 
@@ -76,7 +76,7 @@ impl Node<(), KafkaRequest, KafkaResponse> for KafkaNode {
             KafkaRequest::Send { key, msg } => {
                 debug!("Received send request. key: {}, msg: {}", key, msg);
                 // create entry if not exists
-                let msgs = self.logs.entry(key.clone()).or_insert_with(|| Vec::new());
+                let msgs = self.logs.entry(key.clone()).or_default();
                 // len + 1 as we still haven't pushed the new message
                 let offset = msgs.len() + 1;
                 debug!("Will send back offset: {}", offset);
@@ -95,8 +95,7 @@ impl Node<(), KafkaRequest, KafkaResponse> for KafkaNode {
                         if let Some((first_to_return_idx, _)) = msgs
                             .iter()
                             .enumerate()
-                            .filter(|(_, (o, _))| o >= offset)
-                            .next()
+                            .find(|(_, (o, _))| o >= offset)
                         {
                             resp_msgs.insert(
                                 key.clone(),
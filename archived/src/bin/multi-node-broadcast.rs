@@ -86,7 +86,7 @@ impl Node<(), BroadcastRequest, BroadcastResponse, ()> for BroadcastNode {
         };
         let request = match msg.body.payload {
             Payload::Request(request) => request,
-            Payload::Response(_) => return Ok(()),
+            _ => return Ok(()),
         };
         let reply_payload = match request {
             BroadcastRequest::Broadcast { message } => {
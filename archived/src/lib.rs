@@ -1,6 +1,10 @@
 use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
     io::{BufRead, Write},
-    sync::mpsc::Sender
+    marker::PhantomData,
+    sync::mpsc::{RecvTimeoutError, Sender},
+    time::{Duration, Instant},
 };
 
 use env_logger::Builder;
@@ -16,6 +20,8 @@ pub type MessageId = usize;
 pub enum Event<Req, Res, Inj> {
     Message(Message<Req, Res>),
     Injected(Inj),
+    /// A request sent with [`Rpc::call`] got no reply in time. Its callback has been dropped.
+    Timeout(MessageId),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -40,6 +46,18 @@ pub struct Body<Req, Res> {
 pub enum Payload<Req, Res> {
     Request(Req),
     Response(Res),
+    /// A Maelstrom error reply, from another node or a service
+    Error(ErrorBody),
+    /// A reply from one of the KV services
+    Kv(KvResponse),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "error")]
+pub struct ErrorBody {
+    pub code: u32,
+    #[serde(default)]
+    pub text: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -66,14 +84,14 @@ impl<Req, Res> Payload<Req, Res> {
     pub fn request(self) -> Option<Req> {
         match self {
             Payload::Request(request) => Some(request),
-            Payload::Response(_) => None,
+            _ => None,
         }
     }
 
     pub fn response(self) -> Option<Res> {
         match self {
-            Payload::Request(_) => None,
             Payload::Response(response) => Some(response),
+            _ => None,
         }
     }
 }
@@ -88,10 +106,440 @@ where
         Self: Sized;
 
     fn step(&mut self, msg: Event<Req, Res, Inj>, output: &mut dyn Write) -> anyhow::Result<()>;
+
+    /// Nodes that send requests with an [`Rpc`] return it here, so that the runtime can route
+    /// replies to their callbacks instead of to `step`.
+    fn rpc(&mut self) -> Option<&mut Rpc<Self, Req, Res, Inj>>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+/// Called with the node and the reply when a response to an [`Rpc::call`] arrives.
+pub type Callback<N, Req, Res> =
+    Box<dyn FnOnce(&mut N, Message<Req, Res>, &mut dyn Write) -> anyhow::Result<()> + Send>;
+
+/// Sends requests to other nodes or services and keeps track of the ones still waiting for a
+/// reply.
+pub struct Rpc<N, Req, Res, Inj = ()> {
+    node_id: String,
+    msg_id: MessageId,
+    outstanding: HashMap<MessageId, Callback<N, Req, Res>>,
+    /// The deadlines of the requests sent, for the timer thread
+    deadlines: Sender<(Instant, MessageId)>,
+    _inj: PhantomData<Inj>,
+}
+
+impl<N, Req, Res, Inj> Rpc<N, Req, Res, Inj>
+where
+    Req: Serialize + Send + 'static,
+    Res: Serialize + Send + 'static,
+    Inj: Send + 'static,
+{
+    /// `tx` is the sender the node got in `from_init`. Timeouts are sent through it, by a
+    /// single timer thread for all the requests.
+    pub fn new(node_id: String, tx: Sender<Event<Req, Res, Inj>>) -> Self {
+        Rpc {
+            node_id,
+            msg_id: 0,
+            outstanding: HashMap::new(),
+            deadlines: spawn_timer(tx),
+            _inj: PhantomData,
+        }
+    }
+
+    pub fn next_msg_id(&mut self) -> MessageId {
+        self.msg_id += 1;
+        self.msg_id
+    }
+
+    /// Sends `request` to `dest` and registers `callback` to handle the reply. If there is no
+    /// reply within `timeout`, the node gets an [`Event::Timeout`] with the returned id instead.
+    ///
+    /// `request` doesn't have to be one of the node's own requests: [`KvClient`] sends
+    /// [`KvRequest`]s through here.
+    pub fn call<T, F>(
+        &mut self,
+        dest: &str,
+        request: T,
+        timeout: Duration,
+        output: &mut dyn Write,
+        callback: F,
+    ) -> anyhow::Result<MessageId>
+    where
+        T: Serialize,
+        F: FnOnce(&mut N, Message<Req, Res>, &mut dyn Write) -> anyhow::Result<()> + Send + 'static,
+    {
+        let msg_id = self.next_msg_id();
+        let message: Message<T, Res> = Message {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload: Payload::Request(request),
+            },
+        };
+        serde_json::to_writer(&mut *output, &message).context("error writing request")?;
+        output.write_all(b"\n").context("error writing newline")?;
+        self.outstanding.insert(msg_id, Box::new(callback));
+
+        // The timer thread only stops once the Rpc is gone
+        let _ = self.deadlines.send((Instant::now() + timeout, msg_id));
+        Ok(msg_id)
+    }
+
+    /// Number of requests still waiting for a reply
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    fn take(&mut self, msg_id: MessageId) -> Option<Callback<N, Req, Res>> {
+        self.outstanding.remove(&msg_id)
+    }
+}
+
+/// Starts the thread that sends an [`Event::Timeout`] through `tx` for each request whose
+/// deadline passes, and returns where to send it the deadlines. It keeps them in a heap and
+/// sleeps until the earliest, or until a new one comes in.
+fn spawn_timer<Req, Res, Inj>(tx: Sender<Event<Req, Res, Inj>>) -> Sender<(Instant, MessageId)>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+    Inj: Send + 'static,
+{
+    let (deadlines_tx, deadlines) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut pending: BinaryHeap<Reverse<(Instant, MessageId)>> = BinaryHeap::new();
+        loop {
+            let next = match pending.peek() {
+                None => deadlines.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(Reverse((at, _))) => {
+                    deadlines.recv_timeout(at.saturating_duration_since(Instant::now()))
+                }
+            };
+            match next {
+                Ok(deadline) => pending.push(Reverse(deadline)),
+                Err(RecvTimeoutError::Timeout) => {}
+                // The Rpc is gone, and with it whoever would handle the timeouts
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            let now = Instant::now();
+            while let Some(Reverse((at, msg_id))) = pending.peek().copied() {
+                if at > now {
+                    break;
+                }
+                pending.pop();
+                if tx.send(Event::Timeout(msg_id)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    deadlines_tx
+}
+
+/// Hands an event to the callback waiting for it, or to the node's `step`
+fn dispatch<S, N, Req, Res, Inj>(
+    node: &mut N,
+    event: Event<Req, Res, Inj>,
+    output: &mut dyn Write,
+) -> anyhow::Result<()>
+where
+    N: Node<S, Req, Res, Inj>,
+    Req: Serialize + DeserializeOwned + Send + 'static,
+    Res: Serialize + DeserializeOwned + Send + 'static,
+    Inj: Send + 'static,
+{
+    match event {
+        Event::Message(msg) => {
+            let callback = msg
+                .body
+                .in_reply_to
+                .and_then(|id| node.rpc().and_then(|rpc| rpc.take(id)));
+            match callback {
+                Some(callback) => callback(node, msg, output),
+                None => node.step(Event::Message(msg), output),
+            }
+        }
+        Event::Timeout(msg_id) => {
+            // The reply beat the timer
+            if node.rpc().and_then(|rpc| rpc.take(msg_id)).is_none() {
+                return Ok(());
+            }
+            node.step(Event::Timeout(msg_id), output)
+        }
+        event => node.step(event, output),
+    }
 }
 
 pub trait GossipingNode<Req, Res> {
-    fn gossip(&self, node_ids: &Vec<String>, output: &mut dyn Write) -> anyhow::Result<()>;
+    fn gossip(&self, node_ids: &[String], output: &mut dyn Write) -> anyhow::Result<()>;
+}
+
+/// The KV services Maelstrom provides
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvService {
+    SeqKv,
+    LinKv,
+    LwwKv,
+}
+
+impl KvService {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KvService::SeqKv => "seq-kv",
+            KvService::LinKv => "lin-kv",
+            KvService::LwwKv => "lww-kv",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvRequest {
+    Read {
+        key: serde_json::Value,
+    },
+    Write {
+        key: serde_json::Value,
+        value: serde_json::Value,
+    },
+    Cas {
+        key: serde_json::Value,
+        from: serde_json::Value,
+        to: serde_json::Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvResponse {
+    ReadOk { value: serde_json::Value },
+    WriteOk,
+    CasOk,
+}
+
+/// Why a KV request failed, from the error codes Maelstrom defines
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvError {
+    Timeout,
+    TemporarilyUnavailable,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed(String),
+    Other {
+        code: u32,
+        text: String,
+    },
+    /// The reply wasn't one a KV service sends, or its value wasn't of the type asked for
+    UnexpectedReply(String),
+}
+
+impl From<ErrorBody> for KvError {
+    fn from(error: ErrorBody) -> Self {
+        match error.code {
+            0 => KvError::Timeout,
+            11 => KvError::TemporarilyUnavailable,
+            20 => KvError::KeyDoesNotExist,
+            21 => KvError::KeyAlreadyExists,
+            22 => KvError::PreconditionFailed(error.text),
+            code => KvError::Other {
+                code,
+                text: error.text,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for KvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::Timeout => write!(f, "timeout"),
+            KvError::TemporarilyUnavailable => write!(f, "temporarily unavailable"),
+            KvError::KeyDoesNotExist => write!(f, "key does not exist"),
+            KvError::KeyAlreadyExists => write!(f, "key already exists"),
+            KvError::PreconditionFailed(text) => write!(f, "precondition failed: {}", text),
+            KvError::Other { code, text } => write!(f, "error {}: {}", code, text),
+            KvError::UnexpectedReply(reply) => write!(f, "unexpected reply: {}", reply),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+impl KvResponse {
+    /// Reads a KV reply out of a node's message. Nodes whose own responses look like KV ones
+    /// (a `read_ok` with a `value`) get them parsed as their own type, so those are converted
+    /// back.
+    fn from_payload<Req, Res>(payload: Payload<Req, Res>) -> Result<KvResponse, KvError>
+    where
+        Req: Serialize,
+        Res: Serialize,
+    {
+        let value = match payload {
+            Payload::Kv(response) => return Ok(response),
+            Payload::Error(error) => return Err(error.into()),
+            Payload::Request(request) => serde_json::to_value(request),
+            Payload::Response(response) => serde_json::to_value(response),
+        }
+        .map_err(|e| KvError::UnexpectedReply(e.to_string()))?;
+        serde_json::from_value(value.clone())
+            .map_err(|_| KvError::UnexpectedReply(value.to_string()))
+    }
+}
+
+/// Typed client for one of Maelstrom's KV services. Requests go through the node's [`Rpc`], and
+/// each call takes a callback that gets the node and the outcome.
+#[derive(Clone, Debug)]
+pub struct KvClient {
+    service: KvService,
+    timeout: Duration,
+}
+
+impl KvClient {
+    pub fn new(service: KvService) -> Self {
+        KvClient {
+            service,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// How long to wait for a reply before the node gets an [`Event::Timeout`]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn service(&self) -> KvService {
+        self.service
+    }
+
+    fn request<N, Req, Res, Inj, F>(
+        &self,
+        rpc: &mut Rpc<N, Req, Res, Inj>,
+        request: KvRequest,
+        output: &mut dyn Write,
+        callback: F,
+    ) -> anyhow::Result<MessageId>
+    where
+        Req: Serialize + Send + 'static,
+        Res: Serialize + Send + 'static,
+        Inj: Send + 'static,
+        F: FnOnce(&mut N, Result<KvResponse, KvError>, &mut dyn Write) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    {
+        rpc.call(
+            self.service.name(),
+            request,
+            self.timeout,
+            output,
+            |node, reply: Message<Req, Res>, output| {
+                callback(node, KvResponse::from_payload(reply.body.payload), output)
+            },
+        )
+    }
+
+    pub fn read<N, Req, Res, Inj, T, F>(
+        &self,
+        rpc: &mut Rpc<N, Req, Res, Inj>,
+        key: impl Serialize,
+        output: &mut dyn Write,
+        callback: F,
+    ) -> anyhow::Result<MessageId>
+    where
+        Req: Serialize + Send + 'static,
+        Res: Serialize + Send + 'static,
+        Inj: Send + 'static,
+        T: DeserializeOwned,
+        F: FnOnce(&mut N, Result<T, KvError>, &mut dyn Write) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    {
+        let request = KvRequest::Read {
+            key: serde_json::to_value(key)?,
+        };
+        self.request(rpc, request, output, |node, reply, output| {
+            let value = reply.and_then(|reply| match reply {
+                KvResponse::ReadOk { value } => serde_json::from_value(value.clone())
+                    .map_err(|_| KvError::UnexpectedReply(value.to_string())),
+                other => Err(KvError::UnexpectedReply(format!("{:?}", other))),
+            });
+            callback(node, value, output)
+        })
+    }
+
+    pub fn write<N, Req, Res, Inj, F>(
+        &self,
+        rpc: &mut Rpc<N, Req, Res, Inj>,
+        key: impl Serialize,
+        value: impl Serialize,
+        output: &mut dyn Write,
+        callback: F,
+    ) -> anyhow::Result<MessageId>
+    where
+        Req: Serialize + Send + 'static,
+        Res: Serialize + Send + 'static,
+        Inj: Send + 'static,
+        F: FnOnce(&mut N, Result<(), KvError>, &mut dyn Write) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    {
+        let request = KvRequest::Write {
+            key: serde_json::to_value(key)?,
+            value: serde_json::to_value(value)?,
+        };
+        self.request(rpc, request, output, |node, reply, output| {
+            let result = reply.and_then(|reply| match reply {
+                KvResponse::WriteOk => Ok(()),
+                other => Err(KvError::UnexpectedReply(format!("{:?}", other))),
+            });
+            callback(node, result, output)
+        })
+    }
+
+    /// Sets `key` to `to` if it's currently `from`. With `create_if_not_exists` a missing key
+    /// counts as being `from`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn cas<N, Req, Res, Inj, F>(
+        &self,
+        rpc: &mut Rpc<N, Req, Res, Inj>,
+        key: impl Serialize,
+        from: impl Serialize,
+        to: impl Serialize,
+        create_if_not_exists: bool,
+        output: &mut dyn Write,
+        callback: F,
+    ) -> anyhow::Result<MessageId>
+    where
+        Req: Serialize + Send + 'static,
+        Res: Serialize + Send + 'static,
+        Inj: Send + 'static,
+        F: FnOnce(&mut N, Result<(), KvError>, &mut dyn Write) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    {
+        let request = KvRequest::Cas {
+            key: serde_json::to_value(key)?,
+            from: serde_json::to_value(from)?,
+            to: serde_json::to_value(to)?,
+            create_if_not_exists,
+        };
+        self.request(rpc, request, output, |node, reply, output| {
+            let result = reply.and_then(|reply| match reply {
+                KvResponse::CasOk => Ok(()),
+                other => Err(KvError::UnexpectedReply(format!("{:?}", other))),
+            });
+            callback(node, result, output)
+        })
+    }
 }
 
 pub fn run<S, N, Req, Res, Inj>(init_state: S) -> anyhow::Result<()>
@@ -157,7 +605,7 @@ where
         .context("error writing newline to stdout")?;
 
     for event in rx {
-        node.step(event, &mut stdout)?
+        dispatch::<S, N, _, _, _>(&mut node, event, &mut stdout)?
     }
     Ok(())
 }
@@ -196,4 +644,252 @@ mod test {
             )
         );
     }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Req {
+        Read,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Res {
+        ReadOk { value: usize },
+    }
+
+    struct Reader {
+        rpc: Rpc<Reader, Req, Res>,
+        value: Option<usize>,
+        timeouts: Vec<MessageId>,
+        stepped: usize,
+    }
+
+    impl Node<(), Req, Res> for Reader {
+        fn from_init(
+            _state: (),
+            init: Init,
+            tx: Sender<Event<Req, Res, ()>>,
+        ) -> anyhow::Result<Self> {
+            Ok(Reader {
+                rpc: Rpc::new(init.node_id, tx),
+                value: None,
+                timeouts: Vec::new(),
+                stepped: 0,
+            })
+        }
+
+        fn step(
+            &mut self,
+            msg: Event<Req, Res, ()>,
+            _output: &mut dyn Write,
+        ) -> anyhow::Result<()> {
+            self.stepped += 1;
+            if let Event::Timeout(msg_id) = msg {
+                self.timeouts.push(msg_id);
+            }
+            Ok(())
+        }
+
+        fn rpc(&mut self) -> Option<&mut Rpc<Self, Req, Res>> {
+            Some(&mut self.rpc)
+        }
+    }
+
+    fn reply(in_reply_to: MessageId, value: usize) -> Event<Req, Res, ()> {
+        Event::Message(Message {
+            src: String::from("seq-kv"),
+            dest: String::from("n0"),
+            body: Body {
+                msg_id: None,
+                in_reply_to: Some(in_reply_to),
+                payload: Payload::Response(Res::ReadOk { value }),
+            },
+        })
+    }
+
+    #[test]
+    fn test_rpc_routes_replies_and_timeouts() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let init = Init {
+            node_id: String::from("n0"),
+            node_ids: vec![String::from("n0")],
+        };
+        let mut node = Reader::from_init((), init, tx).unwrap();
+        let mut output = Vec::new();
+        let on_reply = |node: &mut Reader, msg: Message<Req, Res>, _: &mut dyn Write| {
+            if let Payload::Response(Res::ReadOk { value }) = msg.body.payload {
+                node.value = Some(value);
+            }
+            Ok(())
+        };
+        let first = node
+            .rpc
+            .call(
+                "seq-kv",
+                Req::Read,
+                Duration::from_secs(60),
+                &mut output,
+                on_reply,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from(r#"{"src":"n0","dest":"seq-kv","body":{"msg_id":1,"type":"read"}}"#),
+            String::from_utf8(output.clone()).unwrap().trim()
+        );
+        let second = node
+            .rpc
+            .call(
+                "seq-kv",
+                Req::Read,
+                Duration::ZERO,
+                &mut output,
+                |_, _, _| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(2, node.rpc.outstanding());
+
+        dispatch::<(), _, _, _, _>(&mut node, reply(first, 5), &mut output).unwrap();
+        assert_eq!(Some(5), node.value);
+        // Replies nobody is waiting for anymore go to step
+        dispatch::<(), _, _, _, _>(&mut node, reply(first, 6), &mut output).unwrap();
+        assert_eq!((Some(5), 1), (node.value, node.stepped));
+
+        let Ok(Event::Timeout(msg_id)) = rx.recv() else {
+            panic!("expected a timeout");
+        };
+        assert_eq!(second, msg_id);
+        dispatch::<(), _, _, _, _>(&mut node, Event::Timeout(msg_id), &mut output).unwrap();
+        // A timeout for a request that was answered is dropped
+        dispatch::<(), _, _, _, _>(&mut node, Event::Timeout(first), &mut output).unwrap();
+        assert_eq!(vec![second], node.timeouts);
+        assert_eq!(0, node.rpc.outstanding());
+    }
+
+    #[test]
+    fn test_rpc_timeouts_fire_by_deadline() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let init = Init {
+            node_id: String::from("n0"),
+            node_ids: vec![String::from("n0")],
+        };
+        let mut node = Reader::from_init((), init, tx).unwrap();
+        let mut output = Vec::new();
+        let mut ids = Vec::new();
+        for timeout in [30, 10, 20] {
+            let msg_id = node
+                .rpc
+                .call(
+                    "seq-kv",
+                    Req::Read,
+                    Duration::from_millis(timeout),
+                    &mut output,
+                    |_, _, _| Ok(()),
+                )
+                .unwrap();
+            ids.push(msg_id);
+        }
+        let fired: Vec<MessageId> = (0..3)
+            .map(|_| match rx.recv() {
+                Ok(Event::Timeout(msg_id)) => msg_id,
+                _ => panic!("expected a timeout"),
+            })
+            .collect();
+        assert_eq!(vec![ids[1], ids[2], ids[0]], fired);
+    }
+
+    #[test]
+    fn test_kv_replies() {
+        let parse = |line: &str| {
+            let msg: Message<Req, Res> = serde_json::from_str(line).unwrap();
+            KvResponse::from_payload(msg.body.payload)
+        };
+        // Parsed as the node's own read_ok, and converted back
+        assert_eq!(
+            Ok(KvResponse::ReadOk {
+                value: serde_json::json!(3)
+            }),
+            parse(
+                r#"{"src":"seq-kv","dest":"n0","body":{"in_reply_to":1,"type":"read_ok","value":3}}"#
+            )
+        );
+        assert_eq!(
+            Ok(KvResponse::CasOk),
+            parse(r#"{"src":"seq-kv","dest":"n0","body":{"in_reply_to":1,"type":"cas_ok"}}"#)
+        );
+        assert_eq!(
+            Err(KvError::PreconditionFailed(String::from(
+                "expected 1, but had 2"
+            ))),
+            parse(
+                r#"{"src":"lin-kv","dest":"n0","body":{"in_reply_to":1,"type":"error","code":22,"text":"expected 1, but had 2"}}"#
+            )
+        );
+        assert_eq!(
+            Err(KvError::KeyDoesNotExist),
+            parse(
+                r#"{"src":"lin-kv","dest":"n0","body":{"in_reply_to":1,"type":"error","code":20}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_kv_client() {
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let init = Init {
+            node_id: String::from("n0"),
+            node_ids: vec![String::from("n0")],
+        };
+        let mut node = Reader::from_init((), init, tx).unwrap();
+        let mut output = Vec::new();
+        let kv = KvClient::new(KvService::LinKv);
+        let msg_id = kv
+            .cas(
+                &mut node.rpc,
+                "k",
+                1,
+                2,
+                true,
+                &mut output,
+                |node: &mut Reader, result, _| {
+                    assert_eq!(Err(KvError::PreconditionFailed(String::new())), result);
+                    node.value = Some(0);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            r#"{"src":"n0","dest":"lin-kv","body":{"msg_id":1,"type":"cas","key":"k","from":1,"to":2,"create_if_not_exists":true}}"#,
+            String::from_utf8(output.clone()).unwrap().trim()
+        );
+        let error = Event::Message(Message {
+            src: String::from("lin-kv"),
+            dest: String::from("n0"),
+            body: Body {
+                msg_id: None,
+                in_reply_to: Some(msg_id),
+                payload: Payload::Error(ErrorBody {
+                    code: 22,
+                    text: String::new(),
+                }),
+            },
+        });
+        dispatch::<(), _, _, _, _>(&mut node, error, &mut output).unwrap();
+        assert_eq!(Some(0), node.value);
+
+        let msg_id = kv
+            .read(
+                &mut node.rpc,
+                "k",
+                &mut output,
+                |node: &mut Reader, value: Result<usize, _>, _| {
+                    node.value = value.ok();
+                    Ok(())
+                },
+            )
+            .unwrap();
+        dispatch::<(), _, _, _, _>(&mut node, reply(msg_id, 2), &mut output).unwrap();
+        assert_eq!(Some(2), node.value);
+    }
 }
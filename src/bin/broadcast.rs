@@ -1,8 +1,8 @@
 use async_trait::async_trait;
+use gossip_glomers::broadcast::{self, RequestBody, ResponseBody};
 use log::info;
 use maelstrom::protocol::Message;
 use maelstrom::{done, Node, Result, Runtime};
-use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
@@ -29,7 +29,7 @@ impl Node for Handler {
         let body: Result<RequestBody> = req.body.as_obj();
         match body {
             Ok(RequestBody::Broadcast { message }) => {
-                self.set.lock().unwrap().insert(message.clone());
+                self.set.lock().unwrap().insert(message);
                 return runtime.reply_ok(req).await;
             }
             Ok(RequestBody::Read) => {
@@ -45,7 +45,10 @@ impl Node for Handler {
                 // spawn into tokio (instead of runtime) to not to wait
                 // until it is completed, as it will never be.
                 info!("{:?}", node_id);
-                self.neighbours.lock().unwrap().extend(pick_neighbours(node_id, node_ids));
+                self.neighbours
+                    .lock()
+                    .unwrap()
+                    .extend(broadcast::pick_neighbours(&node_id, &node_ids));
                 let (r0, h0) = (runtime.clone(), self.clone());
                 tokio::spawn(async move {
                     loop {
@@ -71,88 +74,6 @@ impl Node for Handler {
     }
 }
 
-fn pick_neighbours(node_id: String, node_ids: Vec<String>) -> Vec<String> {
-    let neighbourhood_size = 5;
-    let node_no: usize = node_id[1..].parse().expect("Error parsing node number");
-
-    // node is the leader if it's the first in the chunk
-    let is_leader = node_no % neighbourhood_size == 0;
-
-    let mut node_ids: Vec<String> = node_ids;
-    node_ids.sort_by(|a, b| {
-        a[1..]
-            .parse::<usize>()
-            .unwrap()
-            .cmp(&b[1..].parse::<usize>().unwrap())
-    });
-
-    let neighbourhood = node_ids
-        .chunks(neighbourhood_size)
-        .nth(node_no / neighbourhood_size)
-        .unwrap()
-        .to_vec();
-
-    let neighbours: Vec<String> = if is_leader {
-        // exclude ourselves. Leader is always first in the chunk
-        let mut neighbours: Vec<String> = neighbourhood[1..].to_vec();
-        // Add other leaders: first of every other chunk
-        neighbours.extend(
-            node_ids
-                .chunks(neighbourhood_size)
-                .enumerate()
-                .filter(|(i, _)| *i != node_no / neighbourhood_size)
-                .map(|(_, chunk)| chunk.iter().next().unwrap().clone())
-                .collect::<Vec<String>>()
-        );
-        neighbours
-    } else {
-        // the leader is the only neighbour
-        neighbourhood[0..1].to_vec()
-    };
-    neighbours
-}
-
 fn to_seq(s: &MutexGuard<HashSet<u64>>) -> Vec<u64> {
     s.iter().copied().collect()
 }
-
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum RequestBody {
-    Init {
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    Broadcast {
-        message: u64,
-    },
-    Read,
-    Topology {
-        topology: std::collections::HashMap<String, Vec<String>>,
-    },
-    Gossip {
-        messages: Vec<u64>,
-    },
-}
-
-#[derive(Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponseBody {
-    ReadOk { messages: Vec<u64> },
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn serialize_read_ok() {
-        let body = ResponseBody::ReadOk {
-            messages: vec![1, 2, 3, 4, 5],
-        };
-        assert_eq!(
-            "{\"type\":\"read_ok\",\"messages\":[1,2,3,4,5]}",
-            serde_json::to_string::<ResponseBody>(&body).unwrap()
-        )
-    }
-}
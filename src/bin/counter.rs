@@ -1,13 +1,11 @@
 use async_trait::async_trait;
+use gossip_glomers::counter::{RequestBody, ResponseBody, COUNTER_KEY};
 use maelstrom::kv::{seq_kv, Storage, KV};
 use maelstrom::protocol::Message;
 use maelstrom::{done, Node, Result, Runtime};
-use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio_context::context::Context;
 
-static COUNTER_KEY: &str = "counter";
-
 pub(crate) fn main() -> Result<()> {
     Runtime::init(try_main())
 }
@@ -26,9 +24,8 @@ impl Node for Handler {
                 let curr_counter_value = self
                     .s
                     .get::<u64>(Context::new().0, String::from(COUNTER_KEY))
-                    .await
-                    .unwrap();
-                let cas_result = self
+                    .await?;
+                let mut cas_result = self
                     .s
                     .cas(
                         Context::new().0,
@@ -38,19 +35,21 @@ impl Node for Handler {
                         false,
                     )
                     .await;
-                while let Err(..) = cas_result {
+                while cas_result.is_err() {
                     let curr_counter_value = self
                         .s
                         .get::<u64>(Context::new().0, String::from(COUNTER_KEY))
-                        .await
-                        .unwrap();
-                    let cas_result = self.s.cas(
-                        Context::new().0,
-                        String::from(COUNTER_KEY),
-                        curr_counter_value,
-                        curr_counter_value + delta,
-                        false,
-                    );
+                        .await?;
+                    cas_result = self
+                        .s
+                        .cas(
+                            Context::new().0,
+                            String::from(COUNTER_KEY),
+                            curr_counter_value,
+                            curr_counter_value + delta,
+                            false,
+                        )
+                        .await;
                 }
                 return runtime.reply_ok(req).await;
             }
@@ -58,8 +57,7 @@ impl Node for Handler {
                 let curr_counter_value = self
                     .s
                     .get::<u64>(Context::new().0, String::from(COUNTER_KEY))
-                    .await
-                    .unwrap();
+                    .await?;
                 return runtime
                     .reply(
                         req,
@@ -90,22 +88,3 @@ async fn try_main() -> Result<()> {
     });
     runtime.with_handler(handler).run().await
 }
-
-#[derive(Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum RequestBody {
-    Add {
-        delta: u64,
-    },
-    Read,
-    Init {
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-}
-
-#[derive(Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponseBody {
-    ReadOk { value: u64 },
-}
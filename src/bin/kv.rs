@@ -0,0 +1,42 @@
+use std::io::{BufRead, Write};
+
+use gossip_glomers::kv::{Consistency, Kv};
+use maelstrom::protocol::Message;
+use maelstrom::Result;
+
+// Usage: kv [lin-kv|seq-kv|lww-kv] [--stale-reads P] [--seed N]
+//
+// Reads Maelstrom messages addressed to the service from stdin, one per line, and writes the
+// replies to stdout.
+pub(crate) fn main() -> Result<()> {
+    let mut consistency = Consistency::Linearizable;
+    let mut stale_reads = 0.0;
+    let mut seed = 0;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stale-reads" => stale_reads = args.next().ok_or("missing value")?.parse()?,
+            "--seed" => seed = args.next().ok_or("missing value")?.parse()?,
+            name => {
+                consistency = Consistency::from_service_name(name)
+                    .ok_or_else(|| format!("unknown service {}", name))?
+            }
+        }
+    }
+    let mut kv = Kv::new(consistency, seed).with_stale_reads(stale_reads);
+
+    let mut stdout = std::io::stdout().lock();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let req: Message = serde_json::from_str(&line)?;
+        let body = kv.apply(&req.src, &req.body);
+        let resp = gossip_glomers::sim::reply(&req, body)?;
+        serde_json::to_writer(&mut stdout, &resp)?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
@@ -1,29 +1,80 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use log::debug;
-use maelstrom::{
-    kv::{seq_kv, Storage, KV},
-    protocol::Message,
-    Node, Result, Runtime,
+use gossip_glomers::error;
+use gossip_glomers::txn::{
+    self, Isolation, Mvcc, Registers, RequestBody, ResponseBody, TwoPhase, Versioned,
 };
-use serde::de;
-use serde::{ser::SerializeSeq, Deserialize, Serialize};
-use tokio_context::context::Context;
+use log::{debug, info};
+use maelstrom::{done, protocol::Message, Node, Result, Runtime};
 
 pub(crate) fn main() -> Result<()> {
-    Runtime::init(try_main())
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let engine = match Isolation::from_args(&args, Isolation::ReadCommitted)? {
+        Isolation::ReadCommitted => Engine::ReadCommitted(Mutex::default()),
+        Isolation::Snapshot => Engine::Snapshot(Mutex::default()),
+        Isolation::Serializable => Engine::Serializable(Arc::default()),
+    };
+    Runtime::init(try_main(engine))
+}
+
+/// Read committed and totally available (challenge 6c).
+///
+/// Each node runs transactions against its own [`Registers`], so it keeps answering whoever it's
+/// partitioned from, and gossips them to the other nodes, which merge them into theirs. A
+/// transaction runs under the lock of the registers and only writes to them once it's done, so
+/// nobody sees its writes half done, and last-writer-wins keeps a single order of the writes to
+/// every key on every node.
+///
+/// With `--isolation snapshot`, transactions run on [`Mvcc`] instead, which gossips the same way
+/// but rejects a transaction that lost a race to write a key with txn-conflict. The first node
+/// certifies every commit with writes, so those fail on nodes partitioned from it.
+///
+/// With `--isolation serializable`, every key has an owner node instead and transactions commit
+/// on their owners by [`TwoPhase`] commit, which gives up availability: a transaction with a key
+/// owned across a partition fails.
+enum Engine {
+    ReadCommitted(Mutex<Registers>),
+    Snapshot(Mutex<Mvcc>),
+    Serializable(Arc<TwoPhase>),
+}
+
+impl Engine {
+    /// The latest value of every key, for gossip
+    fn latest(&self) -> Vec<(usize, Versioned)> {
+        match self {
+            Engine::ReadCommitted(registers) => registers
+                .lock()
+                .unwrap()
+                .values()
+                .iter()
+                .map(|(key, value)| (*key, value.clone()))
+                .collect(),
+            Engine::Snapshot(mvcc) => mvcc.lock().unwrap().latest(),
+            // Owners don't replicate their keys
+            Engine::Serializable(_) => Vec::new(),
+        }
+    }
+
+    fn merge(&self, values: Vec<(usize, Versioned)>) {
+        match self {
+            Engine::ReadCommitted(registers) => registers.lock().unwrap().merge(values),
+            Engine::Snapshot(mvcc) => mvcc.lock().unwrap().merge(values),
+            Engine::Serializable(_) => {}
+        }
+    }
 }
 
 #[derive(Clone)]
 struct Handler {
-    storage: Storage,
+    engine: Arc<Engine>,
 }
 
-async fn try_main() -> Result<()> {
+async fn try_main(engine: Engine) -> Result<()> {
     let runtime = Runtime::new();
     let handler = Arc::new(Handler {
-        storage: seq_kv(runtime.clone()),
+        engine: Arc::new(engine),
     });
     runtime.with_handler(handler).run().await
 }
@@ -31,155 +82,99 @@ async fn try_main() -> Result<()> {
 #[async_trait]
 impl Node for Handler {
     async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
-        let body: RequestBody = req.body.as_obj().expect("Error deserializing message body");
+        let body: RequestBody = error::parse(&req)?;
         match body {
-            RequestBody::Transaction { txn } => {
-                let mut ops: Vec<Operation> =
-                    txn.into_iter().map(|so| so.try_into().unwrap()).collect();
-                debug!("{:?}", ops);
-                for op in ops.iter_mut() {
-                    match op {
-                        Operation::Read { key, value } => {
-                            let read_res = self
-                                .storage
-                                .get(Context::new().0, key.to_string())
-                                .await;
-                            *value = match read_res {
-                                Ok(val) => Some(val),
-                                Err(_) => None,
-                            };
-                        }
-                        Operation::Write { key, value } => self
-                            .storage
-                            .put(Context::new().0, key.to_string(), value)
-                            .await
-                            .unwrap(),
+            RequestBody::Transaction { mut txn } => {
+                debug!("{:?}", txn);
+                let writes = match &*self.engine {
+                    Engine::ReadCommitted(registers) => {
+                        let mut registers = registers.lock().unwrap();
+                        registers
+                            .execute(runtime.node_id(), &mut txn)
+                            .into_iter()
+                            .collect()
                     }
-                }
-                debug!("{:?}", ops);
+                    Engine::Snapshot(mvcc) => txn::mvcc::execute(mvcc, &runtime, &mut txn).await?,
+                    // Written on the owners, nothing to gossip
+                    Engine::Serializable(two_phase) => {
+                        two_phase.execute(&runtime, &mut txn).await?;
+                        Vec::new()
+                    }
+                };
+                debug!("{:?}, wrote {:?}", txn, writes);
                 return runtime
-                    .reply(req.clone(), ResponseBody::TransactionOk { txn: ops })
+                    .reply(req.clone(), ResponseBody::TransactionOk { txn })
                     .await;
             }
-            RequestBody::Init { .. } => Ok(()),
-        }
-    }
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum RequestBody {
-    Init {
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    #[serde(rename = "txn")]
-    Transaction { txn: Vec<Operation> },
-}
-
-#[derive(Serialize, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponseBody {
-    #[serde(rename = "txn_ok")]
-    TransactionOk { txn: Vec<Operation> },
-}
-
-#[derive(Debug, PartialEq)]
-enum Operation {
-    Read { key: usize, value: Option<usize> },
-    Write { key: usize, value: usize },
-}
-
-impl Serialize for Operation {
-    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let mut seq = serializer.serialize_seq(Some(3))?;
-        match self {
-            Operation::Read { key, value } => {
-                seq.serialize_element("r")?;
-                seq.serialize_element(key)?;
-                seq.serialize_element(value)?;
+            RequestBody::Gossip { registers } => {
+                self.engine.merge(registers);
+                Ok(())
             }
-            Operation::Write {
-                key: from_key,
-                value: to_key,
-            } => {
-                seq.serialize_element("w")?;
-                seq.serialize_element(from_key)?;
-                seq.serialize_element(to_key)?;
+            RequestBody::Prepare { id, keys, writes } => {
+                let Engine::Serializable(two_phase) = &*self.engine else {
+                    return done(runtime, req);
+                };
+                let values = two_phase.prepare(&id, keys, writes)?;
+                runtime.reply(req, ResponseBody::PrepareOk { values }).await
+            }
+            RequestBody::Decide { id, decision } => {
+                let Engine::Serializable(two_phase) = &*self.engine else {
+                    return done(runtime, req);
+                };
+                two_phase.finish(&id, decision);
+                runtime.reply(req, ResponseBody::DecideOk).await
+            }
+            RequestBody::Status { id } => {
+                let Engine::Serializable(two_phase) = &*self.engine else {
+                    return done(runtime, req);
+                };
+                let decision = two_phase.status(id, &req.src);
+                runtime
+                    .reply(req, ResponseBody::StatusOk { decision })
+                    .await
+            }
+            RequestBody::Certify { writes } => {
+                let Engine::Snapshot(mvcc) = &*self.engine else {
+                    return done(runtime, req);
+                };
+                let versions = mvcc.lock().unwrap().certify(runtime.node_id(), writes)?;
+                runtime
+                    .reply(req, ResponseBody::CertifyOk { versions })
+                    .await
+            }
+            RequestBody::Init { .. } => {
+                if let Engine::Serializable(two_phase) = &*self.engine {
+                    // Settle the transactions whose decision didn't make it here, forever
+                    let (runtime, two_phase) = (runtime.clone(), two_phase.clone());
+                    tokio::spawn(async move {
+                        loop {
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            two_phase.recover(&runtime).await;
+                        }
+                    });
+                    return Ok(());
+                }
+                // Gossip forever, in the background. Whatever a partition keeps from a node is
+                // in the next round after it heals.
+                let (runtime, handler) = (runtime.clone(), self.clone());
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        let registers = handler.engine.latest();
+                        for peer in runtime.nodes() {
+                            if peer == runtime.node_id() {
+                                continue;
+                            }
+                            info!("gossiping {} registers to {}", registers.len(), peer);
+                            let gossip = RequestBody::Gossip {
+                                registers: registers.clone(),
+                            };
+                            drop(runtime.send_async(peer, gossip));
+                        }
+                    }
+                });
+                Ok(())
             }
-        };
-        seq.end()
-    }
-}
-
-impl<'de> Deserialize<'de> for Operation {
-    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        struct InnerOperation(char, usize, Option<usize>);
-
-        let inner = InnerOperation::deserialize(deserializer)?;
-        match inner.0 {
-            'r' => Ok(Operation::Read {
-                key: inner.1,
-                value: inner.2,
-            }),
-            'w' => Ok(Operation::Write {
-                key: inner.1,
-                value: inner.2.expect("must be a value"),
-            }),
-            x => Err(de::Error::custom(format!(
-                "found unexpected operation type {}",
-                x
-            ))),
         }
     }
 }
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn serialize_operation() {
-        let read_resp = Operation::Read {
-            key: 15,
-            value: Some(10),
-        };
-        assert_eq!(r#"["r",15,10]"#, serde_json::to_string(&read_resp).unwrap());
-        let read_req = Operation::Read {
-            key: 15,
-            value: None,
-        };
-        assert_eq!(
-            r#"["r",15,null]"#,
-            serde_json::to_string(&read_req).unwrap()
-        );
-        let write = Operation::Write { key: 7, value: 12 };
-        assert_eq!(r#"["w",7,12]"#, serde_json::to_string(&write).unwrap());
-    }
-
-    #[test]
-    fn deserialize_operation() {
-        let read_resp = Operation::Read {
-            key: 15,
-            value: Some(10),
-        };
-        let raw = r#"["r",15,10]"#;
-        assert_eq!(read_resp, serde_json::from_str::<Operation>(&raw).unwrap());
-        let read_req = Operation::Read {
-            key: 15,
-            value: None,
-        };
-        let raw = r#"["r",15,null]"#;
-        assert_eq!(read_req, serde_json::from_str::<Operation>(&raw).unwrap());
-        let write = Operation::Write { key: 7, value: 12 };
-        let raw = r#"["w",7,12]"#;
-        assert_eq!(write, serde_json::from_str::<Operation>(&raw).unwrap());
-    }
-}
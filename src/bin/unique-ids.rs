@@ -3,7 +3,6 @@ use maelstrom::protocol::Message;
 use maelstrom::{done, Node, Result, Runtime};
 use serde::Serialize;
 use std::sync::Arc;
-use ulid;
 
 pub(crate) fn main() -> Result<()> {
     Runtime::init(try_main())
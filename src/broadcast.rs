@@ -0,0 +1,100 @@
+//! Messages of the broadcast workload (challenges 3a to 3e), and the topology we gossip over.
+
+use serde::{Deserialize, Serialize};
+
+/// Splits the nodes into neighbourhoods of 5. The first node of each neighbourhood is its leader:
+/// leaders talk to the rest of their neighbourhood and to each other, everyone else only to their
+/// leader.
+pub fn pick_neighbours(node_id: &str, node_ids: &[String]) -> Vec<String> {
+    let neighbourhood_size = 5;
+    let node_no: usize = node_id[1..].parse().expect("Error parsing node number");
+
+    // node is the leader if it's the first in the chunk
+    let is_leader = node_no.is_multiple_of(neighbourhood_size);
+
+    let mut node_ids: Vec<String> = node_ids.to_vec();
+    node_ids.sort_by(|a, b| {
+        a[1..]
+            .parse::<usize>()
+            .unwrap()
+            .cmp(&b[1..].parse::<usize>().unwrap())
+    });
+
+    let neighbourhood = node_ids
+        .chunks(neighbourhood_size)
+        .nth(node_no / neighbourhood_size)
+        .unwrap()
+        .to_vec();
+
+    let neighbours: Vec<String> = if is_leader {
+        // exclude ourselves. Leader is always first in the chunk
+        let mut neighbours: Vec<String> = neighbourhood[1..].to_vec();
+        // Add other leaders: first of every other chunk
+        neighbours.extend(
+            node_ids
+                .chunks(neighbourhood_size)
+                .enumerate()
+                .filter(|(i, _)| *i != node_no / neighbourhood_size)
+                .map(|(_, chunk)| chunk.iter().next().unwrap().clone())
+                .collect::<Vec<String>>(),
+        );
+        neighbours
+    } else {
+        // the leader is the only neighbour
+        neighbourhood[0..1].to_vec()
+    };
+    neighbours
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestBody {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    Broadcast {
+        message: u64,
+    },
+    Read,
+    Topology {
+        topology: std::collections::HashMap<String, Vec<String>>,
+    },
+    Gossip {
+        messages: Vec<u64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseBody {
+    ReadOk { messages: Vec<u64> },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_read_ok() {
+        let body = ResponseBody::ReadOk {
+            messages: vec![1, 2, 3, 4, 5],
+        };
+        assert_eq!(
+            "{\"type\":\"read_ok\",\"messages\":[1,2,3,4,5]}",
+            serde_json::to_string::<ResponseBody>(&body).unwrap()
+        )
+    }
+
+    #[test]
+    fn leaders_connect_neighbourhoods() {
+        let node_ids: Vec<String> = (0..12).map(|i| format!("n{}", i)).collect();
+        let ids = |ids: &[&str]| ids.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            ids(&["n1", "n2", "n3", "n4", "n5", "n10"]),
+            pick_neighbours("n0", &node_ids)
+        );
+        assert_eq!(ids(&["n5"]), pick_neighbours("n7", &node_ids));
+        assert_eq!(ids(&["n11", "n0", "n5"]), pick_neighbours("n10", &node_ids));
+    }
+}
@@ -0,0 +1,203 @@
+//! Just enough of an EDN reader to load Jepsen's `history.edn`. Values are turned into JSON:
+//! keywords and symbols become strings without the leading colon, sets and lists become arrays
+//! and tagged elements (`#jepsen.history.Op{...}`) are replaced by the tagged value.
+
+use serde_json::{Map, Number, Value};
+
+pub fn parse_all(input: &str) -> Result<Vec<Value>, String> {
+    let mut reader = Reader {
+        chars: input.chars().collect(),
+        pos: 0,
+    };
+    let mut values = Vec::new();
+    loop {
+        reader.skip_whitespace();
+        if reader.peek().is_none() {
+            return Ok(values);
+        }
+        values.push(reader.value()?);
+    }
+}
+
+struct Reader {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Reader {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn error(&self, what: &str) -> String {
+        format!("edn: {} at character {}", what, self.pos)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ';' {
+                while !matches!(self.next(), Some('\n') | None) {}
+            } else if c.is_whitespace() || c == ',' {
+                self.pos += 1;
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self
+            .peek()
+            .ok_or_else(|| self.error("unexpected end of input"))?
+        {
+            '[' | '(' => {
+                let close = if self.next() == Some('[') { ']' } else { ')' };
+                Ok(Value::Array(self.seq(close)?))
+            }
+            '{' => {
+                self.pos += 1;
+                self.map()
+            }
+            '"' => {
+                self.pos += 1;
+                self.string().map(Value::String)
+            }
+            '#' => {
+                self.pos += 1;
+                match self.peek() {
+                    Some('{') => {
+                        self.pos += 1;
+                        Ok(Value::Array(self.seq('}')?))
+                    }
+                    // Discard the next form
+                    Some('_') => {
+                        self.pos += 1;
+                        self.value()?;
+                        self.value()
+                    }
+                    // Tagged element: drop the tag, keep the value
+                    _ => {
+                        self.token();
+                        self.value()
+                    }
+                }
+            }
+            ':' => {
+                self.pos += 1;
+                Ok(Value::String(self.token()))
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn seq(&mut self, close: char) -> Result<Vec<Value>, String> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    return Ok(values);
+                }
+                Some(_) => values.push(self.value()?),
+                None => return Err(self.error("unterminated collection")),
+            }
+        }
+    }
+
+    fn map(&mut self) -> Result<Value, String> {
+        let values = self.seq('}')?;
+        if values.len() % 2 != 0 {
+            return Err(self.error("map with an odd number of forms"));
+        }
+        let mut map = Map::new();
+        let mut values = values.into_iter();
+        while let (Some(k), Some(v)) = (values.next(), values.next()) {
+            let key = match k {
+                Value::String(s) => s,
+                other => other.to_string(),
+            };
+            map.insert(key, v);
+        }
+        Ok(Value::Object(map))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some(c) => s.push(c),
+                    None => return Err(self.error("unterminated string")),
+                },
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn token(&mut self) -> String {
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || matches!(c, ',' | '[' | ']' | '(' | ')' | '{' | '}' | '"') {
+                break;
+            }
+            s.push(c);
+            self.pos += 1;
+        }
+        s
+    }
+
+    fn atom(&mut self) -> Result<Value, String> {
+        let token = self.token();
+        if token.is_empty() {
+            return Err(self.error("unexpected character"));
+        }
+        Ok(match token.as_str() {
+            "nil" => Value::Null,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => {
+                // Clojure marks bigints with N and bigdecimals with M
+                let numeric = token.trim_end_matches(['N', 'M']);
+                if let Ok(i) = numeric.parse::<i64>() {
+                    Value::Number(i.into())
+                } else if let Some(n) = numeric.parse::<f64>().ok().and_then(Number::from_f64) {
+                    Value::Number(n)
+                } else {
+                    Value::String(token)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_history_line() {
+        let edn = r#"{:type :ok, :f :txn, :value [[:send "9" [3 28]] [:poll {"9" [[1 2] [3 28]]}]], :time 12N, :process 0, :error nil}
+            ; a comment
+            #jepsen.history.Op{:index 1, :set #{1 2}}"#;
+        let values = parse_all(edn).unwrap();
+        assert_eq!(
+            json!({"type": "ok", "f": "txn", "value": [["send", "9", [3, 28]], ["poll", {"9": [[1, 2], [3, 28]]}]], "time": 12, "process": 0, "error": null}),
+            values[0]
+        );
+        assert_eq!(json!({"index": 1, "set": [1, 2]}), values[1]);
+    }
+}
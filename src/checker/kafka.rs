@@ -0,0 +1,354 @@
+//! Checks the properties the kafka workload cares about: every acknowledged send, batched or not,
+//! gets an offset of its own, polls don't skip acknowledged messages or go backwards, and
+//! committed offsets never move backwards.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::Value;
+
+use super::{History, Op, Outcome};
+
+#[derive(Debug, PartialEq)]
+pub enum Anomaly {
+    /// A poll returned messages on both sides of an acknowledged send but not the send itself
+    LostWrite {
+        key: String,
+        offset: u64,
+        msg: Value,
+    },
+    /// The same offset was handed to two different sends, or polls disagree on what's there
+    DuplicateOffset {
+        key: String,
+        offset: u64,
+        msgs: Vec<Value>,
+    },
+    /// A poll returned offsets out of order, or older than the offset it asked for
+    NonMonotonicPoll { op: usize, key: String },
+    /// `list_committed_offsets` returned less than what was committed or listed before it
+    /// started
+    CommittedOffsetRegression {
+        op: usize,
+        key: String,
+        expected_at_least: u64,
+        got: Option<u64>,
+    },
+}
+
+fn offsets(value: &Value) -> BTreeMap<String, u64> {
+    value
+        .as_object()
+        .map(|m| {
+            m.iter()
+                .filter_map(|(k, v)| v.as_u64().map(|v| (k.clone(), v)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn polled(op: &Op) -> BTreeMap<String, Vec<(u64, Value)>> {
+    let mut msgs = BTreeMap::new();
+    if let Some(m) = op.output["msgs"].as_object() {
+        for (key, pairs) in m {
+            let pairs = pairs
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|p| Some((p[0].as_u64()?, p[1].clone())))
+                .collect();
+            msgs.insert(key.clone(), pairs);
+        }
+    }
+    msgs
+}
+
+pub fn check(history: &History) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    let ok = |f: &'static str| {
+        history
+            .ops
+            .iter()
+            .enumerate()
+            .filter(move |(_, op)| op.f == f && op.outcome == Outcome::Ok)
+    };
+
+    // Everything we know is stored at each (key, offset), from sends and from polls
+    let mut sent: BTreeMap<(String, u64), Vec<Value>> = BTreeMap::new();
+    for (_, op) in ok("send") {
+        let (Some(key), Some(offset)) = (op.input["key"].as_str(), op.output["offset"].as_u64())
+        else {
+            continue;
+        };
+        sent.entry((key.to_string(), offset))
+            .or_default()
+            .push(op.input["msg"].clone());
+    }
+    for (_, op) in ok("send_batch") {
+        let (Some(batch), Some(offsets)) = (
+            op.input["msgs"].as_object(),
+            op.output["offsets"].as_object(),
+        ) else {
+            continue;
+        };
+        for (key, msgs) in batch {
+            let msgs = msgs.as_array().into_iter().flatten();
+            let offsets = offsets
+                .get(key)
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten();
+            for (msg, offset) in msgs.zip(offsets) {
+                let Some(offset) = offset.as_u64() else {
+                    continue;
+                };
+                sent.entry((key.clone(), offset))
+                    .or_default()
+                    .push(msg.clone());
+            }
+        }
+    }
+    let mut seen: BTreeMap<(String, u64), BTreeSet<String>> = BTreeMap::new();
+    for ((key, offset), msgs) in &sent {
+        let texts = seen.entry((key.clone(), *offset)).or_default();
+        texts.extend(msgs.iter().map(Value::to_string));
+        if msgs.len() > 1 {
+            anomalies.push(Anomaly::DuplicateOffset {
+                key: key.clone(),
+                offset: *offset,
+                msgs: msgs.clone(),
+            });
+        }
+    }
+
+    let mut lost: BTreeSet<(String, u64)> = BTreeSet::new();
+    for (i, op) in ok("poll") {
+        let requested = offsets(&op.input["offsets"]);
+        for (key, pairs) in polled(op) {
+            let mut in_order = pairs.windows(2).all(|w| w[0].0 < w[1].0);
+            if let (Some(from), Some((first, _))) = (requested.get(&key), pairs.first()) {
+                in_order &= first >= from;
+            }
+            if !in_order {
+                anomalies.push(Anomaly::NonMonotonicPoll {
+                    op: i,
+                    key: key.clone(),
+                });
+            }
+            for (offset, msg) in &pairs {
+                seen.entry((key.clone(), *offset))
+                    .or_default()
+                    .insert(msg.to_string());
+            }
+            let (Some((first, _)), Some((last, _))) = (pairs.first(), pairs.last()) else {
+                continue;
+            };
+            let from = requested.get(&key).copied().unwrap_or(*first);
+            let returned: BTreeSet<u64> = pairs.iter().map(|(o, _)| *o).collect();
+            for ((_, offset), _) in sent.range((key.clone(), from)..(key.clone(), *last)) {
+                if !returned.contains(offset) {
+                    lost.insert((key.clone(), *offset));
+                }
+            }
+        }
+    }
+    for (key, offset) in lost {
+        let msg = sent[&(key.clone(), offset)][0].clone();
+        anomalies.push(Anomaly::LostWrite { key, offset, msg });
+    }
+    for ((key, offset), texts) in seen {
+        let already_reported = sent
+            .get(&(key.clone(), offset))
+            .is_some_and(|m| m.len() > 1);
+        if texts.len() > 1 && !already_reported {
+            anomalies.push(Anomaly::DuplicateOffset {
+                key,
+                offset,
+                msgs: texts
+                    .iter()
+                    .map(|t| serde_json::from_str(t).unwrap_or(Value::Null))
+                    .collect(),
+            });
+        }
+    }
+
+    // Committed offsets: anything committed or listed in the same consumer group before a list
+    // started must be visible to it. Ops with no completion time, from a client that crashed or
+    // gave up on them, can't be placed before anything.
+    let mut facts: Vec<(std::time::Duration, &Value, BTreeMap<String, u64>)> = Vec::new();
+    for (_, op) in ok("commit_offsets") {
+        let Some(completed_at) = op.completed_at else {
+            continue;
+        };
+        let group = &op.input["group"];
+        facts.push((completed_at, group, offsets(&op.input["offsets"])));
+    }
+    for (_, op) in ok("list_committed_offsets") {
+        let Some(completed_at) = op.completed_at else {
+            continue;
+        };
+        let group = &op.input["group"];
+        facts.push((completed_at, group, offsets(&op.output["offsets"])));
+    }
+    for (i, op) in ok("list_committed_offsets") {
+        let got = offsets(&op.output["offsets"]);
+        let keys = op.input["keys"].as_array().cloned().unwrap_or_default();
+        for key in keys.iter().filter_map(Value::as_str) {
+            let expected = facts
+                .iter()
+                .filter(|(at, group, _)| *at < op.invoked_at && *group == &op.input["group"])
+                .filter_map(|(_, _, offsets)| offsets.get(key))
+                .max();
+            if let Some(expected) = expected {
+                if got.get(key).is_none_or(|got| got < expected) {
+                    anomalies.push(Anomaly::CommittedOffsetRegression {
+                        op: i,
+                        key: key.to_string(),
+                        expected_at_least: *expected,
+                        got: got.get(key).copied(),
+                    });
+                }
+            }
+        }
+    }
+    anomalies
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn op(f: &str, input: Value, output: Value, at: u64) -> Op {
+        Op {
+            process: String::from("c1"),
+            f: f.to_string(),
+            input,
+            output,
+            outcome: Outcome::Ok,
+            invoked_at: Duration::from_millis(at),
+            completed_at: Some(Duration::from_millis(at + 1)),
+        }
+    }
+
+    fn send(key: &str, msg: u64, offset: u64, at: u64) -> Op {
+        op(
+            "send",
+            json!({"key": key, "msg": msg}),
+            json!({"offset": offset}),
+            at,
+        )
+    }
+
+    fn poll(from: u64, msgs: Value, at: u64) -> Op {
+        op(
+            "poll",
+            json!({"offsets": {"k": from}}),
+            json!({"msgs": {"k": msgs}}),
+            at,
+        )
+    }
+
+    #[test]
+    fn valid_history() {
+        let history = History {
+            ops: vec![
+                send("k", 10, 1, 0),
+                send("k", 11, 2, 2),
+                op(
+                    "send_batch",
+                    json!({"msgs": {"k": [12, 13]}}),
+                    json!({"offsets": {"k": [3, 4]}}),
+                    3,
+                ),
+                poll(1, json!([[1, 10], [2, 11], [3, 12], [4, 13]]), 4),
+                op("commit_offsets", json!({"offsets": {"k": 2}}), json!({}), 6),
+                // Another consumer group's offsets don't count
+                op(
+                    "commit_offsets",
+                    json!({"group": "g", "offsets": {"k": 5}}),
+                    json!({}),
+                    6,
+                ),
+                op(
+                    "list_committed_offsets",
+                    json!({"keys": ["k"]}),
+                    json!({"offsets": {"k": 2}}),
+                    8,
+                ),
+            ],
+        };
+        assert_eq!(Vec::<Anomaly>::new(), check(&history));
+    }
+
+    #[test]
+    fn finds_anomalies() {
+        let history = History {
+            ops: vec![
+                send("k", 10, 1, 0),
+                send("k", 11, 2, 2),
+                send("k", 12, 2, 3),
+                send("k", 13, 3, 4),
+                poll(1, json!([[1, 10], [3, 13]]), 6),
+                poll(1, json!([[3, 13], [1, 10]]), 7),
+                op("commit_offsets", json!({"offsets": {"k": 3}}), json!({}), 8),
+                op(
+                    "commit_offsets",
+                    json!({"offsets": {"k": 1}}),
+                    json!({}),
+                    10,
+                ),
+                op(
+                    "list_committed_offsets",
+                    json!({"keys": ["k"]}),
+                    json!({"offsets": {"k": 1}}),
+                    12,
+                ),
+            ],
+        };
+        let anomalies = check(&history);
+        assert!(anomalies.contains(&Anomaly::DuplicateOffset {
+            key: "k".to_string(),
+            offset: 2,
+            msgs: vec![json!(11), json!(12)]
+        }));
+        assert!(anomalies.contains(&Anomaly::LostWrite {
+            key: "k".to_string(),
+            offset: 2,
+            msg: json!(11)
+        }));
+        assert!(anomalies.contains(&Anomaly::NonMonotonicPoll {
+            op: 5,
+            key: "k".to_string()
+        }));
+        assert!(anomalies.contains(&Anomaly::CommittedOffsetRegression {
+            op: 8,
+            key: "k".to_string(),
+            expected_at_least: 3,
+            got: Some(1)
+        }));
+        assert_eq!(4, anomalies.len());
+    }
+
+    #[test]
+    fn ops_that_never_completed_are_skipped() {
+        let mut unfinished = op("commit_offsets", json!({"offsets": {"k": 3}}), json!({}), 0);
+        unfinished.completed_at = None;
+        let mut timed_out = op("commit_offsets", json!({"offsets": {"k": 4}}), json!({}), 1);
+        timed_out.outcome = Outcome::Info;
+        timed_out.completed_at = None;
+        let history = History {
+            ops: vec![
+                send("k", 10, 1, 0),
+                unfinished,
+                timed_out,
+                op(
+                    "list_committed_offsets",
+                    json!({"keys": ["k"]}),
+                    json!({"offsets": {"k": 1}}),
+                    5,
+                ),
+            ],
+        };
+        assert_eq!(Vec::<Anomaly>::new(), check(&history));
+    }
+}
@@ -0,0 +1,314 @@
+//! Linearizability checking with the Wing & Gong search, as improved by Lowe ("Testing for
+//! linearizability", 2017): walk the history in time order, tentatively linearize calls, and
+//! backtrack when a return is reached for an op that couldn't be linearized. Configurations
+//! already explored are cached so the search doesn't revisit them.
+
+use std::collections::{BTreeMap, HashSet};
+use std::hash::Hash;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use super::{History, Op, Outcome};
+
+/// Sequential specification of the object the history operated on.
+pub trait Model {
+    type State: Clone + Eq + Hash;
+
+    fn init(&self) -> Self::State;
+
+    /// State after `op`, or `None` if `op` can't have happened in `state`. Called for ops of
+    /// every outcome, so failed ops can constrain the state too.
+    fn step(&self, state: &Self::State, op: &Op) -> Option<Self::State>;
+}
+
+/// A single read/write/cas register, as served by `lin-kv`. Values are compared by their JSON
+/// text.
+pub struct Register;
+
+impl Model for Register {
+    type State = Option<String>;
+
+    fn init(&self) -> Self::State {
+        None
+    }
+
+    fn step(&self, state: &Self::State, op: &Op) -> Option<Self::State> {
+        let field = |name: &str| op.input.get(name).map(Value::to_string);
+        let same = |name: &str| *state == field(name);
+        match (op.f.as_str(), op.outcome, op.error_code()) {
+            ("read", Outcome::Ok, _) => {
+                (*state == op.output.get("value").map(Value::to_string)).then(|| state.clone())
+            }
+            ("read", Outcome::Fail, Some(20)) => state.is_none().then_some(None),
+            ("write", Outcome::Ok | Outcome::Info, _) => Some(field("value")),
+            ("cas", Outcome::Ok | Outcome::Info, _) => {
+                let create = op.input["create_if_not_exists"] == Value::Bool(true);
+                (same("from") || (create && state.is_none())).then(|| field("to"))
+            }
+            ("cas", Outcome::Fail, Some(20)) => state.is_none().then_some(None),
+            ("cas", Outcome::Fail, Some(22)) => {
+                (state.is_some() && !same("from")).then(|| state.clone())
+            }
+            // Anything else tells us nothing about the register
+            _ => Some(state.clone()),
+        }
+    }
+}
+
+/// A counter with `add` and `read`, as in the g-counter workload.
+pub struct Counter;
+
+impl Model for Counter {
+    type State = i64;
+
+    fn init(&self) -> Self::State {
+        0
+    }
+
+    fn step(&self, state: &Self::State, op: &Op) -> Option<Self::State> {
+        match (op.f.as_str(), op.outcome) {
+            ("add", Outcome::Ok | Outcome::Info) => op.input["delta"].as_i64().map(|d| state + d),
+            ("read", Outcome::Ok) => {
+                (op.output["value"].as_i64() == Some(*state)).then_some(*state)
+            }
+            _ => Some(*state),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct NotLinearizable {
+    /// Index (in the checked ops) of the op that couldn't be linearized
+    pub op: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    op: usize,
+    is_call: bool,
+    // Index of the matching return entry, for calls of ops that completed
+    ret: Option<usize>,
+    prev: usize,
+    next: Option<usize>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Bitset(Vec<u64>);
+
+impl Bitset {
+    fn new(n: usize) -> Self {
+        Bitset(vec![0; n.div_ceil(64)])
+    }
+    fn set(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+    fn clear(&mut self, i: usize) {
+        self.0[i / 64] &= !(1 << (i % 64));
+    }
+}
+
+/// Checks that `ops` are linearizable with respect to `model`.
+///
+/// Ops that never completed may take effect at any point after they were invoked, or not at all.
+pub fn check<M: Model>(model: &M, ops: &[&Op]) -> Result<(), NotLinearizable> {
+    // Entry 0 is the list head; calls sort before returns that happen at the same time, which
+    // treats ops that touch in time as concurrent.
+    let mut events: Vec<(Duration, bool, usize)> = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        events.push((op.invoked_at, false, i));
+        if let Some(at) = op.completed_at {
+            events.push((at, true, i));
+        }
+    }
+    events.sort();
+    let mut entries = vec![Entry {
+        op: usize::MAX,
+        is_call: false,
+        ret: None,
+        prev: 0,
+        next: None,
+    }];
+    let mut call_entry = vec![0; ops.len()];
+    for (_, is_return, op) in events {
+        let idx = entries.len();
+        let prev = idx - 1;
+        entries[prev].next = Some(idx);
+        entries.push(Entry {
+            op,
+            is_call: !is_return,
+            ret: None,
+            prev,
+            next: None,
+        });
+        if is_return {
+            entries[call_entry[op]].ret = Some(idx);
+        } else {
+            call_entry[op] = idx;
+        }
+    }
+    let mut returns_left = ops.iter().filter(|op| op.completed_at.is_some()).count();
+
+    // Unlinks an entry, or puts it back where it was
+    fn lift(entries: &mut [Entry], i: usize) {
+        let Entry { prev, next, .. } = entries[i];
+        entries[prev].next = next;
+        if let Some(next) = next {
+            entries[next].prev = prev;
+        }
+    }
+    fn unlift(entries: &mut [Entry], i: usize) {
+        let Entry { prev, next, .. } = entries[i];
+        entries[prev].next = Some(i);
+        if let Some(next) = next {
+            entries[next].prev = i;
+        }
+    }
+
+    let mut state = model.init();
+    let mut linearized = Bitset::new(ops.len());
+    let mut cache: HashSet<(Bitset, M::State)> = HashSet::new();
+    let mut stack: Vec<(usize, M::State)> = Vec::new();
+    let mut entry = entries[0].next;
+    let mut furthest = 0;
+    while returns_left > 0 {
+        let Some(i) = entry else {
+            // Only ops that never returned are left, and they don't have to happen
+            break;
+        };
+        let e = entries[i];
+        if e.is_call {
+            let next_state = model.step(&state, ops[e.op]).filter(|s| {
+                let mut l = linearized.clone();
+                l.set(e.op);
+                cache.insert((l, s.clone()))
+            });
+            if let Some(next_state) = next_state {
+                stack.push((i, std::mem::replace(&mut state, next_state)));
+                linearized.set(e.op);
+                lift(&mut entries, i);
+                if let Some(ret) = e.ret {
+                    lift(&mut entries, ret);
+                    returns_left -= 1;
+                }
+                entry = entries[0].next;
+            } else {
+                entry = e.next;
+            }
+        } else {
+            furthest = furthest.max(e.op);
+            let Some((call, prev_state)) = stack.pop() else {
+                return Err(NotLinearizable { op: furthest });
+            };
+            let c = entries[call];
+            state = prev_state;
+            linearized.clear(c.op);
+            if let Some(ret) = c.ret {
+                unlift(&mut entries, ret);
+                returns_left += 1;
+            }
+            unlift(&mut entries, call);
+            entry = entries[call].next;
+        }
+    }
+    Ok(())
+}
+
+/// Checks every key of a multi-key register history (like lin-kv's) on its own. Returns the
+/// keys whose ops aren't linearizable.
+pub fn check_registers(history: &History) -> BTreeMap<String, NotLinearizable> {
+    let mut by_key: BTreeMap<String, Vec<&Op>> = BTreeMap::new();
+    for op in &history.ops {
+        if let Some(key) = op.input.get("key") {
+            by_key.entry(key.to_string()).or_default().push(op);
+        }
+    }
+    by_key
+        .into_iter()
+        .filter_map(|(key, ops)| check(&Register, &ops).err().map(|e| (key, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn op(f: &str, input: Value, output: Value, from: u64, to: Option<u64>) -> Op {
+        Op {
+            process: String::from("c1"),
+            f: f.to_string(),
+            input,
+            output,
+            outcome: if to.is_some() {
+                Outcome::Ok
+            } else {
+                Outcome::Info
+            },
+            invoked_at: Duration::from_millis(from),
+            completed_at: to.map(Duration::from_millis),
+        }
+    }
+
+    fn write(v: i64, from: u64, to: Option<u64>) -> Op {
+        op("write", json!({"key": 1, "value": v}), json!({}), from, to)
+    }
+
+    fn read(v: i64, from: u64, to: u64) -> Op {
+        op(
+            "read",
+            json!({"key": 1}),
+            json!({"value": v}),
+            from,
+            Some(to),
+        )
+    }
+
+    #[test]
+    fn concurrent_reads_can_see_either_value() {
+        let ops = [
+            write(1, 0, Some(10)),
+            write(2, 5, Some(15)),
+            read(1, 12, 20),
+        ];
+        assert_eq!(Ok(()), check(&Register, &ops.iter().collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn stale_read_is_caught() {
+        let ops = [
+            write(1, 0, Some(10)),
+            write(2, 11, Some(15)),
+            read(1, 20, 30),
+        ];
+        assert!(check(&Register, &ops.iter().collect::<Vec<_>>()).is_err());
+    }
+
+    #[test]
+    fn unfinished_ops_may_or_may_not_happen() {
+        let ops = [
+            write(1, 0, Some(10)),
+            write(2, 11, None),
+            read(1, 20, 30),
+            read(2, 40, 50),
+        ];
+        assert_eq!(Ok(()), check(&Register, &ops.iter().collect::<Vec<_>>()));
+        let ops = [write(1, 0, Some(10)), write(2, 11, None), read(1, 20, 30)];
+        assert_eq!(Ok(()), check(&Register, &ops.iter().collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn counter_reads() {
+        let add = |d: i64, from, to| op("add", json!({"delta": d}), json!({}), from, Some(to));
+        let read = |v: i64, from, to| op("read", json!({}), json!({"value": v}), from, Some(to));
+        let ops = [
+            add(1, 0, 10),
+            add(2, 5, 30),
+            read(1, 11, 20),
+            read(3, 31, 40),
+        ];
+        assert_eq!(Ok(()), check(&Counter, &ops.iter().collect::<Vec<_>>()));
+        let ops = [add(1, 0, 10), add(2, 11, 20), read(1, 21, 30)];
+        assert!(check(&Counter, &ops.iter().collect::<Vec<_>>()).is_err());
+    }
+}
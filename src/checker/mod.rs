@@ -0,0 +1,270 @@
+//! Histories of client operations and checkers for them.
+//!
+//! A [`History`] can be recorded from a simulation's journal or loaded from the `history.edn`
+//! Maelstrom leaves in `store/`. Operations keep the shape of the Maelstrom messages that carry
+//! them: `input` holds the fields of the request and `output` the fields of the reply, so
+//! `{"type":"send","key":"k","msg":1}` answered by `{"type":"send_ok","offset":3}` becomes an op
+//! with `f = "send"`, `input = {"key":"k","msg":1}` and `output = {"offset":3}`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use maelstrom::protocol::Message;
+use maelstrom::Result;
+use serde_json::{json, Map, Value};
+
+use crate::sim::Delivery;
+
+mod edn;
+pub mod kafka;
+pub mod linearizable;
+pub mod txn;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The operation took place
+    Ok,
+    /// The operation definitely did not take place
+    Fail,
+    /// We don't know: the request timed out or crashed
+    Info,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Op {
+    pub process: String,
+    pub f: String,
+    pub input: Value,
+    /// Reply fields. For failed ops these are the error's `code` and `text`.
+    pub output: Value,
+    pub outcome: Outcome,
+    pub invoked_at: Duration,
+    /// `None` for ops that never completed
+    pub completed_at: Option<Duration>,
+}
+
+impl Op {
+    /// Error code of a failed op
+    pub fn error_code(&self) -> Option<i64> {
+        match self.outcome {
+            Outcome::Fail => self.output.get("code").and_then(Value::as_i64),
+            _ => None,
+        }
+    }
+}
+
+/// Operations ordered by invocation time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct History {
+    pub ops: Vec<Op>,
+}
+
+// Maelstrom error codes after which the operation may or may not have happened
+const INDEFINITE_ERRORS: [i64; 2] = [0, 13];
+
+fn fields(msg: &Message) -> Value {
+    Value::Object(msg.body.extra.clone())
+}
+
+impl History {
+    pub fn push(&mut self, op: Op) {
+        self.ops.push(op);
+    }
+
+    /// Records every request a client sent to a node, and the reply it got.
+    pub fn from_journal(journal: &[Delivery]) -> Self {
+        History::from_journal_where(journal, |m| {
+            m.src.starts_with('c') && m.get_type() != "init"
+        })
+    }
+
+    /// Records the requests sent to one address, e.g. all the traffic `lin-kv` served.
+    pub fn from_journal_to(journal: &[Delivery], dest: &str) -> Self {
+        History::from_journal_where(journal, |m| m.dest == dest)
+    }
+
+    fn from_journal_where(journal: &[Delivery], is_request: impl Fn(&Message) -> bool) -> Self {
+        let mut history = History::default();
+        // (requester, responder, msg_id) -> index in history
+        let mut pending: HashMap<(String, String, u64), usize> = HashMap::new();
+        for Delivery { at, message } in journal {
+            if message.body.in_reply_to == 0 && message.body.msg_id != 0 && is_request(message) {
+                let key = (
+                    message.src.clone(),
+                    message.dest.clone(),
+                    message.body.msg_id,
+                );
+                // The nemesis may deliver a request twice; the first delivery is the invocation
+                if pending.contains_key(&key) {
+                    continue;
+                }
+                pending.insert(key, history.ops.len());
+                history.push(Op {
+                    process: message.src.clone(),
+                    f: message.get_type().to_string(),
+                    input: fields(message),
+                    output: Value::Null,
+                    outcome: Outcome::Info,
+                    invoked_at: *at,
+                    completed_at: None,
+                });
+                continue;
+            }
+            let key = (
+                message.dest.clone(),
+                message.src.clone(),
+                message.body.in_reply_to,
+            );
+            let Some(i) = pending.get(&key) else {
+                continue;
+            };
+            let op = &mut history.ops[*i];
+            if op.completed_at.is_some() {
+                continue;
+            }
+            op.output = fields(message);
+            op.outcome = if !message.body.is_error() {
+                Outcome::Ok
+            } else if op
+                .output
+                .get("code")
+                .and_then(Value::as_i64)
+                .is_some_and(|c| INDEFINITE_ERRORS.contains(&c))
+            {
+                Outcome::Info
+            } else {
+                Outcome::Fail
+            };
+            if op.outcome != Outcome::Info {
+                op.completed_at = Some(*at);
+            }
+        }
+        history
+    }
+
+    /// Loads a Jepsen `history.edn` as written by Maelstrom's lin-kv, g-counter, kafka and
+    /// txn-rw-register workloads.
+    pub fn from_edn(edn: &str) -> Result<Self> {
+        let mut history = History::default();
+        // process -> index of its outstanding invocation
+        let mut pending: HashMap<String, Vec<usize>> = HashMap::new();
+        for event in edn::parse_all(edn)? {
+            let process = match &event["process"] {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            let f = event["f"].as_str().unwrap_or_default().to_string();
+            let value = event["value"].clone();
+            let time = Duration::from_nanos(event["time"].as_u64().unwrap_or_default());
+            match event["type"].as_str() {
+                Some("invoke") => {
+                    let mut indices = Vec::new();
+                    for (f, input, _) in normalize(&f, &value) {
+                        indices.push(history.ops.len());
+                        history.push(Op {
+                            process: process.clone(),
+                            f,
+                            input,
+                            output: Value::Null,
+                            outcome: Outcome::Info,
+                            invoked_at: time,
+                            completed_at: None,
+                        });
+                    }
+                    pending.insert(process, indices);
+                }
+                Some(typ @ ("ok" | "fail")) => {
+                    let Some(indices) = pending.remove(&process) else {
+                        continue;
+                    };
+                    let outputs = normalize(&f, &value);
+                    for (i, (_, _, output)) in indices.into_iter().zip(outputs) {
+                        let op = &mut history.ops[i];
+                        if typ == "ok" {
+                            op.outcome = Outcome::Ok;
+                            op.output = output;
+                        } else {
+                            op.outcome = Outcome::Fail;
+                            op.output = json!({"text": event["error"].to_string()});
+                        }
+                        op.completed_at = Some(time);
+                    }
+                }
+                // :info leaves the op pending forever
+                _ => {
+                    pending.remove(&process);
+                }
+            }
+        }
+        Ok(history)
+    }
+}
+
+/// Turns a Jepsen op value into (f, request fields, reply fields) triples, one per Maelstrom
+/// request. Kafka ops carry several micro-ops, each of which was a request of its own.
+fn normalize(f: &str, value: &Value) -> Vec<(String, Value, Value)> {
+    let single = |input: Value, output: Value| vec![(f.to_string(), input, output)];
+    match (f, value) {
+        // Independent-key registers: [k v] or [k [from to]]
+        ("read", Value::Array(kv)) if kv.len() == 2 => single(
+            json!({"key": kv[0]}),
+            json!({"value": kv[1]}),
+        ),
+        ("write", Value::Array(kv)) if kv.len() == 2 => single(
+            json!({"key": kv[0], "value": kv[1]}),
+            Value::Object(Map::new()),
+        ),
+        ("cas", Value::Array(kv)) if kv.len() == 2 => single(
+            json!({"key": kv[0], "from": kv[1][0], "to": kv[1][1]}),
+            Value::Object(Map::new()),
+        ),
+        // g-counter
+        ("add", delta) => single(json!({"delta": delta}), Value::Object(Map::new())),
+        ("read", value) => single(json!({}), json!({"value": value})),
+        ("txn", Value::Array(mops))
+            if mops
+                .iter()
+                .all(|m| matches!(m[0].as_str(), Some("send" | "poll"))) =>
+        {
+            mops.iter()
+                .map(|m| match m[0].as_str() {
+                    Some("send") => (
+                        "send".to_string(),
+                        json!({"key": m[1], "msg": if m[2].is_array() { m[2][1].clone() } else { m[2].clone() }}),
+                        json!({"offset": m[2][0]}),
+                    ),
+                    _ => ("poll".to_string(), json!({}), json!({"msgs": m[1]})),
+                })
+                .collect()
+        }
+        ("send" | "poll", Value::Array(mops)) if mops.iter().all(Value::is_array) => {
+            normalize("txn", value)
+        }
+        ("txn", txn) => single(json!({"txn": txn}), json!({"txn": txn})),
+        (_, value) => single(value.clone(), value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pairs_edn_events() {
+        let edn = r#"
+{:type :invoke, :f :txn, :value [[:send "9" 28] [:poll]], :time 10, :process 0, :index 0}
+{:type :invoke, :f :write, :value [1 5], :time 11, :process 1, :index 1}
+{:type :ok, :f :txn, :value [[:send "9" [3 28]] [:poll {"9" [[3 28]]}]], :time 20, :process 0, :index 2}
+{:type :info, :f :write, :value [1 5], :time 25, :process 1, :index 3}
+"#;
+        let history = History::from_edn(edn).unwrap();
+        assert_eq!(3, history.ops.len());
+        let send = &history.ops[0];
+        assert_eq!(("send", Outcome::Ok), (send.f.as_str(), send.outcome));
+        assert_eq!(json!({"key": "9", "msg": 28}), send.input);
+        assert_eq!(json!({"offset": 3}), send.output);
+        assert_eq!(json!({"msgs": {"9": [[3, 28]]}}), history.ops[1].output);
+        assert_eq!(Outcome::Info, history.ops[2].outcome);
+        assert_eq!(None, history.ops[2].completed_at);
+    }
+}
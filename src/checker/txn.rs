@@ -0,0 +1,585 @@
+//! An Elle-style checker for read/write register transactions, as in the txn-rw-register
+//! workload ("Elle: Inferring Isolation Anomalies from Experimental Observations", Kingsbury &
+//! Alvaro, 2020).
+//!
+//! Like the workload, it relies on every write of a key having a unique value, so a read tells us
+//! exactly which transaction it observed. From the history we build a graph of dependencies
+//! between transactions:
+//!
+//! * write-read: `T2` read a value `T1` wrote
+//! * write-write: `T2` overwrote a value `T1` wrote
+//! * read-write: `T1` read a value that `T2` then overwrote
+//!
+//! and look for cycles in it, plus reads of aborted (G1a) and intermediate (G1b) values.
+//!
+//! Registers don't tell us the order in which versions were installed, so the version order is
+//! inferred: the initial `null` comes before everything, and a transaction that reads a key
+//! before writing it installed its write right after the version it read.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+
+use serde_json::Value;
+
+use super::{History, Op, Outcome};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Dependency {
+    WriteWrite,
+    WriteRead,
+    ReadWrite,
+}
+
+/// A cycle of transactions: each entry is the index of an op in the history and the dependency
+/// from it to the next entry (the last one wraps around to the first).
+pub type Cycle = Vec<(usize, Dependency)>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Anomaly {
+    /// Dirty write: a cycle of write-write dependencies
+    G0(Cycle),
+    /// Aborted read: `op` read a value that `writer`, which failed, wrote
+    G1a {
+        op: usize,
+        writer: usize,
+        key: Value,
+        value: Value,
+    },
+    /// Intermediate read: `op` read a value that `writer` overwrote later in the same transaction
+    G1b {
+        op: usize,
+        writer: usize,
+        key: Value,
+        value: Value,
+    },
+    /// Circular information flow: a cycle of write-write and write-read dependencies
+    G1c(Cycle),
+    /// Read skew: a cycle with exactly one read-write dependency
+    GSingle(Cycle),
+    /// Anti-dependency cycle: a cycle with several read-write dependencies
+    G2(Cycle),
+}
+
+impl Anomaly {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Anomaly::G0(_) => "G0",
+            Anomaly::G1a { .. } => "G1a",
+            Anomaly::G1b { .. } => "G1b",
+            Anomaly::G1c(_) => "G1c",
+            Anomaly::GSingle(_) => "G-single",
+            Anomaly::G2(_) => "G2",
+        }
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::G0(cycle)
+            | Anomaly::G1c(cycle)
+            | Anomaly::GSingle(cycle)
+            | Anomaly::G2(cycle) => {
+                write!(f, "{}:", self.name())?;
+                for (op, dep) in cycle {
+                    write!(f, " op {} -{:?}->", op, dep)?;
+                }
+                write!(f, " op {}", cycle[0].0)
+            }
+            Anomaly::G1a {
+                op,
+                writer,
+                key,
+                value,
+            }
+            | Anomaly::G1b {
+                op,
+                writer,
+                key,
+                value,
+            } => write!(
+                f,
+                "{}: op {} read {} = {} written by op {}",
+                self.name(),
+                op,
+                key,
+                value,
+                writer
+            ),
+        }
+    }
+}
+
+/// Isolation levels, weakest first, with the anomalies each one proscribes (after Adya).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    SnapshotIsolation,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub fn allows(&self, anomaly: &Anomaly) -> bool {
+        let proscribed = match anomaly {
+            Anomaly::G0(_) => IsolationLevel::ReadUncommitted,
+            Anomaly::G1a { .. } | Anomaly::G1b { .. } | Anomaly::G1c(_) => {
+                IsolationLevel::ReadCommitted
+            }
+            Anomaly::GSingle(_) => IsolationLevel::SnapshotIsolation,
+            Anomaly::G2(_) => IsolationLevel::Serializable,
+        };
+        *self < proscribed
+    }
+}
+
+/// Checks a history against an isolation level, returning the anomalies it doesn't allow.
+pub fn check_isolation(history: &History, level: IsolationLevel) -> Result<(), Vec<Anomaly>> {
+    let anomalies: Vec<Anomaly> = check(history)
+        .into_iter()
+        .filter(|a| !level.allows(a))
+        .collect();
+    if anomalies.is_empty() {
+        Ok(())
+    } else {
+        Err(anomalies)
+    }
+}
+
+struct MicroOp {
+    read: bool,
+    key: String,
+    value: Value,
+}
+
+// The micro-ops of a txn op: what the reply said for completed ones, what was asked otherwise
+fn micro_ops(op: &Op) -> Vec<MicroOp> {
+    let txn = if op.outcome == Outcome::Ok {
+        &op.output["txn"]
+    } else {
+        &op.input["txn"]
+    };
+    txn.as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|m| {
+            Some(MicroOp {
+                read: m[0].as_str()? == "r",
+                key: m[1].to_string(),
+                value: m[2].clone(),
+            })
+        })
+        .collect()
+}
+
+struct Write {
+    op: usize,
+    // Whether the op wrote the key again afterwards
+    overwritten: bool,
+}
+
+/// Finds every anomaly in the txn ops of `history`.
+pub fn check(history: &History) -> Vec<Anomaly> {
+    let txns: BTreeMap<usize, Vec<MicroOp>> = history
+        .ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| op.f == "txn")
+        .map(|(i, op)| (i, micro_ops(op)))
+        .collect();
+    let outcome = |i: usize| history.ops[i].outcome;
+
+    // (key, value) -> the write that produced it
+    let mut writes: HashMap<(String, String), Write> = HashMap::new();
+    for (&i, mops) in &txns {
+        for (j, m) in mops.iter().enumerate() {
+            if m.read {
+                continue;
+            }
+            let overwritten = mops[j + 1..].iter().any(|n| !n.read && n.key == m.key);
+            writes.insert(
+                (m.key.clone(), m.value.to_string()),
+                Write { op: i, overwritten },
+            );
+        }
+    }
+
+    let mut anomalies = Vec::new();
+    // Reads of other txns' writes, as (reader, key, value) with value not null
+    let mut external_reads: Vec<(usize, String, String)> = Vec::new();
+    // (key, version read, version written) by txns that read a key and then wrote it
+    let mut version_edges: BTreeSet<(String, String, String)> = BTreeSet::new();
+    let mut null_reads: Vec<(usize, String)> = Vec::new();
+    for (&i, mops) in txns.iter().filter(|(&i, _)| outcome(i) == Outcome::Ok) {
+        let mut written: BTreeSet<&str> = BTreeSet::new();
+        let mut last_read: BTreeMap<&str, String> = BTreeMap::new();
+        for m in mops {
+            if !m.read {
+                if let Some(read) = last_read.get(m.key.as_str()) {
+                    if !written.contains(m.key.as_str()) {
+                        version_edges.insert((m.key.clone(), read.clone(), m.value.to_string()));
+                    }
+                }
+                written.insert(&m.key);
+                continue;
+            }
+            // Reads of our own writes say nothing about other txns
+            if written.contains(m.key.as_str()) {
+                continue;
+            }
+            last_read.insert(&m.key, m.value.to_string());
+            if m.value.is_null() {
+                null_reads.push((i, m.key.clone()));
+                continue;
+            }
+            let Some(write) = writes.get(&(m.key.clone(), m.value.to_string())) else {
+                continue;
+            };
+            if write.op == i {
+                continue;
+            }
+            if outcome(write.op) == Outcome::Fail {
+                anomalies.push(Anomaly::G1a {
+                    op: i,
+                    writer: write.op,
+                    key: serde_json::from_str(&m.key).unwrap_or(Value::Null),
+                    value: m.value.clone(),
+                });
+            } else if write.overwritten {
+                anomalies.push(Anomaly::G1b {
+                    op: i,
+                    writer: write.op,
+                    key: serde_json::from_str(&m.key).unwrap_or(Value::Null),
+                    value: m.value.clone(),
+                });
+            }
+            external_reads.push((i, m.key.clone(), m.value.to_string()));
+        }
+    }
+
+    // Txns that took effect: the ones that completed, and those we don't know about whose
+    // writes were seen
+    let mut committed: BTreeSet<usize> = txns
+        .keys()
+        .copied()
+        .filter(|&i| outcome(i) == Outcome::Ok)
+        .collect();
+    for (_, key, value) in &external_reads {
+        let write = &writes[&(key.clone(), value.clone())];
+        if outcome(write.op) == Outcome::Info {
+            committed.insert(write.op);
+        }
+    }
+    let writer = |key: &str, value: &str| {
+        writes
+            .get(&(key.to_string(), value.to_string()))
+            .map(|w| w.op)
+            .filter(|op| committed.contains(op))
+    };
+
+    let mut graph = Graph::default();
+    for (reader, key, value) in &external_reads {
+        if let Some(w) = writer(key, value) {
+            graph.add(w, *reader, Dependency::WriteRead);
+        }
+    }
+    for (key, from, to) in &version_edges {
+        let (Some(a), Some(b)) = (writer(key, from), writer(key, to)) else {
+            continue;
+        };
+        graph.add(a, b, Dependency::WriteWrite);
+        for (reader, k, v) in &external_reads {
+            if k == key && v == from {
+                graph.add(*reader, b, Dependency::ReadWrite);
+            }
+        }
+    }
+    // Whoever read the initial null came before every write of that key
+    for (reader, key) in &null_reads {
+        for ((k, _), write) in &writes {
+            if k == key && committed.contains(&write.op) {
+                graph.add(*reader, write.op, Dependency::ReadWrite);
+            }
+        }
+    }
+    graph.remove_self_loops();
+    anomalies.extend(graph.cycles());
+    anomalies
+}
+
+#[derive(Default)]
+struct Graph {
+    edges: BTreeMap<usize, BTreeSet<(usize, Dependency)>>,
+}
+
+impl Graph {
+    fn add(&mut self, from: usize, to: usize, dep: Dependency) {
+        self.edges.entry(from).or_default().insert((to, dep));
+    }
+
+    fn remove_self_loops(&mut self) {
+        for (from, out) in self.edges.iter_mut() {
+            out.retain(|(to, _)| to != from);
+        }
+    }
+
+    /// Strongly connected components with more than one node (Tarjan)
+    fn components(&self) -> Vec<BTreeSet<usize>> {
+        struct Tarjan<'a> {
+            graph: &'a Graph,
+            index: HashMap<usize, usize>,
+            low: HashMap<usize, usize>,
+            stack: Vec<usize>,
+            on_stack: BTreeSet<usize>,
+            components: Vec<BTreeSet<usize>>,
+        }
+        impl Tarjan<'_> {
+            fn visit(&mut self, v: usize) {
+                let i = self.index.len();
+                self.index.insert(v, i);
+                self.low.insert(v, i);
+                self.stack.push(v);
+                self.on_stack.insert(v);
+                for &(w, _) in self.graph.edges.get(&v).into_iter().flatten() {
+                    if !self.index.contains_key(&w) {
+                        self.visit(w);
+                        self.low.insert(v, self.low[&v].min(self.low[&w]));
+                    } else if self.on_stack.contains(&w) {
+                        self.low.insert(v, self.low[&v].min(self.index[&w]));
+                    }
+                }
+                if self.low[&v] == self.index[&v] {
+                    let mut component = BTreeSet::new();
+                    while let Some(w) = self.stack.pop() {
+                        self.on_stack.remove(&w);
+                        component.insert(w);
+                        if w == v {
+                            break;
+                        }
+                    }
+                    if component.len() > 1 {
+                        self.components.push(component);
+                    }
+                }
+            }
+        }
+        let mut tarjan = Tarjan {
+            graph: self,
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: Vec::new(),
+            on_stack: BTreeSet::new(),
+            components: Vec::new(),
+        };
+        for &v in self.edges.keys() {
+            if !tarjan.index.contains_key(&v) {
+                tarjan.visit(v);
+            }
+        }
+        tarjan.components
+    }
+
+    /// Shortest path from `from` to `to` within `nodes`, using only edges `allowed` accepts
+    fn path(
+        &self,
+        from: usize,
+        to: usize,
+        nodes: &BTreeSet<usize>,
+        allowed: impl Fn(Dependency) -> bool,
+    ) -> Option<Cycle> {
+        let mut came_from: HashMap<usize, (usize, Dependency)> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(v) = queue.pop_front() {
+            for &(w, dep) in self.edges.get(&v).into_iter().flatten() {
+                if !nodes.contains(&w) || !allowed(dep) || came_from.contains_key(&w) {
+                    continue;
+                }
+                came_from.insert(w, (v, dep));
+                if w == to {
+                    let mut path = Vec::new();
+                    let mut at = to;
+                    loop {
+                        let (prev, dep) = came_from[&at];
+                        path.push((prev, dep));
+                        at = prev;
+                        if at == from {
+                            break;
+                        }
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                queue.push_back(w);
+            }
+        }
+        None
+    }
+
+    /// One anomaly per strongly connected component, the worst kind of cycle it contains
+    fn cycles(&self) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+        for nodes in self.components() {
+            let cycle_through = |allowed: &dyn Fn(Dependency) -> bool| {
+                nodes.iter().find_map(|&v| self.path(v, v, &nodes, allowed))
+            };
+            if let Some(cycle) = cycle_through(&|d| d == Dependency::WriteWrite) {
+                anomalies.push(Anomaly::G0(cycle));
+                continue;
+            }
+            if let Some(cycle) = cycle_through(&|d| d != Dependency::ReadWrite) {
+                anomalies.push(Anomaly::G1c(cycle));
+                continue;
+            }
+            // A single read-write edge closed by write-write and write-read ones
+            let single = nodes.iter().find_map(|&v| {
+                self.edges
+                    .get(&v)
+                    .into_iter()
+                    .flatten()
+                    .find_map(|&(w, dep)| {
+                        if dep != Dependency::ReadWrite || !nodes.contains(&w) {
+                            return None;
+                        }
+                        let mut cycle = self.path(w, v, &nodes, |d| d != Dependency::ReadWrite)?;
+                        cycle.insert(0, (v, dep));
+                        Some(cycle)
+                    })
+            });
+            if let Some(cycle) = single {
+                anomalies.push(Anomaly::GSingle(cycle));
+            } else if let Some(cycle) = cycle_through(&|_| true) {
+                anomalies.push(Anomaly::G2(cycle));
+            }
+        }
+        anomalies
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn txn(outcome: Outcome, ops: Value, at: u64) -> Op {
+        Op {
+            process: String::from("c1"),
+            f: String::from("txn"),
+            input: json!({ "txn": ops }),
+            output: if outcome == Outcome::Ok {
+                json!({ "txn": ops })
+            } else {
+                json!({"code": 30, "text": "txn-conflict"})
+            },
+            outcome,
+            invoked_at: Duration::from_millis(at),
+            completed_at: (outcome != Outcome::Info).then(|| Duration::from_millis(at + 1)),
+        }
+    }
+
+    fn ok(ops: Value) -> Op {
+        txn(Outcome::Ok, ops, 0)
+    }
+
+    fn names(history: &History) -> Vec<&'static str> {
+        check(history).iter().map(Anomaly::name).collect()
+    }
+
+    #[test]
+    fn serial_history_is_serializable() {
+        let history = History {
+            ops: vec![
+                ok(json!([["r", 1, null], ["w", 1, 1]])),
+                ok(json!([["r", 1, 1], ["w", 1, 2], ["w", 2, 1]])),
+                ok(json!([["r", 1, 2], ["r", 2, 1]])),
+            ],
+        };
+        assert_eq!(
+            Ok(()),
+            check_isolation(&history, IsolationLevel::Serializable)
+        );
+    }
+
+    #[test]
+    fn aborted_and_intermediate_reads() {
+        let history = History {
+            ops: vec![
+                txn(Outcome::Fail, json!([["w", 1, 1]]), 0),
+                ok(json!([["w", 2, 1], ["w", 2, 2]])),
+                ok(json!([["r", 1, 1], ["r", 2, 1]])),
+            ],
+        };
+        let anomalies = check(&history);
+        assert_eq!(vec!["G1a", "G1b"], names(&history));
+        assert_eq!(
+            Anomaly::G1a {
+                op: 2,
+                writer: 0,
+                key: json!(1),
+                value: json!(1)
+            },
+            anomalies[0]
+        );
+        assert!(IsolationLevel::ReadUncommitted.allows(&anomalies[0]));
+        assert!(check_isolation(&history, IsolationLevel::ReadCommitted).is_err());
+    }
+
+    #[test]
+    fn circular_information_flow() {
+        // Each txn saw the other's write
+        let history = History {
+            ops: vec![
+                ok(json!([["w", 1, 1], ["r", 2, 1]])),
+                ok(json!([["w", 2, 1], ["r", 1, 1]])),
+            ],
+        };
+        assert_eq!(vec!["G1c"], names(&history));
+        assert_eq!(
+            Anomaly::G1c(vec![(0, Dependency::WriteRead), (1, Dependency::WriteRead)]),
+            check(&history)[0]
+        );
+    }
+
+    #[test]
+    fn dirty_writes() {
+        // T2 overwrote T1 on key 1 and T3 overwrote T2 on key 2, but T1 overwrote T3 on key 2
+        let history = History {
+            ops: vec![
+                ok(json!([["w", 1, 1], ["r", 2, 3], ["w", 2, 1]])),
+                ok(json!([["r", 1, 1], ["w", 1, 2], ["w", 2, 2]])),
+                ok(json!([["r", 2, 2], ["w", 2, 3]])),
+            ],
+        };
+        assert_eq!(vec!["G0"], names(&history));
+    }
+
+    #[test]
+    fn read_skew_and_write_skew() {
+        // T2 read key 1 before T1's write and key 2 after it
+        let history = History {
+            ops: vec![
+                ok(json!([["w", 1, 1], ["w", 2, 1]])),
+                ok(json!([["r", 1, null], ["r", 2, 1]])),
+            ],
+        };
+        assert_eq!(vec!["G-single"], names(&history));
+        assert_eq!(
+            Ok(()),
+            check_isolation(&history, IsolationLevel::ReadCommitted)
+        );
+        assert!(check_isolation(&history, IsolationLevel::SnapshotIsolation).is_err());
+
+        // Both read the initial state and wrote the key the other read
+        let history = History {
+            ops: vec![
+                ok(json!([["r", 1, null], ["w", 2, 1]])),
+                ok(json!([["r", 2, null], ["w", 1, 1]])),
+            ],
+        };
+        assert_eq!(vec!["G2"], names(&history));
+        assert_eq!(
+            Ok(()),
+            check_isolation(&history, IsolationLevel::SnapshotIsolation)
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! Messages of the grow-only counter workload (challenge 4).
+
+use serde::{Deserialize, Serialize};
+
+/// The seq-kv key all nodes keep the counter under
+pub static COUNTER_KEY: &str = "counter";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestBody {
+    Add {
+        delta: u64,
+    },
+    Read,
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseBody {
+    ReadOk { value: u64 },
+}
@@ -0,0 +1,178 @@
+//! Errors handlers send back to clients, with the codes Maelstrom defines
+//! (<https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>).
+//!
+//! maelstrom-node replies with an `error` body when `Node::process` fails with a
+//! [`maelstrom::Error`], and stops the whole node for any other error. [`Error`] converts into the
+//! former keeping its text, so handlers can use `?` on it and the client gets a proper error
+//! reply.
+
+use std::fmt;
+
+use maelstrom::protocol::Message;
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Timeout,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+}
+
+impl ErrorCode {
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+        }
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        Some(match code {
+            0 => ErrorCode::Timeout,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            _ => return None,
+        })
+    }
+
+    /// Whether the operation definitely didn't happen. Timeouts and crashes leave it unknown.
+    pub fn is_definite(self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Error {
+            code,
+            text: text.into(),
+        }
+    }
+
+    pub fn malformed(text: impl Into<String>) -> Self {
+        Error::new(ErrorCode::MalformedRequest, text)
+    }
+
+    pub fn crash(text: impl Into<String>) -> Self {
+        Error::new(ErrorCode::Crash, text)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error({}): {}", self.code.code(), self.text)
+    }
+}
+
+// Not `std::error::Error`: boxing through that would hide it from maelstrom-node, which only
+// turns `maelstrom::Error`s into replies.
+impl From<Error> for Box<dyn std::error::Error + Send + Sync> {
+    fn from(error: Error) -> Self {
+        Box::new(maelstrom::Error::Custom(error.code.code(), error.text))
+    }
+}
+
+impl From<maelstrom::Error> for Error {
+    fn from(error: maelstrom::Error) -> Self {
+        let code = ErrorCode::from_code(error.code()).unwrap_or(ErrorCode::Crash);
+        Error::new(code, error.description())
+    }
+}
+
+/// Whether a call to another node or service failed with `code`
+pub fn failed_with(e: &(dyn std::error::Error + Send + Sync + 'static), code: ErrorCode) -> bool {
+    e.downcast_ref::<maelstrom::Error>()
+        .is_some_and(|e| e.code() == code.code())
+}
+
+/// Parses the body of a request. Types the handler doesn't know are `not-supported`, anything
+/// else it can't parse is a `malformed-request`.
+pub fn parse<'de, T: Deserialize<'de>>(req: &Message) -> Result<T, Error> {
+    req.body.as_obj().map_err(|e| {
+        let text = e.to_string();
+        if text.starts_with("unknown variant") {
+            Error::new(
+                ErrorCode::NotSupported,
+                format!("{}: {}", req.get_type(), text),
+            )
+        } else {
+            Error::malformed(text)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maelstrom::protocol::MessageBody;
+    use serde_json::json;
+
+    #[derive(Deserialize, Debug)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum RequestBody {
+        #[allow(dead_code)]
+        Add { delta: u64 },
+    }
+
+    fn request(body: serde_json::Value) -> Message {
+        Message {
+            src: String::from("c1"),
+            dest: String::from("n0"),
+            body: serde_json::from_value::<MessageBody>(body).unwrap(),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = parse::<RequestBody>(&request(json!({"type": "add", "delta": "x"}))).unwrap_err();
+        assert_eq!(ErrorCode::MalformedRequest, err.code);
+        let err = parse::<RequestBody>(&request(json!({"type": "subtract"}))).unwrap_err();
+        assert_eq!(ErrorCode::NotSupported, err.code);
+        assert!(err.text.starts_with("subtract"));
+    }
+
+    #[test]
+    fn converts_to_maelstrom_errors() {
+        let boxed: Box<dyn std::error::Error + Send + Sync> =
+            Error::new(ErrorCode::TxnConflict, "someone else wrote k").into();
+        let err = boxed.downcast_ref::<maelstrom::Error>().unwrap();
+        assert_eq!(30, err.code());
+        assert_eq!("someone else wrote k", err.description());
+        assert_eq!(
+            Error::new(ErrorCode::KeyDoesNotExist, "key does not exist"),
+            Error::from(maelstrom::Error::KeyDoesNotExist)
+        );
+        for code in [0, 10, 11, 12, 13, 14, 20, 21, 22, 30] {
+            assert_eq!(code, ErrorCode::from_code(code).unwrap().code());
+        }
+        assert!(!ErrorCode::Crash.is_definite());
+    }
+}
@@ -0,0 +1,716 @@
+//! A store that also writes everything to disk, so that a single node can be restarted without
+//! losing its logs, e.g. to use it as a tiny local broker outside Maelstrom.
+//!
+//! The data directory holds:
+//! - `logs/<key in hex>.log`, one per key, with a JSON record per line for each message. Keys
+//!   too long for that get `logs/long-<n>.log` instead, listed in `long-keys.log`.
+//! - `offsets.log`, with a JSON record per line for each commit
+//!
+//! Records are appended, and the state is rebuilt from them on startup. A crash in the middle of
+//! an append can only leave the last line of a file torn, which recovery drops: its request was
+//! never acknowledged. Once retention dropped enough of a key's messages, its log file is
+//! rewritten with only the ones it kept, after a record standing in for the dropped ones. The
+//! new file is written next to the old one, as `.tmp`, and renamed over it.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::warn;
+use maelstrom::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::store::State;
+use super::{Fetch, Log, LogStore, Msg, Offsets, Producer, Retention, Sequence};
+use crate::error;
+
+/// The longest key whose log file is named after it. Twice that in hex, plus `.log`, has to fit
+/// in the 255 bytes most file systems allow a name.
+const MAX_NAMED_KEY: usize = 120;
+
+/// How many records retention dropped a log file has to hold before it's compacted, at least.
+/// It also has to hold as many as it keeps, so that compaction costs no more than the appends.
+const COMPACT_AFTER: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct MessageRecord {
+    offset: usize,
+    msg: Msg,
+    /// Kept so that retries are still recognised after a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    producer: Option<Producer>,
+}
+
+/// The first record of a compacted log file, in place of the ones compaction dropped
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct StartRecord {
+    /// The offset of the file's first message, or of the next one if it has none
+    next_offset: usize,
+    /// Kept like [`MessageRecord::producer`], from the dropped messages too
+    producers: HashMap<String, VecDeque<(u64, usize)>>,
+}
+
+/// A line of a log file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum LogRecord {
+    Message(MessageRecord),
+    Start(StartRecord),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct CommitRecord {
+    group: Option<String>,
+    offsets: Offsets,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct LongKeyRecord {
+    key: String,
+    file: String,
+}
+
+/// Keeps everything in memory like [`super::MemoryStore`], and appends every send and commit to
+/// files in a data directory before acknowledging it. Retention applies to memory, and log files
+/// are compacted after appends once they hold more than [`COMPACT_AFTER`] records it dropped,
+/// and more than they keep.
+///
+/// Writes run on tokio's blocking threads, and each key's log has a lock of its own, so a send
+/// waiting for the disk only holds up later sends to the same key. Log files are opened for each
+/// append and closed after it, so the store doesn't hold a file open per key it ever saw.
+pub struct DiskStore {
+    dir: PathBuf,
+    /// Whether to wait for records to reach the disk before acknowledging them. Without it a
+    /// crash of the machine, rather than the process, can lose acknowledged sends.
+    fsync: bool,
+    retention: Retention,
+    /// Never held while waiting for the disk
+    state: Mutex<State>,
+    /// Held for the whole of an append to the key, so that its records are written in order
+    logs: std::sync::Mutex<HashMap<String, LogFile>>,
+    offsets: Mutex<File>,
+    long_keys: Arc<std::sync::Mutex<LongKeys>>,
+}
+
+/// How many records a key's log file holds, behind the lock on it
+type LogFile = Arc<Mutex<usize>>;
+
+/// The files of the keys longer than [`MAX_NAMED_KEY`]
+struct LongKeys {
+    files: HashMap<String, String>,
+    index: File,
+}
+
+impl DiskStore {
+    /// Opens the data directory `dir`, creating it if needed, and rebuilds the state from it
+    pub fn open(dir: impl Into<PathBuf>, fsync: bool, retention: Retention) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join("logs"))?;
+        let mut state = State::default();
+
+        // Commits first, so that retention can drop what's below them while replaying the logs
+        let offsets_path = dir.join("offsets.log");
+        for record in read_records::<CommitRecord>(&offsets_path)? {
+            state.commit_offsets(record.group.as_deref(), record.offsets, &retention);
+        }
+        let long_keys_path = dir.join("long-keys.log");
+        let mut files = HashMap::new();
+        let mut long_keys_by_file = HashMap::new();
+        for record in read_records::<LongKeyRecord>(&long_keys_path)? {
+            long_keys_by_file.insert(record.file.clone(), record.key.clone());
+            files.insert(record.key, record.file);
+        }
+        let mut logs = HashMap::new();
+        for entry in fs::read_dir(dir.join("logs"))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                // A compaction that didn't get to replace its file
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let long_key = path
+                .file_name()
+                .and_then(|name| long_keys_by_file.get(name.to_str()?))
+                .cloned();
+            let Some(key) = long_key.or_else(|| key_of(&path)) else {
+                warn!("skipping {}, which isn't a log", path.display());
+                continue;
+            };
+            let records = read_records::<LogRecord>(&path)?;
+            logs.insert(key.clone(), Arc::new(Mutex::new(records.len())));
+            for (i, record) in records.into_iter().enumerate() {
+                let record = match record {
+                    LogRecord::Message(record) => record,
+                    LogRecord::Start(start) if i == 0 => {
+                        let log = Log::resume(start.next_offset, start.producers);
+                        state.restore(&key, log);
+                        continue;
+                    }
+                    LogRecord::Start(_) => {
+                        return Err(corrupt(&path, "start record after the first line"));
+                    }
+                };
+                if record.offset != state.next_offset(&key) {
+                    return Err(corrupt(&path, "offsets out of sequence"));
+                }
+                let producer = record.producer.as_ref();
+                state
+                    .append(&key, record.msg, producer, &retention)
+                    .map_err(|e| corrupt(&path, &e.text))?;
+            }
+        }
+
+        let offsets = append_to(&offsets_path)?;
+        let index = append_to(&long_keys_path)?;
+        Ok(DiskStore {
+            dir,
+            fsync,
+            retention,
+            state: Mutex::new(state),
+            logs: std::sync::Mutex::new(logs),
+            offsets: Mutex::new(offsets),
+            long_keys: Arc::new(std::sync::Mutex::new(LongKeys { files, index })),
+        })
+    }
+
+    fn log_file(&self, key: &str) -> LogFile {
+        let mut logs = self.logs.lock().unwrap();
+        logs.entry(key.to_string()).or_default().clone()
+    }
+
+    /// Opens the log file of `key`, which the caller holds the lock on
+    async fn open_log(&self, key: &str) -> Result<File> {
+        let (dir, key) = (self.dir.clone(), key.to_string());
+        let (long_keys, fsync) = (self.long_keys.clone(), self.fsync);
+        Ok(blocking(move || open_file(&dir, &key, &long_keys, fsync)).await?)
+    }
+
+    /// Rewrites the log file of `key`, which holds `records` and which the caller holds the lock
+    /// on, if retention dropped enough of them. Only logs a failure, the append it follows went
+    /// through.
+    async fn compact(&self, key: &str, records: &mut usize) {
+        let (start, kept) = {
+            let state = self.state.lock().await;
+            let Some(log) = state.log(key) else {
+                return;
+            };
+            let dropped = records.saturating_sub(log.retained());
+            if dropped < COMPACT_AFTER || dropped < log.retained() {
+                return;
+            }
+            let start = StartRecord {
+                next_offset: log.earliest(),
+                producers: log.producers().clone(),
+            };
+            (start, log.read_from(log.earliest(), None).msgs)
+        };
+        let mut compacted = vec![LogRecord::Start(start)];
+        for (offset, msg) in kept {
+            // The start record has their producers' sends
+            let record = MessageRecord {
+                offset,
+                msg,
+                producer: None,
+            };
+            compacted.push(LogRecord::Message(record));
+        }
+        let (dir, name) = (self.dir.clone(), key.to_string());
+        let (long_keys, fsync) = (self.long_keys.clone(), self.fsync);
+        let count = compacted.len();
+        let compacted = blocking(move || {
+            let path = log_path(&dir, &name, &long_keys, fsync)?;
+            rewrite(&path, &encode(&compacted)?, fsync)
+        });
+        match compacted.await {
+            Ok(()) => *records = count,
+            Err(e) => warn!("couldn't compact the log of key {}: {}", key, e),
+        }
+    }
+
+    /// Appends `records` to `file`, waiting for them to reach the disk if configured to
+    async fn write<T: Serialize>(&self, file: &File, records: &[T]) -> Result<()> {
+        let lines = encode(records).map_err(crash)?;
+        let mut file = file.try_clone().map_err(crash)?;
+        let fsync = self.fsync;
+        Ok(blocking(move || append_lines(&mut file, &lines, fsync)).await?)
+    }
+}
+
+#[async_trait]
+impl LogStore for DiskStore {
+    async fn append(&self, key: &str, msg: Msg, producer: Option<&Producer>) -> Result<usize> {
+        let log = self.log_file(key);
+        let mut records = log.lock().await;
+        let record = {
+            let state = self.state.lock().await;
+            match state.sequence(key, producer) {
+                Sequence::New => {}
+                Sequence::Duplicate(offset) => return Ok(offset),
+                Sequence::Stale => return Err(producer.unwrap().stale().into()),
+            }
+            MessageRecord {
+                offset: state.next_offset(key),
+                msg,
+                producer: producer.cloned(),
+            }
+        };
+        let file = self.open_log(key).await?;
+        self.write(&file, std::slice::from_ref(&record)).await?;
+        *records += 1;
+        let offset = {
+            let mut state = self.state.lock().await;
+            state.append(key, record.msg, producer, &self.retention)?
+        };
+        self.compact(key, &mut records).await;
+        Ok(offset)
+    }
+
+    async fn append_batch(&self, key: &str, msgs: &[Msg]) -> Result<Vec<usize>> {
+        let log = self.log_file(key);
+        let mut records = log.lock().await;
+        let first = self.state.lock().await.next_offset(key);
+        let batch: Vec<MessageRecord> = (first..)
+            .zip(msgs)
+            .map(|(offset, msg)| MessageRecord {
+                offset,
+                msg: msg.clone(),
+                producer: None,
+            })
+            .collect();
+        let file = self.open_log(key).await?;
+        self.write(&file, &batch).await?;
+        *records += batch.len();
+        let offsets = {
+            let mut state = self.state.lock().await;
+            state.append_batch(key, msgs, &self.retention)
+        };
+        self.compact(key, &mut records).await;
+        Ok(offsets)
+    }
+
+    async fn read_from(&self, key: &str, offset: usize, max: Option<usize>) -> Result<Fetch> {
+        let mut state = self.state.lock().await;
+        Ok(state.read_from(key, offset, max, &self.retention))
+    }
+
+    async fn commit_offsets(&self, group: Option<&str>, offsets: Offsets) -> Result<()> {
+        let file = self.offsets.lock().await;
+        let record = CommitRecord {
+            group: group.map(String::from),
+            offsets,
+        };
+        self.write(&file, std::slice::from_ref(&record)).await?;
+        let mut state = self.state.lock().await;
+        state.commit_offsets(group, record.offsets, &self.retention);
+        Ok(())
+    }
+
+    async fn committed_offsets(&self, group: Option<&str>, keys: &[String]) -> Result<Offsets> {
+        let state = self.state.lock().await;
+        Ok(state.committed_offsets(group, keys))
+    }
+}
+
+/// Runs `f`, which waits on the disk, on tokio's blocking threads instead of the caller's
+async fn blocking<T, F>(f: F) -> std::result::Result<T, error::Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result.map_err(crash),
+        Err(e) => Err(error::Error::crash(format!(
+            "writing to disk failed: {}",
+            e
+        ))),
+    }
+}
+
+/// Opens the log file of `key` for appending, creating it, and its entry in `long_keys` if the
+/// key needs one, if it doesn't exist yet
+fn open_file(
+    dir: &Path,
+    key: &str,
+    long_keys: &std::sync::Mutex<LongKeys>,
+    fsync: bool,
+) -> io::Result<File> {
+    let path = log_path(dir, key, long_keys, fsync)?;
+    let new = !path.exists();
+    let file = append_to(&path)?;
+    if fsync && new {
+        // Make sure the new file itself survives a crash, not just what's in it
+        File::open(path.parent().unwrap())?.sync_all()?;
+    }
+    Ok(file)
+}
+
+/// The path of the log file of `key`, after giving it an entry in `long_keys` if it needs one
+/// and has none yet
+fn log_path(
+    dir: &Path,
+    key: &str,
+    long_keys: &std::sync::Mutex<LongKeys>,
+    fsync: bool,
+) -> io::Result<PathBuf> {
+    let name = match named_log(key) {
+        Some(name) => name,
+        None => {
+            let mut long_keys = long_keys.lock().unwrap();
+            match long_keys.files.get(key) {
+                Some(name) => name.clone(),
+                None => {
+                    let name = format!("long-{}.log", long_keys.files.len());
+                    let record = LongKeyRecord {
+                        key: key.to_string(),
+                        file: name.clone(),
+                    };
+                    // Before the file exists, so that recovery always knows whose it is
+                    let lines = encode(std::slice::from_ref(&record))?;
+                    append_lines(&mut long_keys.index, &lines, fsync)?;
+                    long_keys.files.insert(record.key, name.clone());
+                    name
+                }
+            }
+        }
+    };
+    Ok(dir.join("logs").join(name))
+}
+
+/// Replaces the file at `path` with one holding `lines`, all at once even if there's a crash
+/// in between
+fn rewrite(path: &Path, lines: &[u8], fsync: bool) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(lines)?;
+    if fsync {
+        file.sync_data()?;
+    }
+    fs::rename(&tmp, path)?;
+    if fsync {
+        File::open(path.parent().unwrap())?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn encode<T: Serialize>(records: &[T]) -> io::Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut lines, record)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+/// Appends `lines` to `file`, and waits for them to reach the disk if `fsync`
+fn append_lines(file: &mut File, lines: &[u8], fsync: bool) -> io::Result<()> {
+    // A single write, so that a crash can only tear the end of it
+    let len = file.metadata()?.len();
+    let mut written = file.write_all(lines);
+    if fsync && written.is_ok() {
+        written = file.sync_data();
+    }
+    if let Err(e) = written {
+        // The append fails, so its records mustn't come back on recovery, and the next ones
+        // mustn't be written after half a record. Any offset in them is given out again.
+        let _ = file.set_len(len);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// The write failed, and we don't know whether the record made it to disk or not
+fn crash(e: io::Error) -> error::Error {
+    error::Error::crash(format!("couldn't write to the data directory: {}", e))
+}
+
+fn corrupt(path: &Path, text: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), text),
+    )
+}
+
+/// The name of the log file of `key`, unless it's too long to name a file after
+fn named_log(key: &str) -> Option<String> {
+    if key.len() > MAX_NAMED_KEY {
+        return None;
+    }
+    let hex: String = key.bytes().map(|b| format!("{:02x}", b)).collect();
+    Some(format!("{}.log", hex))
+}
+
+fn append_to(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// The key whose log is at `path`, if it's named like one
+fn key_of(path: &Path) -> Option<String> {
+    if path.extension()? != "log" {
+        return None;
+    }
+    let hex = path.file_stem()?.to_str()?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Reads the records of the file at `path`, if there's one. A torn last record is cut off the
+/// file; anything else that doesn't parse is an error.
+fn read_records<T: DeserializeOwned>(path: &Path) -> io::Result<Vec<T>> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    let lines: Vec<&[u8]> = data.split_inclusive(|b| *b == b'\n').collect();
+    let mut records = Vec::new();
+    let mut good_len = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let record = line
+            .strip_suffix(b"\n")
+            .and_then(|line| serde_json::from_slice(line).ok());
+        match record {
+            Some(record) => {
+                records.push(record);
+                good_len += line.len();
+            }
+            None if i + 1 == lines.len() => {
+                warn!("dropping torn record at the end of {}", path.display());
+                file.set_len(good_len as u64)?;
+                file.sync_all()?;
+            }
+            None => return Err(corrupt(path, &format!("bad record on line {}", i + 1))),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn data_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("kafka-{}", ulid::Ulid::new()))
+    }
+
+    #[tokio::test]
+    async fn recovers_after_restart() {
+        let dir = data_dir();
+        let store = DiskStore::open(&dir, true, Retention::default()).unwrap();
+        assert_eq!(1, store.append("k", json!(10), None).await.unwrap());
+        assert_eq!(2, store.append("k", json!(11), None).await.unwrap());
+        assert_eq!(1, store.append("a/b", json!(20), None).await.unwrap());
+        assert_eq!(
+            vec![2, 3],
+            store
+                .append_batch("a/b", &[json!(21), json!(22)])
+                .await
+                .unwrap()
+        );
+        let producer = Producer {
+            id: String::from("p"),
+            seq: 1,
+        };
+        assert_eq!(
+            1,
+            store.append("j", json!(30), Some(&producer)).await.unwrap()
+        );
+        let offsets = Offsets::from([(String::from("k"), 2)]);
+        store.commit_offsets(Some("g"), offsets).await.unwrap();
+        drop(store);
+
+        let store = DiskStore::open(&dir, true, Retention::default()).unwrap();
+        assert_eq!(
+            vec![(2, json!(11))],
+            store.read_from("k", 2, None).await.unwrap().msgs
+        );
+        assert_eq!(
+            vec![(1, json!(20)), (2, json!(21)), (3, json!(22))],
+            store.read_from("a/b", 1, None).await.unwrap().msgs
+        );
+        let keys = [String::from("k")];
+        assert_eq!(
+            Offsets::from([(String::from("k"), 2)]),
+            store.committed_offsets(Some("g"), &keys).await.unwrap()
+        );
+        assert_eq!(3, store.append("k", json!(12), None).await.unwrap());
+        // A retry from before the restart
+        assert_eq!(
+            1,
+            store.append("j", json!(30), Some(&producer)).await.unwrap()
+        );
+        assert_eq!(2, store.append("j", json!(31), None).await.unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_torn_last_record() {
+        let dir = data_dir();
+        let store = DiskStore::open(&dir, false, Retention::default()).unwrap();
+        store.append("k", json!(10), None).await.unwrap();
+        let path = dir.join("logs").join(named_log("k").unwrap());
+        drop(store);
+        // The process died halfway through writing the second record
+        let mut file = append_to(&path).unwrap();
+        file.write_all(br#"{"offset":2,"m"#).unwrap();
+
+        let store = DiskStore::open(&dir, false, Retention::default()).unwrap();
+        assert_eq!(
+            vec![(1, json!(10))],
+            store.read_from("k", 1, None).await.unwrap().msgs
+        );
+        // The offset of the torn record wasn't acknowledged, so it's given out again
+        assert_eq!(2, store.append("k", json!(11), None).await.unwrap());
+        drop(store);
+        let store = DiskStore::open(&dir, false, Retention::default()).unwrap();
+        assert_eq!(
+            vec![(1, json!(10)), (2, json!(11))],
+            store.read_from("k", 1, None).await.unwrap().msgs
+        );
+
+        // Damage anywhere else is reported rather than dropped
+        fs::write(
+            &path,
+            "{\"offset\":1,\"msg\":10}\ngarbage\n{\"offset\":2,\"msg\":11}\n",
+        )
+        .unwrap();
+        assert!(DiskStore::open(&dir, false, Retention::default()).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_duplicated_offsets() {
+        let dir = data_dir();
+        let store = DiskStore::open(&dir, false, Retention::default()).unwrap();
+        store.append("k", json!(10), None).await.unwrap();
+        store.append("k", json!(11), None).await.unwrap();
+        let path = dir.join("logs").join(named_log("k").unwrap());
+        drop(store);
+        // What a failed append that was left in the file would lead to: its offset given out
+        // again to the next one
+        let mut file = append_to(&path).unwrap();
+        file.write_all(b"{\"offset\":2,\"msg\":12}\n").unwrap();
+
+        let Err(e) = DiskStore::open(&dir, false, Retention::default()) else {
+            panic!("opened a log with a duplicated offset");
+        };
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert!(e.to_string().contains("offsets out of sequence"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compacts_what_retention_dropped() {
+        let dir = data_dir();
+        let retention = Retention {
+            max_messages: Some(2),
+            ..Retention::default()
+        };
+        let store = DiskStore::open(&dir, true, retention.clone()).unwrap();
+        let producer = Producer {
+            id: String::from("p"),
+            seq: 1,
+        };
+        store.append("k", json!(0), Some(&producer)).await.unwrap();
+        for msg in 1..100 {
+            store.append("k", json!(msg), None).await.unwrap();
+        }
+        drop(store);
+        let path = dir.join("logs").join(named_log("k").unwrap());
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= COMPACT_AFTER + 2, "{} lines", lines);
+
+        let store = DiskStore::open(&dir, true, retention).unwrap();
+        assert_eq!(
+            Fetch {
+                msgs: vec![(99, json!(98)), (100, json!(99))],
+                earliest: Some(99),
+                next: None
+            },
+            store.read_from("k", 1, None).await.unwrap()
+        );
+        // The retry is recognised even though its record was compacted away
+        assert_eq!(
+            1,
+            store.append("k", json!(0), Some(&producer)).await.unwrap()
+        );
+        assert_eq!(101, store.append("k", json!(100), None).await.unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_sends_get_offsets_in_file_order() {
+        let dir = data_dir();
+        let store = Arc::new(DiskStore::open(&dir, true, Retention::default()).unwrap());
+        let mut sends = Vec::new();
+        for msg in 0..20 {
+            let store = store.clone();
+            let key = if msg % 2 == 0 { "even" } else { "odd" };
+            sends.push(tokio::spawn(async move {
+                store.append(key, json!(msg), None).await.unwrap()
+            }));
+        }
+        let mut offsets = Vec::new();
+        for send in sends {
+            offsets.push(send.await.unwrap());
+        }
+        offsets.sort();
+        let expected: Vec<usize> = (1..=10).flat_map(|offset| [offset, offset]).collect();
+        assert_eq!(expected, offsets);
+        drop(store);
+
+        // The log files replay, so they hold every key's records in offset order
+        let store = DiskStore::open(&dir, true, Retention::default()).unwrap();
+        assert_eq!(
+            10,
+            store.read_from("odd", 1, None).await.unwrap().msgs.len()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_keys_get_numbered_files() {
+        let dir = data_dir();
+        let long = "k".repeat(1000);
+        let longer = "k".repeat(2000);
+        let store = DiskStore::open(&dir, true, Retention::default()).unwrap();
+        assert_eq!(1, store.append(&long, json!(10), None).await.unwrap());
+        assert_eq!(1, store.append(&longer, json!(20), None).await.unwrap());
+        assert_eq!(2, store.append(&long, json!(11), None).await.unwrap());
+        drop(store);
+
+        let store = DiskStore::open(&dir, true, Retention::default()).unwrap();
+        assert_eq!(
+            vec![(1, json!(10)), (2, json!(11))],
+            store.read_from(&long, 1, None).await.unwrap().msgs
+        );
+        assert_eq!(2, store.append(&longer, json!(21), None).await.unwrap());
+        assert!(dir.join("logs/long-1.log").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keys_round_trip_through_file_names() {
+        for key in ["k", "a/b", "..", "ключ"] {
+            let path = Path::new("data").join(named_log(key).unwrap());
+            assert_eq!(Some(key.to_string()), key_of(&path));
+        }
+        assert_eq!(None, named_log(&"k".repeat(MAX_NAMED_KEY + 1)));
+        assert_eq!(None, key_of(Path::new("logs/6.log")));
+        assert_eq!(None, key_of(Path::new("logs/6b.tmp")));
+    }
+}
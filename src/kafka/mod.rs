@@ -0,0 +1,602 @@
+//! Messages of the kafka-style log workload (challenges 5a to 5c), and the log logic both kafka
+//! binaries share.
+//!
+//! Both binaries run the same [`Handler`]; they only differ in the [`Backend`] [`serve`] picks at
+//! startup: where the logs are kept, and whether each key belongs to one node.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{self, ErrorCode};
+
+mod disk;
+mod node;
+pub mod store;
+
+pub use disk::DiskStore;
+pub use node::{serve, Backend, Handler};
+pub use store::{KvStore, LogStore, MemoryStore};
+
+/// A message in a log. Maelstrom's kafka workload only sends integers, but any JSON goes.
+pub type Msg = serde_json::Value;
+/// An `(offset, message)` entry of a log
+pub type Pair = (usize, Msg);
+pub type Offsets = HashMap<String, usize>;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestBody {
+    Send {
+        key: String,
+        msg: Msg,
+        /// Together with `seq`, makes retries of the send safe: a send with the same producer
+        /// and sequence number as one already appended gets its offset back instead
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        producer_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Sends several messages at once, possibly to several keys. The messages of each key are
+    /// appended in order, with consecutive offsets.
+    SendBatch { msgs: HashMap<String, Vec<Msg>> },
+    Poll {
+        offsets: Offsets,
+        /// Caps the messages in the reply, on top of the node's own [`PollLimits`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_messages: Option<usize>,
+    },
+    CommitOffsets {
+        offsets: Offsets,
+        /// Consumer group the offsets belong to. Requests without one share a default group.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+    ListCommittedOffsets {
+        keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub enum ResponseBody {
+    SendOk {
+        offset: usize,
+    },
+    /// The offset of each message of the batch, in the order they were sent
+    SendBatchOk {
+        offsets: HashMap<String, Vec<usize>>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<Pair>>,
+        /// Keys whose log was truncated past the offset asked for, with the earliest offset still
+        /// available. `msgs` starts there for them.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        earliest_offsets: Offsets,
+        /// Keys that had more messages than the reply had room for, with the offset the next
+        /// poll should ask for
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        next_offsets: Offsets,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsetsOk {
+        offsets: Offsets,
+    },
+}
+
+/// The messages of `log` on or after `offset`. `log` is sorted by offset.
+pub fn messages_from(log: &[Pair], offset: usize) -> &[Pair] {
+    &log[log.partition_point(|(o, _)| *o < offset)..]
+}
+
+/// How many messages a poll returns at most. The default has no limits, which is what Maelstrom's
+/// kafka workload expects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PollLimits {
+    pub per_key: Option<usize>,
+    pub total: Option<usize>,
+}
+
+impl PollLimits {
+    /// Reads `--poll-max-per-key <n>` and `--poll-max-total <n>` from the command line arguments,
+    /// skipping any others.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut limits = PollLimits::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let limit = match arg.as_str() {
+                "--poll-max-per-key" => &mut limits.per_key,
+                "--poll-max-total" => &mut limits.total,
+                _ => continue,
+            };
+            let value = args.next().ok_or(format!("{} needs a value", arg))?;
+            let value = value
+                .parse()
+                .map_err(|e| format!("{} {}: {}", arg, value, e))?;
+            *limit = Some(value);
+        }
+        Ok(limits)
+    }
+
+    /// The total for a poll that asked for at most `max_messages`
+    pub fn total_for(&self, max_messages: Option<usize>) -> Option<usize> {
+        min_limit(self.total, max_messages)
+    }
+}
+
+/// The tighter of two optional limits
+pub(crate) fn min_limit(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Which messages a log may drop. The default keeps everything, which is what Maelstrom's kafka
+/// workload expects.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Keep at most this many messages per key
+    pub max_messages: Option<usize>,
+    /// Drop messages appended longer ago than this
+    pub max_age: Option<Duration>,
+    /// Drop the messages below the lowest offset the consumer groups that committed the key have
+    /// committed. Groups that never committed it don't hold it back.
+    pub below_committed: bool,
+}
+
+impl Retention {
+    /// Reads `--retain-messages <n>`, `--retain-ms <ms>` and `--compact-committed` from the
+    /// command line arguments, skipping any others.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut retention = Retention::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut number = || -> Result<u64, String> {
+                let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                value
+                    .parse()
+                    .map_err(|e| format!("{} {}: {}", arg, value, e))
+            };
+            match arg.as_str() {
+                "--retain-messages" => retention.max_messages = Some(number()? as usize),
+                "--retain-ms" => retention.max_age = Some(Duration::from_millis(number()?)),
+                "--compact-committed" => retention.below_committed = true,
+                _ => {}
+            }
+        }
+        Ok(retention)
+    }
+}
+
+/// A send that can be retried safely: the `seq`th send of producer `id`. A producer's sends to a
+/// key are expected in increasing `seq` order.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Producer {
+    pub id: String,
+    pub seq: u64,
+}
+
+impl Producer {
+    /// The producer of a send with these fields. They come together or not at all.
+    pub fn from_fields(
+        producer_id: Option<String>,
+        seq: Option<u64>,
+    ) -> Result<Option<Self>, error::Error> {
+        match (producer_id, seq) {
+            (Some(id), Some(seq)) => Ok(Some(Producer { id, seq })),
+            (None, None) => Ok(None),
+            _ => Err(error::Error::malformed("producer_id and seq go together")),
+        }
+    }
+
+    /// The error for a send older than the ones its log remembers
+    pub fn stale(&self) -> error::Error {
+        error::Error::new(
+            ErrorCode::PreconditionFailed,
+            format!(
+                "seq {} of producer {} is older than what was appended since",
+                self.seq, self.id
+            ),
+        )
+    }
+}
+
+/// How many of each producer's latest sends a log remembers, like Kafka does
+const PRODUCER_WINDOW: usize = 5;
+
+/// Where a send stands with respect to what its log already has
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sequence {
+    /// Not seen before, to be appended
+    New,
+    /// A retry of a send appended at this offset
+    Duplicate(usize),
+    /// Older than the sends the log remembers from its producer
+    Stale,
+}
+
+/// Where send `seq` stands among `sent`, the latest `(seq, offset)`s of its producer
+fn sequence_in(sent: &VecDeque<(u64, usize)>, seq: u64) -> Sequence {
+    if let Some((_, offset)) = sent.iter().find(|(sent_seq, _)| *sent_seq == seq) {
+        return Sequence::Duplicate(*offset);
+    }
+    match sent.back() {
+        Some((last, _)) if seq < *last => Sequence::Stale,
+        _ => Sequence::New,
+    }
+}
+
+/// Adds send `seq` at `offset` to the latest sends of its producer, forgetting the oldest past
+/// [`PRODUCER_WINDOW`]. Kept in seq order, as concurrent sends can get their offsets out of it.
+fn remember(sent: &mut VecDeque<(u64, usize)>, seq: u64, offset: usize) {
+    let at = sent.partition_point(|(sent_seq, _)| *sent_seq < seq);
+    sent.insert(at, (seq, offset));
+    if sent.len() > PRODUCER_WINDOW {
+        sent.pop_front();
+    }
+}
+
+/// The messages of one key, minus the ones retention dropped
+#[derive(Debug)]
+pub struct Log {
+    msgs: Vec<Pair>,
+    appended_at: Vec<Instant>,
+    next_offset: usize,
+    /// The latest `(seq, offset)`s of each producer. Retention leaves them alone, so that a
+    /// retry is still recognised after its message is gone.
+    producers: HashMap<String, VecDeque<(u64, usize)>>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            msgs: Vec::new(),
+            appended_at: Vec::new(),
+            // Offsets start at 1
+            next_offset: 1,
+            producers: HashMap::new(),
+        }
+    }
+}
+
+/// What reading a log from some offset found
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Fetch {
+    pub msgs: Vec<Pair>,
+    /// The earliest offset still available, if the log was truncated past the one asked for
+    pub earliest: Option<usize>,
+    /// Where to carry on reading, if there were more messages than asked for
+    pub next: Option<usize>,
+}
+
+impl Log {
+    /// An empty log that carries on from `next_offset`, remembering the `producers`' sends that
+    /// came before
+    pub fn resume(next_offset: usize, producers: HashMap<String, VecDeque<(u64, usize)>>) -> Self {
+        Log {
+            next_offset,
+            producers,
+            ..Log::default()
+        }
+    }
+
+    /// Appends `msg` and returns the offset it got. Check the [`Log::sequence`] of sends from a
+    /// producer first.
+    pub fn append(&mut self, msg: Msg, producer: Option<&Producer>, now: Instant) -> usize {
+        let offset = self.next_offset;
+        self.next_offset += 1;
+        self.msgs.push((offset, msg));
+        self.appended_at.push(now);
+        if let Some(producer) = producer {
+            let sent = self.producers.entry(producer.id.clone()).or_default();
+            remember(sent, producer.seq, offset);
+        }
+        offset
+    }
+
+    pub fn sequence(&self, producer: Option<&Producer>) -> Sequence {
+        let Some(producer) = producer else {
+            return Sequence::New;
+        };
+        match self.producers.get(&producer.id) {
+            Some(sent) => sequence_in(sent, producer.seq),
+            None => Sequence::New,
+        }
+    }
+
+    /// The offset the next message appended gets
+    pub fn next_offset(&self) -> usize {
+        self.next_offset
+    }
+
+    /// How many messages retention kept
+    pub fn retained(&self) -> usize {
+        self.msgs.len()
+    }
+
+    /// The latest `(seq, offset)`s of each producer
+    pub fn producers(&self) -> &HashMap<String, VecDeque<(u64, usize)>> {
+        &self.producers
+    }
+
+    /// The offset of the first message retention kept. Once everything is dropped, the offset
+    /// the next message will get.
+    pub fn earliest(&self) -> usize {
+        self.msgs
+            .first()
+            .map_or(self.next_offset, |(offset, _)| *offset)
+    }
+
+    /// The messages on or after `offset`, `max` of them at most
+    pub fn read_from(&self, offset: usize, max: Option<usize>) -> Fetch {
+        let earliest = self.earliest();
+        let msgs = messages_from(&self.msgs, offset);
+        let (msgs, next) = match max {
+            Some(max) if msgs.len() > max => (&msgs[..max], Some(msgs[max].0)),
+            _ => (msgs, None),
+        };
+        Fetch {
+            msgs: msgs.to_vec(),
+            // Nothing was dropped below an offset nobody sent to yet
+            earliest: (offset < earliest && earliest > 1).then_some(earliest),
+            next,
+        }
+    }
+
+    /// Drops what `retention` lets go of. `committed` is the lowest committed offset of the key.
+    pub fn truncate(&mut self, retention: &Retention, committed: Option<usize>, now: Instant) {
+        let mut keep_from = 0;
+        if let Some(max_messages) = retention.max_messages {
+            keep_from = keep_from.max(self.msgs.len().saturating_sub(max_messages));
+        }
+        if let Some(max_age) = retention.max_age {
+            let expired = self
+                .appended_at
+                .partition_point(|at| now.saturating_duration_since(*at) > max_age);
+            keep_from = keep_from.max(expired);
+        }
+        if let (true, Some(committed)) = (retention.below_committed, committed) {
+            let below = self.msgs.partition_point(|(offset, _)| *offset < committed);
+            keep_from = keep_from.max(below);
+        }
+        self.msgs.drain(..keep_from);
+        self.appended_at.drain(..keep_from);
+    }
+}
+
+/// The node that owns `key`, the one its hash lands on. All nodes agree on it since they get the
+/// same `node_ids` at init.
+pub fn owner_of<'a>(key: &str, node_ids: &'a [String]) -> Option<&'a String> {
+    if node_ids.is_empty() {
+        return None;
+    }
+    // FNV-1a, which unlike `DefaultHasher` doesn't depend on the build
+    let hash = key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    node_ids.get((hash % node_ids.len() as u64) as usize)
+}
+
+/// Merges newly committed `offsets` into `committed`. Offsets only move forward, so a commit
+/// that arrives late doesn't undo a newer one.
+pub fn merge_offsets(committed: &mut Offsets, offsets: Offsets) {
+    for (key, offset) in offsets {
+        let current = committed.entry(key).or_default();
+        *current = (*current).max(offset);
+    }
+}
+
+/// The committed offsets of the keys asked for
+pub fn committed_offsets_of(committed: &Offsets, keys: &[String]) -> Offsets {
+    committed
+        .iter()
+        .filter(|(k, _)| keys.contains(k))
+        .map(|(k, v)| (k.clone(), *v))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn poll_returns_from_offset() {
+        let log = vec![(1, json!(10)), (2, json!(11)), (3, json!(12))];
+        assert_eq!(&[(2, json!(11)), (3, json!(12))], messages_from(&log, 2));
+        assert!(messages_from(&log, 4).is_empty());
+    }
+
+    #[test]
+    fn reads_are_capped() {
+        let mut log = Log::default();
+        let now = Instant::now();
+        for msg in 0..5 {
+            log.append(json!(msg), None, now);
+        }
+        let fetch = log.read_from(2, Some(2));
+        assert_eq!(vec![(2, json!(1)), (3, json!(2))], fetch.msgs);
+        assert_eq!(Some(4), fetch.next);
+        assert_eq!(None, log.read_from(4, Some(2)).next);
+        assert_eq!(Some(1), log.read_from(1, Some(0)).next);
+
+        let limits = PollLimits {
+            per_key: Some(10),
+            total: Some(5),
+        };
+        assert_eq!(Some(3), limits.total_for(Some(3)));
+        assert_eq!(Some(5), limits.total_for(Some(30)));
+        assert_eq!(Some(3), PollLimits::default().total_for(Some(3)));
+        let args = ["--poll-max-per-key", "10", "--kv", "--poll-max-total", "5"].map(String::from);
+        assert_eq!(limits, PollLimits::from_args(&args).unwrap());
+    }
+
+    #[test]
+    fn retries_are_recognised() {
+        let now = Instant::now();
+        let mut log = Log::default();
+        let producer = |seq| Producer {
+            id: String::from("p"),
+            seq,
+        };
+        assert_eq!(Sequence::New, log.sequence(Some(&producer(1))));
+        assert_eq!(1, log.append(json!(10), Some(&producer(1)), now));
+        assert_eq!(2, log.append(json!(11), None, now));
+        assert_eq!(Sequence::Duplicate(1), log.sequence(Some(&producer(1))));
+        assert_eq!(Sequence::New, log.sequence(Some(&producer(3))));
+        for seq in 3..9 {
+            log.append(json!(12), Some(&producer(seq)), now);
+        }
+        // Only the last few are remembered
+        assert_eq!(Sequence::Stale, log.sequence(Some(&producer(3))));
+        assert_eq!(Sequence::Duplicate(7), log.sequence(Some(&producer(7))));
+        let other = Producer {
+            id: String::from("q"),
+            seq: 1,
+        };
+        assert_eq!(Sequence::New, log.sequence(Some(&other)));
+
+        assert_eq!(Ok(None), Producer::from_fields(None, None));
+        let err = Producer::from_fields(Some(String::from("p")), None).unwrap_err();
+        assert_eq!(ErrorCode::MalformedRequest, err.code);
+    }
+
+    #[test]
+    fn retention_truncates_logs() {
+        let start = Instant::now();
+        let mut log = Log::default();
+        for msg in 0..5 {
+            let at = start + Duration::from_millis(msg as u64 * 10);
+            assert_eq!(msg + 1, log.append(json!(msg), None, at));
+        }
+        let now = start + Duration::from_millis(40);
+        log.truncate(&Retention::default(), Some(5), now);
+        assert_eq!(Fetch::default(), log.read_from(6, None));
+        assert_eq!(None, log.read_from(1, None).earliest);
+
+        let by_count = Retention {
+            max_messages: Some(4),
+            ..Retention::default()
+        };
+        log.truncate(&by_count, None, now);
+        assert_eq!(2, log.earliest());
+        let by_age = Retention {
+            max_age: Some(Duration::from_millis(25)),
+            ..Retention::default()
+        };
+        log.truncate(&by_age, None, now);
+        assert_eq!(3, log.earliest());
+        let below_committed = Retention {
+            below_committed: true,
+            ..Retention::default()
+        };
+        log.truncate(&below_committed, Some(4), now);
+        assert_eq!(
+            Fetch {
+                msgs: vec![(4, json!(3)), (5, json!(4))],
+                earliest: Some(4),
+                next: None
+            },
+            log.read_from(2, None)
+        );
+
+        // Offsets keep counting after everything was dropped
+        log.truncate(&below_committed, Some(6), now);
+        assert_eq!(6, log.earliest());
+        assert_eq!(Some(6), log.read_from(1, None).earliest);
+        assert_eq!(6, log.append(json!(5), None, now));
+    }
+
+    #[test]
+    fn retention_from_args() {
+        let args = ["--kv", "--retain-messages", "10", "--compact-committed"].map(String::from);
+        assert_eq!(
+            Retention {
+                max_messages: Some(10),
+                max_age: None,
+                below_committed: true
+            },
+            Retention::from_args(&args).unwrap()
+        );
+        let args = ["--retain-ms", "soon"].map(String::from);
+        assert!(Retention::from_args(&args).is_err());
+        assert!(Retention::from_args(&[String::from("--retain-ms")]).is_err());
+    }
+
+    #[test]
+    fn committed_offsets_only_move_forward() {
+        let mut committed = Offsets::from([(String::from("a"), 3), (String::from("b"), 1)]);
+        merge_offsets(
+            &mut committed,
+            Offsets::from([(String::from("a"), 2), (String::from("b"), 4)]),
+        );
+        merge_offsets(&mut committed, Offsets::from([(String::from("c"), 1)]));
+        assert_eq!(
+            Offsets::from([
+                (String::from("a"), 3),
+                (String::from("b"), 4),
+                (String::from("c"), 1)
+            ]),
+            committed
+        );
+    }
+
+    #[test]
+    fn keys_spread_over_owners() {
+        let node_ids: Vec<String> = ["n0", "n1", "n2"].map(String::from).to_vec();
+        assert_eq!(None, owner_of("k", &[]));
+        let owners: Vec<&String> = (0..30)
+            .map(|key| owner_of(&key.to_string(), &node_ids).unwrap())
+            .collect();
+        for node in &node_ids {
+            assert!(owners.contains(&node));
+        }
+    }
+
+    #[test]
+    fn group_is_optional() {
+        let body: RequestBody =
+            serde_json::from_str(r#"{"type":"commit_offsets","offsets":{"k":1}}"#).unwrap();
+        assert!(matches!(
+            body,
+            RequestBody::CommitOffsets { group: None, .. }
+        ));
+        let body = RequestBody::ListCommittedOffsets {
+            keys: vec![String::from("k")],
+            group: Some(String::from("g")),
+        };
+        assert_eq!(
+            r#"{"type":"list_committed_offsets","keys":["k"],"group":"g"}"#,
+            serde_json::to_string(&body).unwrap()
+        );
+    }
+
+    #[test]
+    fn messages_can_be_any_json() {
+        let send = r#"{"type":"send","key":"k","msg":{"id":"a","tags":[1,null]}}"#;
+        let body: RequestBody = serde_json::from_str(send).unwrap();
+        assert_eq!(send, serde_json::to_string(&body).unwrap());
+    }
+
+    #[test]
+    fn serialize_poll_ok() {
+        let body = ResponseBody::PollOk {
+            msgs: HashMap::from([(String::from("k"), vec![(1, json!(10))])]),
+            earliest_offsets: Offsets::new(),
+            next_offsets: Offsets::new(),
+        };
+        assert_eq!(
+            r#"{"type":"poll_ok","msgs":{"k":[[1,10]]}}"#,
+            serde_json::to_string(&body).unwrap()
+        );
+    }
+}
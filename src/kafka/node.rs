@@ -0,0 +1,282 @@
+//! The kafka handler both binaries run.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::debug;
+use maelstrom::protocol::Message;
+use maelstrom::{Node, Result, Runtime};
+use tokio_context::context::Context;
+
+use super::{
+    DiskStore, KvStore, LogStore, MemoryStore, Offsets, PollLimits, Producer, RequestBody,
+    ResponseBody, Retention,
+};
+use crate::error;
+
+/// Where the handler keeps the logs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// In the node's memory. Only correct with a single node.
+    Memory,
+    /// In the node's memory and in a data directory, see [`DiskStore`]. Only correct with a
+    /// single node.
+    Disk { dir: PathBuf, fsync: bool },
+    /// In Maelstrom's lin-kv and seq-kv services, shared by all nodes
+    Kv,
+    /// Each key belongs to one node, picked with [`super::owner_of`], which keeps its log and
+    /// committed offset in memory. The others forward requests about the key to it.
+    ///
+    /// Maelstrom's kafka workload doesn't crash nodes, so the owner doesn't need lin-kv for
+    /// durability, and node membership never changes, so it doesn't need it for fencing either:
+    /// no KV traffic at all.
+    Partitioned,
+}
+
+/// Runs a kafka node keeping its logs in `backend`. Logs follow `retention`, as far as the
+/// backend's store can apply it, and polls are capped by `limits`.
+pub async fn serve(backend: Backend, retention: Retention, limits: PollLimits) -> Result<()> {
+    let runtime = Runtime::new();
+    let store: Arc<dyn LogStore> = match backend {
+        Backend::Memory | Backend::Partitioned => Arc::new(MemoryStore::new(retention)),
+        Backend::Disk { ref dir, fsync } => Arc::new(DiskStore::open(dir, fsync, retention)?),
+        Backend::Kv => Arc::new(KvStore::new(runtime.clone(), retention)),
+    };
+    let handler = Arc::new(Handler::new(store, backend == Backend::Partitioned, limits));
+    runtime.with_handler(handler).run().await
+}
+
+#[derive(Clone)]
+pub struct Handler {
+    store: Arc<dyn LogStore>,
+    partitioned: bool,
+    limits: PollLimits,
+}
+
+impl Handler {
+    /// A handler keeping logs in `store`. If `partitioned`, `store` only holds the keys this node
+    /// owns.
+    pub fn new(store: Arc<dyn LogStore>, partitioned: bool, limits: PollLimits) -> Self {
+        Handler {
+            store,
+            partitioned,
+            limits,
+        }
+    }
+
+    /// The node that owns `key`, or `None` if it's this one
+    fn remote_owner(&self, runtime: &Runtime, key: &str) -> Option<String> {
+        if !self.partitioned {
+            return None;
+        }
+        super::owner_of(key, runtime.nodes())
+            .filter(|owner| *owner != runtime.node_id())
+            .cloned()
+    }
+
+    /// Splits `entries` into the ones this node owns and the ones other nodes do, by owner
+    #[allow(clippy::type_complexity)]
+    fn split_by_owner<T>(
+        &self,
+        runtime: &Runtime,
+        entries: impl IntoIterator<Item = (String, T)>,
+    ) -> (Vec<(String, T)>, HashMap<String, Vec<(String, T)>>) {
+        let mut local = Vec::new();
+        let mut remote: HashMap<String, Vec<(String, T)>> = HashMap::new();
+        for (key, value) in entries {
+            match self.remote_owner(runtime, &key) {
+                Some(owner) => remote.entry(owner).or_default().push((key, value)),
+                None => local.push((key, value)),
+            }
+        }
+        (local, remote)
+    }
+
+    /// Sends `body` to `owner` and hands back its reply. Error replies come back as errors, so
+    /// the client gets the owner's error.
+    async fn forward(
+        &self,
+        runtime: &Runtime,
+        owner: String,
+        body: RequestBody,
+    ) -> Result<ResponseBody> {
+        debug!("Forwarding {:?} to {}", body, owner);
+        let reply = runtime.call(Context::new().0, owner, body).await?;
+        reply.body.as_obj()
+    }
+}
+
+fn unexpected(reply: ResponseBody) -> error::Error {
+    error::Error::crash(format!("unexpected reply from owner: {:?}", reply))
+}
+
+#[async_trait]
+impl Node for Handler {
+    async fn process(&self, runtime: Runtime, req: Message) -> Result<()> {
+        let body: RequestBody = error::parse(&req)?;
+        match body {
+            RequestBody::Send {
+                key,
+                msg,
+                producer_id,
+                seq,
+            } => {
+                if let Some(owner) = self.remote_owner(&runtime, &key) {
+                    let send = RequestBody::Send {
+                        key,
+                        msg,
+                        producer_id,
+                        seq,
+                    };
+                    let resp = self.forward(&runtime, owner, send).await?;
+                    return runtime.reply(req, resp).await;
+                }
+                let producer = Producer::from_fields(producer_id, seq)?;
+                let offset = self.store.append(&key, msg, producer.as_ref()).await?;
+                debug!("Will send back offset: {}", offset);
+                runtime.reply(req, ResponseBody::SendOk { offset }).await
+            }
+            RequestBody::SendBatch { msgs } => {
+                // If part of the batch fails, the rest may still have been appended, just like a
+                // send that fails may have been
+                let (local, remote) = self.split_by_owner(&runtime, msgs);
+                let mut offsets = HashMap::new();
+                for (key, msgs) in local {
+                    if msgs.is_empty() {
+                        continue;
+                    }
+                    let appended = self.store.append_batch(&key, &msgs).await?;
+                    offsets.insert(key, appended);
+                }
+                for (owner, msgs) in remote {
+                    let msgs = msgs.into_iter().collect();
+                    match self
+                        .forward(&runtime, owner, RequestBody::SendBatch { msgs })
+                        .await?
+                    {
+                        ResponseBody::SendBatchOk { offsets: owned } => offsets.extend(owned),
+                        other => return Err(unexpected(other).into()),
+                    }
+                }
+                runtime
+                    .reply(req, ResponseBody::SendBatchOk { offsets })
+                    .await
+            }
+            RequestBody::Poll {
+                offsets,
+                max_messages,
+            } => {
+                // Sorted, so that which keys make it into a capped reply doesn't change from one
+                // poll to the next
+                let mut offsets: Vec<(String, usize)> = offsets.into_iter().collect();
+                offsets.sort();
+                let (local, remote) = self.split_by_owner(&runtime, offsets);
+                let mut budget = self.limits.total_for(max_messages);
+                let mut msgs = HashMap::new();
+                let mut earliest_offsets = Offsets::new();
+                let mut next_offsets = Offsets::new();
+                for (key, offset) in local {
+                    if budget == Some(0) {
+                        next_offsets.insert(key, offset);
+                        continue;
+                    }
+                    let max = super::min_limit(self.limits.per_key, budget);
+                    let fetch = self.store.read_from(&key, offset, max).await?;
+                    budget = budget.map(|budget| budget - fetch.msgs.len());
+                    if let Some(earliest) = fetch.earliest {
+                        earliest_offsets.insert(key.clone(), earliest);
+                    }
+                    if let Some(next) = fetch.next {
+                        next_offsets.insert(key.clone(), next);
+                    }
+                    if !fetch.msgs.is_empty() {
+                        msgs.insert(key, fetch.msgs);
+                    }
+                }
+                let mut remote: Vec<_> = remote.into_iter().collect();
+                remote.sort();
+                for (owner, offsets) in remote {
+                    if budget == Some(0) {
+                        next_offsets.extend(offsets);
+                        continue;
+                    }
+                    let offsets = offsets.into_iter().collect();
+                    let poll = RequestBody::Poll {
+                        offsets,
+                        max_messages: budget,
+                    };
+                    match self.forward(&runtime, owner, poll).await? {
+                        ResponseBody::PollOk {
+                            msgs: owned,
+                            earliest_offsets: owned_earliest,
+                            next_offsets: owned_next,
+                        } => {
+                            let polled: usize = owned.values().map(Vec::len).sum();
+                            budget = budget.map(|budget| budget.saturating_sub(polled));
+                            msgs.extend(owned);
+                            earliest_offsets.extend(owned_earliest);
+                            next_offsets.extend(owned_next);
+                        }
+                        other => return Err(unexpected(other).into()),
+                    }
+                }
+                let resp = ResponseBody::PollOk {
+                    msgs,
+                    earliest_offsets,
+                    next_offsets,
+                };
+                runtime.reply(req, resp).await
+            }
+            RequestBody::CommitOffsets { offsets, group } => {
+                let (local, remote) = self.split_by_owner(&runtime, offsets);
+                if !local.is_empty() {
+                    self.store
+                        .commit_offsets(group.as_deref(), local.into_iter().collect())
+                        .await?;
+                }
+                for (owner, offsets) in remote {
+                    let offsets = offsets.into_iter().collect();
+                    let group = group.clone();
+                    self.forward(
+                        &runtime,
+                        owner,
+                        RequestBody::CommitOffsets { offsets, group },
+                    )
+                    .await?;
+                }
+                runtime.reply(req, ResponseBody::CommitOffsetsOk).await
+            }
+            RequestBody::ListCommittedOffsets { keys, group } => {
+                let (local, remote) =
+                    self.split_by_owner(&runtime, keys.into_iter().map(|key| (key, ())));
+                let keys: Vec<String> = local.into_iter().map(|(key, ())| key).collect();
+                let mut offsets: Offsets = self
+                    .store
+                    .committed_offsets(group.as_deref(), &keys)
+                    .await?;
+                for (owner, keys) in remote {
+                    let keys = keys.into_iter().map(|(key, ())| key).collect();
+                    let group = group.clone();
+                    match self
+                        .forward(
+                            &runtime,
+                            owner,
+                            RequestBody::ListCommittedOffsets { keys, group },
+                        )
+                        .await?
+                    {
+                        ResponseBody::ListCommittedOffsetsOk { offsets: owned } => {
+                            offsets.extend(owned)
+                        }
+                        other => return Err(unexpected(other).into()),
+                    }
+                }
+                let resp = ResponseBody::ListCommittedOffsetsOk { offsets };
+                runtime.reply(req, resp).await
+            }
+            RequestBody::Init { .. } => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,772 @@
+//! Where the kafka handler keeps its logs and committed offsets.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use maelstrom::kv::{lin_kv, Storage, KV};
+use maelstrom::{Result, Runtime};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio_context::context::Context;
+
+use super::{remember, sequence_in, Fetch, Log, Msg, Offsets, Producer, Retention, Sequence};
+use crate::error::{self, failed_with, ErrorCode};
+
+#[async_trait]
+pub trait LogStore: Send + Sync {
+    /// Appends `msg` to the log of `key` and returns the offset it got. If `producer` already
+    /// appended it, returns the offset it got then.
+    async fn append(&self, key: &str, msg: Msg, producer: Option<&Producer>) -> Result<usize>;
+
+    /// Appends `msgs` to the log of `key` in one go and returns the offsets they got, which are
+    /// consecutive
+    async fn append_batch(&self, key: &str, msgs: &[Msg]) -> Result<Vec<usize>>;
+
+    /// The messages of the log of `key` on or after `offset`, `max` of them at most. Keys nobody
+    /// sent to have an empty log.
+    async fn read_from(&self, key: &str, offset: usize, max: Option<usize>) -> Result<Fetch>;
+
+    /// Commits `offsets` for the consumer `group`, `None` being the default one
+    async fn commit_offsets(&self, group: Option<&str>, offsets: Offsets) -> Result<()>;
+
+    /// The committed offsets of `group` for the keys asked for. Keys without one are left out.
+    async fn committed_offsets(&self, group: Option<&str>, keys: &[String]) -> Result<Offsets>;
+}
+
+/// Keeps everything in the node's memory, which is all a single node needs. Logs are truncated
+/// according to its [`Retention`] whenever they're touched.
+#[derive(Default)]
+pub struct MemoryStore {
+    retention: Retention,
+    state: Mutex<State>,
+}
+
+/// The logs and committed offsets of the stores that keep them in memory
+#[derive(Default)]
+pub(super) struct State {
+    logs: HashMap<String, Log>,
+    committed_offsets: HashMap<Option<String>, Offsets>,
+}
+
+impl State {
+    /// Appends `msg` unless `producer` already did, see [`LogStore::append`]
+    pub(super) fn append(
+        &mut self,
+        key: &str,
+        msg: Msg,
+        producer: Option<&Producer>,
+        retention: &Retention,
+    ) -> std::result::Result<usize, error::Error> {
+        match self.sequence(key, producer) {
+            Sequence::New => {}
+            Sequence::Duplicate(offset) => return Ok(offset),
+            Sequence::Stale => return Err(producer.unwrap().stale()),
+        }
+        // create entry if not exists
+        let log = self.logs.entry(key.to_string()).or_default();
+        let offset = log.append(msg, producer, Instant::now());
+        self.truncate(key, retention);
+        debug!("Currently in logs: {:?}", self.logs);
+        Ok(offset)
+    }
+
+    /// Appends all of `msgs`, see [`LogStore::append_batch`]
+    pub(super) fn append_batch(
+        &mut self,
+        key: &str,
+        msgs: &[Msg],
+        retention: &Retention,
+    ) -> Vec<usize> {
+        let log = self.logs.entry(key.to_string()).or_default();
+        let now = Instant::now();
+        let offsets = msgs
+            .iter()
+            .map(|msg| log.append(msg.clone(), None, now))
+            .collect();
+        self.truncate(key, retention);
+        offsets
+    }
+
+    pub(super) fn sequence(&self, key: &str, producer: Option<&Producer>) -> Sequence {
+        self.logs
+            .get(key)
+            .map_or(Sequence::New, |log| log.sequence(producer))
+    }
+
+    /// The offset the next message appended to `key` gets
+    pub(super) fn next_offset(&self, key: &str) -> usize {
+        self.logs.get(key).map_or(1, Log::next_offset)
+    }
+
+    pub(super) fn read_from(
+        &mut self,
+        key: &str,
+        offset: usize,
+        max: Option<usize>,
+        retention: &Retention,
+    ) -> Fetch {
+        self.truncate(key, retention);
+        self.logs
+            .get(key)
+            .map(|log| log.read_from(offset, max))
+            .unwrap_or_default()
+    }
+
+    pub(super) fn commit_offsets(
+        &mut self,
+        group: Option<&str>,
+        offsets: Offsets,
+        retention: &Retention,
+    ) {
+        let committed = self
+            .committed_offsets
+            .entry(group.map(String::from))
+            .or_default();
+        let keys: Vec<String> = offsets.keys().cloned().collect();
+        super::merge_offsets(committed, offsets);
+        for key in keys {
+            self.truncate(&key, retention);
+        }
+    }
+
+    pub(super) fn committed_offsets(&self, group: Option<&str>, keys: &[String]) -> Offsets {
+        let Some(committed) = self.committed_offsets.get(&group.map(String::from)) else {
+            return Offsets::new();
+        };
+        super::committed_offsets_of(committed, keys)
+    }
+
+    /// The lowest offset of `key` any consumer group committed
+    fn lowest_committed(&self, key: &str) -> Option<usize> {
+        self.committed_offsets
+            .values()
+            .filter_map(|offsets| offsets.get(key))
+            .min()
+            .copied()
+    }
+
+    pub(super) fn truncate(&mut self, key: &str, retention: &Retention) {
+        let committed = self.lowest_committed(key);
+        if let Some(log) = self.logs.get_mut(key) {
+            log.truncate(retention, committed, Instant::now());
+        }
+    }
+
+    pub(super) fn log(&self, key: &str) -> Option<&Log> {
+        self.logs.get(key)
+    }
+
+    /// Replaces the log of `key` with `log`, e.g. one [resumed](Log::resume) from a compacted
+    /// file
+    pub(super) fn restore(&mut self, key: &str, log: Log) {
+        self.logs.insert(key.to_string(), log);
+    }
+}
+
+impl MemoryStore {
+    pub fn new(retention: Retention) -> Self {
+        MemoryStore {
+            retention,
+            state: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl LogStore for MemoryStore {
+    async fn append(&self, key: &str, msg: Msg, producer: Option<&Producer>) -> Result<usize> {
+        let mut s = self.state.lock().await;
+        Ok(s.append(key, msg, producer, &self.retention)?)
+    }
+
+    async fn append_batch(&self, key: &str, msgs: &[Msg]) -> Result<Vec<usize>> {
+        let mut s = self.state.lock().await;
+        Ok(s.append_batch(key, msgs, &self.retention))
+    }
+
+    async fn read_from(&self, key: &str, offset: usize, max: Option<usize>) -> Result<Fetch> {
+        let mut s = self.state.lock().await;
+        Ok(s.read_from(key, offset, max, &self.retention))
+    }
+
+    async fn commit_offsets(&self, group: Option<&str>, offsets: Offsets) -> Result<()> {
+        let mut s = self.state.lock().await;
+        s.commit_offsets(group, offsets, &self.retention);
+        Ok(())
+    }
+
+    async fn committed_offsets(&self, group: Option<&str>, keys: &[String]) -> Result<Offsets> {
+        let s = self.state.lock().await;
+        Ok(s.committed_offsets(group, keys))
+    }
+}
+
+/// Keeps the logs and the committed offsets in lin-kv, so that every node sees the same ones.
+///
+/// Each key has a counter with the last offset handed out, under `offset/<key>`, and each message
+/// lives under its own `log/<key>/<offset>` entry. A send only has to win the CaS on the counter,
+/// and a poll reads the counter and then the entries it asks for, so neither gets slower as the
+/// log grows. Committed offsets get an entry per key too, `committed/<key>`, which commits only
+/// ever CaS forward.
+///
+/// A send writes its message after getting the offset, so a poll can see the counter ahead of the
+/// entries. It stops at the first missing one rather than skip it: returning a later offset and
+/// the missing message on a later poll would look like a lost write to the client. The only
+/// entries it skips are the ones marked skipped, offsets that were reserved and then not needed,
+/// or whose send failed to write its message. Entries are only ever created, never written over,
+/// so whichever of the message and the mark gets there first stands.
+///
+/// Each producer has a single entry per key, `producer/<key>/<producer>`, with the `(seq, offset)`s
+/// of its latest [`PRODUCER_WINDOW`](super::PRODUCER_WINDOW) sends, like [`Log`] keeps them. A
+/// send from a producer reserves its offset, CaSes it into that entry and only then writes its
+/// message. A retry that finds its seq there writes its message at that offset, which finishes
+/// the job if the first attempt died in between, and returns that offset. If the first attempt
+/// marked the offset skipped instead, the retry gets a new one. A send that finds its seq there
+/// only after reserving an offset, because a retry raced it, marks that offset skipped.
+///
+/// A batch reserves the offsets of all its messages for a key with a single CaS, moving the
+/// counter past all of them, and then writes each entry.
+///
+/// Retention moves `earliest/<key>`, the first offset polls still get, forward after sends and
+/// commits, and then writes a marker over each entry below it, since lin-kv can't delete them.
+/// Only `max_messages` and `below_committed` apply: the nodes don't share a clock to age
+/// messages by. For the latter, `groups/<key>` lists the consumer groups that committed the key.
+#[derive(Clone)]
+pub struct KvStore {
+    lin_kv_store: Storage,
+    retention: Retention,
+}
+
+impl KvStore {
+    pub fn new(runtime: Runtime, retention: Retention) -> Self {
+        if retention.max_age.is_some() {
+            warn!("Messages in lin-kv don't expire, only max_messages and below_committed apply");
+        }
+        KvStore {
+            lin_kv_store: lin_kv(runtime),
+            retention,
+        }
+    }
+
+    /// The last offset handed out for `key`, 0 if none
+    async fn last_offset(&self, key: &str) -> Result<usize> {
+        Ok(self.get_offset(offset_key(key)).await?.unwrap_or(0))
+    }
+
+    /// The first offset of `key` retention kept, 1 if it never dropped any
+    async fn earliest(&self, key: &str) -> Result<usize> {
+        // Saves polls a read when there's no retention
+        if self.retention.max_messages.is_none() && !self.retention.below_committed {
+            return Ok(1);
+        }
+        Ok(self.get_offset(earliest_key(key)).await?.unwrap_or(1))
+    }
+
+    /// Hands out the next `count` offsets of `key` and returns the first of them
+    async fn reserve_offsets(&self, key: &str, count: usize) -> Result<usize> {
+        loop {
+            let last = self.last_offset(key).await?;
+            // create_if_not_exists lets the first send to a key cas from 0
+            let cas_res = self
+                .lin_kv_store
+                .cas(Context::new().0, offset_key(key), last, last + count, true)
+                .await;
+            match cas_res {
+                Ok(()) => return Ok(last + 1),
+                Err(e) if failed_with(&*e, ErrorCode::PreconditionFailed) => {
+                    debug!("Someone else got offset {} of key: {}", last + 1, key);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Creates the entry at `offset` of `key`, unless there is one already. Returns the entry
+    /// that stands.
+    async fn create_entry(&self, key: &str, offset: usize, entry: Entry) -> Result<Entry> {
+        // Comparing against the entry itself: the CaS only goes through if there's none yet, or
+        // if it's the same already
+        let cas_res = self
+            .lin_kv_store
+            .cas(
+                Context::new().0,
+                message_key(key, offset),
+                entry.clone(),
+                entry.clone(),
+                true,
+            )
+            .await;
+        match cas_res {
+            Ok(()) => Ok(entry),
+            Err(e) if failed_with(&*e, ErrorCode::PreconditionFailed) => {
+                self.lin_kv_store
+                    .get(Context::new().0, message_key(key, offset))
+                    .await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes `msg` at `offset` of `key`. If a message is there already, from an earlier attempt
+    /// of the same send, that one stays.
+    async fn write_message(&self, key: &str, offset: usize, msg: &Msg) -> Result<()> {
+        match self
+            .create_entry(key, offset, Entry::Msg(msg.clone()))
+            .await?
+        {
+            Entry::Msg(_) => Ok(()),
+            Entry::Skipped | Entry::Dropped => Err(error::Error::new(
+                ErrorCode::TemporarilyUnavailable,
+                format!("offset {} of key {} was given up on", offset, key),
+            )
+            .into()),
+        }
+    }
+
+    /// The latest sends of `producer` to `key`, none if it never sent to it
+    async fn sent_by(&self, key: &str, producer: &Producer) -> Result<VecDeque<(u64, usize)>> {
+        match self
+            .lin_kv_store
+            .get(Context::new().0, producer_key(key, producer))
+            .await
+        {
+            Ok(sent) => Ok(sent),
+            Err(e) if failed_with(&*e, ErrorCode::KeyDoesNotExist) => Ok(VecDeque::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Marks `offsets` of `key` skipped, unless their message made it there after all, so that
+    /// polls don't stop at them. Only logs what it couldn't mark: it's called when something
+    /// already failed.
+    async fn give_up(&self, key: &str, offsets: impl IntoIterator<Item = usize>) {
+        for offset in offsets {
+            if let Err(e) = self.create_entry(key, offset, Entry::Skipped).await {
+                warn!(
+                    "Couldn't mark offset {} of key {} skipped: {}",
+                    offset, key, e
+                );
+            }
+        }
+    }
+
+    /// Drops what retention lets go of from the log of `key`, whose last offset handed out is
+    /// `last`. Only logs what it couldn't drop, the send or commit it follows went through.
+    async fn truncate(&self, key: &str, last: usize) {
+        if let Err(e) = self.try_truncate(key, last).await {
+            warn!("Couldn't truncate key {}: {}", key, e);
+        }
+    }
+
+    async fn try_truncate(&self, key: &str, last: usize) -> Result<()> {
+        let mut keep_from = 1;
+        if let Some(max_messages) = self.retention.max_messages {
+            keep_from = keep_from.max((last + 1).saturating_sub(max_messages));
+        }
+        if self.retention.below_committed {
+            if let Some(committed) = self.lowest_committed(key).await? {
+                keep_from = keep_from.max(committed.min(last + 1));
+            }
+        }
+        if keep_from == 1 {
+            return Ok(());
+        }
+        let dropped_from = loop {
+            let earliest = self.earliest(key).await?;
+            if keep_from <= earliest {
+                return Ok(());
+            }
+            // create_if_not_exists covers the first truncation, from 1
+            let cas_res = self
+                .lin_kv_store
+                .cas(
+                    Context::new().0,
+                    earliest_key(key),
+                    earliest,
+                    keep_from,
+                    true,
+                )
+                .await;
+            match cas_res {
+                Ok(()) => break earliest,
+                Err(e) if failed_with(&*e, ErrorCode::PreconditionFailed) => {
+                    debug!("Someone else truncated key {} in between", key);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        // Polls don't read below the new earliest offset anymore, and whoever moved it past an
+        // entry is the only one writing over it
+        for offset in dropped_from..keep_from {
+            self.lin_kv_store
+                .put(Context::new().0, message_key(key, offset), Entry::Dropped)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The lowest offset of `key` any consumer group committed
+    async fn lowest_committed(&self, key: &str) -> Result<Option<usize>> {
+        let mut lowest: Option<usize> = None;
+        for group in self.groups_of(key).await? {
+            if let Some(offset) = self
+                .get_offset(committed_key(group.as_deref(), key))
+                .await?
+            {
+                lowest = Some(lowest.map_or(offset, |lowest| lowest.min(offset)));
+            }
+        }
+        Ok(lowest)
+    }
+
+    /// The consumer groups that committed `key`, `None` being the default one
+    async fn groups_of(&self, key: &str) -> Result<Vec<Option<String>>> {
+        match self
+            .lin_kv_store
+            .get(Context::new().0, groups_key(key))
+            .await
+        {
+            Ok(groups) => Ok(groups),
+            Err(e) if failed_with(&*e, ErrorCode::KeyDoesNotExist) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds `group` to the groups that committed `key`, before it commits, so that a truncation
+    /// never misses its offset
+    async fn add_group(&self, key: &str, group: Option<&str>) -> Result<()> {
+        loop {
+            let groups = self.groups_of(key).await?;
+            if groups.iter().any(|g| g.as_deref() == group) {
+                return Ok(());
+            }
+            let mut updated = groups.clone();
+            updated.push(group.map(String::from));
+            // create_if_not_exists covers the first group
+            let cas_res = self
+                .lin_kv_store
+                .cas(Context::new().0, groups_key(key), groups, updated, true)
+                .await;
+            match cas_res {
+                Ok(()) => return Ok(()),
+                Err(e) if failed_with(&*e, ErrorCode::PreconditionFailed) => {
+                    debug!("Another group committed key {} in between", key);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The offset stored under `kv_key`, if any
+    async fn get_offset(&self, kv_key: String) -> Result<Option<usize>> {
+        match self
+            .lin_kv_store
+            .get::<usize>(Context::new().0, kv_key)
+            .await
+        {
+            Ok(offset) => Ok(Some(offset)),
+            Err(e) if failed_with(&*e, ErrorCode::KeyDoesNotExist) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn offset_key(key: &str) -> String {
+    format!("offset/{}", key)
+}
+
+fn message_key(key: &str, offset: usize) -> String {
+    format!("log/{}/{}", key, offset)
+}
+
+fn producer_key(key: &str, producer: &Producer) -> String {
+    // Like the group of committed offsets, the producer id can have anything in it
+    let id = serde_json::to_string(&producer.id).unwrap();
+    format!("producer/{}/{}", key, id)
+}
+
+/// What a `log/<key>/<offset>` entry holds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Entry {
+    Msg(Msg),
+    /// No message is coming for this offset
+    Skipped,
+    /// Retention dropped whatever this offset held
+    Dropped,
+}
+
+fn earliest_key(key: &str) -> String {
+    format!("earliest/{}", key)
+}
+
+fn groups_key(key: &str) -> String {
+    format!("groups/{}", key)
+}
+
+fn committed_key(group: Option<&str>, key: &str) -> String {
+    match group {
+        None => format!("committed/{}", key),
+        // Group and key both come from clients, so they can't simply be joined with a slash
+        Some(group) => format!(
+            "group-committed/{}",
+            serde_json::to_string(&(group, key)).unwrap()
+        ),
+    }
+}
+
+#[async_trait]
+impl LogStore for KvStore {
+    async fn append(&self, key: &str, msg: Msg, producer: Option<&Producer>) -> Result<usize> {
+        let Some(producer) = producer else {
+            let offset = self.reserve_offsets(key, 1).await?;
+            if let Err(e) = self.write_message(key, offset, &msg).await {
+                self.give_up(key, [offset]).await;
+                return Err(e);
+            }
+            self.truncate(key, offset).await;
+            return Ok(offset);
+        };
+        let mut reserved = None;
+        loop {
+            let sent = self.sent_by(key, producer).await?;
+            let mut updated = sent.clone();
+            match sequence_in(&sent, producer.seq) {
+                Sequence::New => {}
+                Sequence::Duplicate(offset) => {
+                    // In case the first attempt didn't get to write it
+                    let entry = Entry::Msg(msg.clone());
+                    match self.create_entry(key, offset, entry).await? {
+                        // Retention dropped it, whether it was written or not, it's below what
+                        // polls get either way
+                        Entry::Msg(_) | Entry::Dropped => {
+                            self.give_up(key, reserved).await;
+                            return Ok(offset);
+                        }
+                        // The first attempt gave up on it, this one needs an offset of its own
+                        Entry::Skipped => updated.retain(|(seq, _)| *seq != producer.seq),
+                    }
+                }
+                Sequence::Stale => {
+                    self.give_up(key, reserved).await;
+                    return Err(producer.stale().into());
+                }
+            }
+            let offset = match reserved {
+                Some(offset) => offset,
+                None => self.reserve_offsets(key, 1).await?,
+            };
+            reserved = Some(offset);
+            remember(&mut updated, producer.seq, offset);
+            // create_if_not_exists covers the producer's first send to the key
+            let cas_res = self
+                .lin_kv_store
+                .cas(
+                    Context::new().0,
+                    producer_key(key, producer),
+                    sent,
+                    updated,
+                    true,
+                )
+                .await;
+            match cas_res {
+                Ok(()) => {
+                    if let Err(e) = self.write_message(key, offset, &msg).await {
+                        self.give_up(key, [offset]).await;
+                        return Err(e);
+                    }
+                    self.truncate(key, offset).await;
+                    return Ok(offset);
+                }
+                Err(e) if failed_with(&*e, ErrorCode::PreconditionFailed) => {
+                    debug!("Producer {} sent to key {} in between", producer.id, key);
+                }
+                Err(e) => {
+                    self.give_up(key, reserved).await;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn append_batch(&self, key: &str, msgs: &[Msg]) -> Result<Vec<usize>> {
+        let first = self.reserve_offsets(key, msgs.len()).await?;
+        let offsets: Vec<usize> = (first..first + msgs.len()).collect();
+        // In order, so that a poll in between sees a prefix of the batch rather than a gap
+        for (i, (offset, msg)) in offsets.iter().zip(msgs).enumerate() {
+            if let Err(e) = self.write_message(key, *offset, msg).await {
+                self.give_up(key, offsets[i..].iter().copied()).await;
+                return Err(e);
+            }
+        }
+        self.truncate(key, first + msgs.len() - 1).await;
+        Ok(offsets)
+    }
+
+    async fn read_from(&self, key: &str, offset: usize, max: Option<usize>) -> Result<Fetch> {
+        let earliest = self.earliest(key).await?;
+        let last = self.last_offset(key).await?;
+        // Nothing was dropped below an offset nobody sent to yet, like in `Log::read_from`
+        let truncated = (offset < earliest && earliest > 1).then_some(earliest);
+        let mut msgs = Vec::new();
+        for at in offset.max(earliest)..=last {
+            if max.is_some_and(|max| msgs.len() == max) {
+                return Ok(Fetch {
+                    msgs,
+                    earliest: truncated,
+                    next: Some(at),
+                });
+            }
+            match self
+                .lin_kv_store
+                .get::<Entry>(Context::new().0, message_key(key, at))
+                .await
+            {
+                Ok(Entry::Msg(msg)) => msgs.push((at, msg)),
+                Ok(Entry::Skipped) => {}
+                // Truncated since we read the earliest offset, start over from the new one
+                Ok(Entry::Dropped) => return self.read_from(key, offset, max).await,
+                Err(e) if failed_with(&*e, ErrorCode::KeyDoesNotExist) => {
+                    debug!("Message {} of key {} isn't written yet", at, key);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Fetch {
+            msgs,
+            earliest: truncated,
+            next: None,
+        })
+    }
+
+    async fn commit_offsets(&self, group: Option<&str>, offsets: Offsets) -> Result<()> {
+        for (key, offset) in offsets {
+            if self.retention.below_committed {
+                self.add_group(&key, group).await?;
+            }
+            loop {
+                let current = self.get_offset(committed_key(group, &key)).await?;
+                if current.is_some_and(|current| current >= offset) {
+                    debug!("Offset {} of key {} is already committed", offset, key);
+                    break;
+                }
+                // create_if_not_exists covers the first commit of a key
+                let cas_res = self
+                    .lin_kv_store
+                    .cas(
+                        Context::new().0,
+                        committed_key(group, &key),
+                        current.unwrap_or(0),
+                        offset,
+                        true,
+                    )
+                    .await;
+                match cas_res {
+                    Ok(()) => break,
+                    Err(e) if failed_with(&*e, ErrorCode::PreconditionFailed) => {
+                        debug!("Someone else committed key {} in between", key);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if self.retention.below_committed {
+                let last = self.last_offset(&key).await?;
+                self.truncate(&key, last).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn committed_offsets(&self, group: Option<&str>, keys: &[String]) -> Result<Offsets> {
+        let mut offsets = Offsets::new();
+        for key in keys {
+            if let Some(offset) = self.get_offset(committed_key(group, key)).await? {
+                offsets.insert(key.clone(), offset);
+            }
+        }
+        Ok(offsets)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn memory_store_appends_and_reads() {
+        let store = MemoryStore::default();
+        assert_eq!(1, store.append("k", json!(10), None).await.unwrap());
+        assert_eq!(2, store.append("k", json!(11), None).await.unwrap());
+        assert_eq!(1, store.append("other", json!(20), None).await.unwrap());
+        assert_eq!(
+            vec![3, 4],
+            store
+                .append_batch("k", &[json!(12), json!(13)])
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            vec![(2, json!(11)), (3, json!(12)), (4, json!(13))],
+            store.read_from("k", 2, None).await.unwrap().msgs
+        );
+        assert_eq!(
+            Fetch::default(),
+            store.read_from("missing", 1, None).await.unwrap()
+        );
+
+        store
+            .commit_offsets(None, Offsets::from([(String::from("k"), 2)]))
+            .await
+            .unwrap();
+        store
+            .commit_offsets(Some("g"), Offsets::from([(String::from("k"), 1)]))
+            .await
+            .unwrap();
+        let keys = [String::from("k"), String::from("other")];
+        assert_eq!(
+            Offsets::from([(String::from("k"), 2)]),
+            store.committed_offsets(None, &keys).await.unwrap()
+        );
+        assert_eq!(
+            Offsets::from([(String::from("k"), 1)]),
+            store.committed_offsets(Some("g"), &keys).await.unwrap()
+        );
+        assert!(store
+            .committed_offsets(Some("h"), &keys)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_store_compacts_below_committed() {
+        let store = MemoryStore::new(Retention {
+            below_committed: true,
+            ..Retention::default()
+        });
+        for msg in 0..4 {
+            store.append("k", json!(msg), None).await.unwrap();
+        }
+        let commit = |offset| Offsets::from([(String::from("k"), offset)]);
+        store.commit_offsets(Some("g"), commit(2)).await.unwrap();
+        // The other group still needs offset 2
+        store.commit_offsets(None, commit(3)).await.unwrap();
+        assert_eq!(
+            Fetch {
+                msgs: vec![(2, json!(1)), (3, json!(2)), (4, json!(3))],
+                earliest: Some(2),
+                next: None
+            },
+            store.read_from("k", 1, None).await.unwrap()
+        );
+        store.commit_offsets(Some("g"), commit(4)).await.unwrap();
+        assert_eq!(
+            Some(3),
+            store.read_from("k", 1, None).await.unwrap().earliest
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Local implementation of Maelstrom's `lin-kv`, `seq-kv` and `lww-kv` services.
+//!
+//! [`Kv`] speaks the same `read`/`write`/`cas` protocol as the real services, so it can be
+//! registered in a [`Simulation`](crate::sim::Simulation) under the service's name or run on its
+//! own as a JSON-lines process (see `src/bin/kv.rs`).
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::debug;
+use maelstrom::protocol::{ErrorMessageBody, Message};
+use maelstrom::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::sim::{reply, Process, Rng};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    /// Every operation sees the latest value
+    Linearizable,
+    /// Reads may return stale values, but never older than what the same client has already
+    /// written or read
+    Sequential,
+    /// Reads may return any recent value, regardless of what the client has seen before
+    LastWriteWins,
+}
+
+impl Consistency {
+    /// The address Maelstrom uses for the service
+    pub fn service_name(&self) -> &'static str {
+        match self {
+            Consistency::Linearizable => "lin-kv",
+            Consistency::Sequential => "seq-kv",
+            Consistency::LastWriteWins => "lww-kv",
+        }
+    }
+
+    pub fn from_service_name(name: &str) -> Option<Self> {
+        match name {
+            "lin-kv" => Some(Consistency::Linearizable),
+            "seq-kv" => Some(Consistency::Sequential),
+            "lww-kv" => Some(Consistency::LastWriteWins),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestBody {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum ResponseBody {
+    ReadOk { value: Value },
+    WriteOk,
+    CasOk,
+}
+
+pub struct Kv {
+    consistency: Consistency,
+    rng: Rng,
+    // Probability that a read is served from an older version, when the consistency model allows
+    stale_reads: f64,
+    // Every version ever written, per key (keyed by the key's JSON text). Versions are numbered
+    // from a single counter shared by all keys, so a version number identifies a snapshot of the
+    // whole store.
+    history: HashMap<String, Vec<(u64, Value)>>,
+    version: u64,
+    // Latest version each client is known to have observed
+    floors: HashMap<String, u64>,
+}
+
+impl Kv {
+    pub fn new(consistency: Consistency, seed: u64) -> Self {
+        Kv {
+            consistency,
+            rng: Rng::new(seed),
+            stale_reads: 0.0,
+            history: HashMap::new(),
+            version: 0,
+            floors: HashMap::new(),
+        }
+    }
+
+    pub fn lin() -> Self {
+        Kv::new(Consistency::Linearizable, 0)
+    }
+
+    /// Makes reads return an older version with probability `p`. Has no effect on lin-kv.
+    pub fn with_stale_reads(mut self, p: f64) -> Self {
+        self.stale_reads = p;
+        self
+    }
+
+    pub fn consistency(&self) -> Consistency {
+        self.consistency
+    }
+
+    fn latest(&self, key: &str) -> Option<&Value> {
+        self.history.get(key).and_then(|h| h.last()).map(|(_, v)| v)
+    }
+
+    fn put(&mut self, client: &str, key: String, value: Value) {
+        self.version += 1;
+        self.history
+            .entry(key)
+            .or_default()
+            .push((self.version, value));
+        self.floors.insert(client.to_string(), self.version);
+    }
+
+    fn read(&mut self, client: &str, key: &str) -> Option<Value> {
+        let floor = match self.consistency {
+            Consistency::Linearizable => return self.latest(key).cloned(),
+            Consistency::Sequential => self.floors.get(client).copied().unwrap_or(0),
+            Consistency::LastWriteWins => 0,
+        };
+        let at = if self.stale_reads > 0.0 && self.rng.chance(self.stale_reads) {
+            floor + self.rng.below(self.version - floor + 1)
+        } else {
+            self.version
+        };
+        if self.consistency == Consistency::Sequential {
+            self.floors.insert(client.to_string(), at);
+        }
+        debug!(
+            "{} reads {} at version {} (latest {})",
+            client, key, at, self.version
+        );
+        self.history
+            .get(key)?
+            .iter()
+            .rev()
+            .find(|(version, _)| *version <= at)
+            .map(|(_, v)| v.clone())
+    }
+
+    /// Applies one request and returns the body of the reply.
+    pub fn apply(&mut self, client: &str, body: &maelstrom::protocol::MessageBody) -> Value {
+        let result = match body.as_obj::<RequestBody>() {
+            Err(e) => {
+                debug!("malformed kv request: {}", e);
+                Err(Error::MalformedRequest)
+            }
+            Ok(RequestBody::Read { key }) => match self.read(client, &key.to_string()) {
+                Some(value) => Ok(ResponseBody::ReadOk { value }),
+                None => Err(Error::KeyDoesNotExist),
+            },
+            Ok(RequestBody::Write { key, value }) => {
+                self.put(client, key.to_string(), value);
+                Ok(ResponseBody::WriteOk)
+            }
+            Ok(RequestBody::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            }) => {
+                let key = key.to_string();
+                match self.latest(&key) {
+                    None if create_if_not_exists => {
+                        self.put(client, key, to);
+                        Ok(ResponseBody::CasOk)
+                    }
+                    None => Err(Error::KeyDoesNotExist),
+                    Some(current) if *current == from => {
+                        self.put(client, key, to);
+                        Ok(ResponseBody::CasOk)
+                    }
+                    Some(current) => Err(Error::Custom(
+                        Error::PreconditionFailed.code(),
+                        format!("expected {}, but had {}", from, current),
+                    )),
+                }
+            }
+        };
+        match result {
+            Ok(resp) => serde_json::to_value(resp).expect("kv responses serialize"),
+            Err(e) => serde_json::to_value(ErrorMessageBody::from(e)).expect("errors serialize"),
+        }
+    }
+}
+
+impl Process for Kv {
+    fn handle(&mut self, _now: Duration, msg: Message, out: &mut Vec<Message>) -> Result<()> {
+        let body = self.apply(&msg.src, &msg.body);
+        out.push(reply(&msg, body)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maelstrom::protocol::MessageBody;
+    use serde_json::json;
+
+    fn body(v: Value) -> MessageBody {
+        serde_json::from_value(v).unwrap()
+    }
+
+    #[test]
+    fn read_write_cas() {
+        let mut kv = Kv::lin();
+        let read = body(json!({"type": "read", "key": "a"}));
+        assert_eq!(json!(20), kv.apply("n0", &read)["code"]);
+        let cas = body(json!({"type": "cas", "key": "a", "from": 0, "to": 1}));
+        assert_eq!(json!(20), kv.apply("n0", &cas)["code"]);
+        let create = body(
+            json!({"type": "cas", "key": "a", "from": 0, "to": 1, "create_if_not_exists": true}),
+        );
+        assert_eq!(json!({"type": "cas_ok"}), kv.apply("n0", &create));
+        assert_eq!(
+            json!({"type": "read_ok", "value": 1}),
+            kv.apply("n1", &read)
+        );
+        // The value is 1 now, so a cas from 0 fails
+        assert_eq!(json!(22), kv.apply("n1", &cas)["code"]);
+        let write = body(json!({"type": "write", "key": "a", "value": [1, 2]}));
+        assert_eq!(json!({"type": "write_ok"}), kv.apply("n1", &write));
+        assert_eq!(json!([1, 2]), kv.apply("n0", &read)["value"]);
+    }
+
+    #[test]
+    fn seq_kv_stale_reads_respect_client_order() {
+        let mut kv = Kv::new(Consistency::Sequential, 3).with_stale_reads(1.0);
+        for i in 0..10 {
+            let write = body(json!({"type": "write", "key": "k", "value": i}));
+            kv.apply("n0", &write);
+        }
+        let read = body(json!({"type": "read", "key": "k"}));
+        // n0 wrote 9 last, so it can never see anything older
+        for _ in 0..20 {
+            assert_eq!(json!(9), kv.apply("n0", &read)["value"]);
+        }
+        // n1 can see stale values, but never goes back in time
+        let seen: Vec<Value> = (0..20).map(|_| kv.apply("n1", &read)).collect();
+        assert!(seen[0] != json!({"type": "read_ok", "value": 9}));
+        let values: Vec<i64> = seen
+            .iter()
+            .filter_map(|b| b.get("value").and_then(Value::as_i64))
+            .collect();
+        assert!(values.windows(2).all(|w| w[0] <= w[1]));
+    }
+}
@@ -0,0 +1,8 @@
+pub mod broadcast;
+pub mod checker;
+pub mod counter;
+pub mod error;
+pub mod kafka;
+pub mod kv;
+pub mod sim;
+pub mod txn;
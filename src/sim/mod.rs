@@ -0,0 +1,449 @@
+//! Deterministic, in-process stand-in for the Maelstrom network.
+//!
+//! A [`Simulation`] hosts a set of [`Process`]es (nodes under test and services), moves their
+//! messages around on a virtual clock and draws every random decision from one seeded [`Rng`].
+//! Given the same seed and the same in-process nodes, two runs deliver exactly the same messages
+//! at exactly the same virtual times, which is what lets us turn a failing run into a seed.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use maelstrom::protocol::{Message, MessageBody};
+use maelstrom::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+mod nemesis;
+mod process;
+mod rng;
+
+pub use nemesis::{Fault, Nemesis};
+pub use process::{reply, Binary, Process};
+pub use rng::Rng;
+
+/// How long a message spends on the wire.
+#[derive(Clone, Debug)]
+pub enum Latency {
+    Constant(Duration),
+    Uniform(Duration, Duration),
+    /// Exponentially distributed with the given mean, like Maelstrom's `--latency`.
+    Exponential(Duration),
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut Rng) -> Duration {
+        match self {
+            Latency::Constant(d) => *d,
+            Latency::Uniform(min, max) => {
+                let span = max.saturating_sub(*min).as_micros() as u64;
+                *min + Duration::from_micros(rng.below(span + 1))
+            }
+            Latency::Exponential(mean) => {
+                let micros = -(mean.as_micros() as f64) * (1.0 - rng.unit()).ln();
+                Duration::from_micros(micros as u64)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub seed: u64,
+    pub latency: Latency,
+    /// Interval at which every process gets a `tick`
+    pub tick: Duration,
+    /// Keep virtual time from running ahead of the wall clock. Needed when hosting [`Binary`]
+    /// nodes, whose own timers run on real time.
+    pub pace: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            seed: 0,
+            latency: Latency::Constant(Duration::ZERO),
+            tick: Duration::from_millis(10),
+            pace: false,
+        }
+    }
+}
+
+/// A message as it was handed to its destination.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delivery {
+    pub at: Duration,
+    pub message: Message,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct InFlight {
+    at: Duration,
+    // Tie breaker so that messages due at the same time are delivered in send order
+    seq: u64,
+    message: Message,
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub struct Simulation {
+    config: Config,
+    rng: Rng,
+    now: Duration,
+    next_tick: Duration,
+    seq: u64,
+    client_msg_id: u64,
+    nodes: Vec<String>,
+    processes: BTreeMap<String, Box<dyn Process>>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    // Messages addressed to clients (ids starting with `c`), in delivery order
+    client_inbox: Vec<Delivery>,
+    journal: Vec<Delivery>,
+    nemesis: Nemesis,
+    started: Option<Instant>,
+}
+
+impl Simulation {
+    pub fn new(config: Config) -> Self {
+        Simulation {
+            rng: Rng::new(config.seed),
+            next_tick: config.tick,
+            config,
+            now: Duration::ZERO,
+            seq: 0,
+            client_msg_id: 0,
+            nodes: Vec::new(),
+            processes: BTreeMap::new(),
+            in_flight: BinaryHeap::new(),
+            client_inbox: Vec::new(),
+            journal: Vec::new(),
+            nemesis: Nemesis::new(),
+            started: None,
+        }
+    }
+
+    pub fn with_nemesis(mut self, nemesis: Nemesis) -> Self {
+        self.nemesis = nemesis;
+        self
+    }
+
+    /// Gives access to the nemesis, e.g. to apply a [`Fault`] right now instead of on a schedule.
+    pub fn nemesis(&mut self) -> &mut Nemesis {
+        &mut self.nemesis
+    }
+
+    /// Applies a fault immediately.
+    pub fn fault(&mut self, fault: Fault) {
+        info!("{:?}: {:?}", self.now, fault);
+        self.nemesis.apply(fault, &self.nodes, &mut self.rng);
+    }
+
+    /// Adds a node under test. It will receive an `init` message listing every node once
+    /// [`Simulation::init`] is called.
+    pub fn add_node(&mut self, id: impl Into<String>, process: impl Process + 'static) {
+        let id = id.into();
+        self.nodes.push(id.clone());
+        self.processes.insert(id, Box::new(process));
+    }
+
+    /// Adds a process that is reachable by address but is not part of the cluster, such as
+    /// `seq-kv`. Services don't get an `init` message.
+    pub fn add_service(&mut self, id: impl Into<String>, process: impl Process + 'static) {
+        self.processes.insert(id.into(), Box::new(process));
+    }
+
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    /// Every message delivered so far, in delivery order. Two runs with the same seed and the
+    /// same in-process nodes produce the same journal.
+    pub fn journal(&self) -> &[Delivery] {
+        &self.journal
+    }
+
+    /// Messages delivered to clients so far.
+    pub fn client_inbox(&self) -> &[Delivery] {
+        &self.client_inbox
+    }
+
+    /// Sends `init` to every node from `c0`, the way Maelstrom starts a test.
+    pub fn init(&mut self) -> Result<()> {
+        for node_id in self.nodes.clone() {
+            let body = json!({"type": "init", "node_id": node_id, "node_ids": self.nodes});
+            self.request("c0", node_id, body)?;
+        }
+        Ok(())
+    }
+
+    /// Sends `body` from `client` to `dest` with a fresh `msg_id`, which is returned so the
+    /// reply can be found later with [`Simulation::reply_to`].
+    pub fn request<T: Serialize>(
+        &mut self,
+        client: impl Into<String>,
+        dest: impl Into<String>,
+        body: T,
+    ) -> Result<u64> {
+        let mut msg = maelstrom::protocol::message(client, dest, body)?;
+        if let Some(Value::String(typ)) = msg.body.extra.remove("type") {
+            msg.body.typ = typ;
+        }
+        self.client_msg_id += 1;
+        msg.body.msg_id = self.client_msg_id;
+        self.send(msg);
+        Ok(self.client_msg_id)
+    }
+
+    /// Finds the reply a client got to the request with the given `msg_id`, if it has arrived.
+    pub fn reply_to(&self, client: &str, msg_id: u64) -> Option<&Message> {
+        self.client_inbox
+            .iter()
+            .map(|d| &d.message)
+            .find(|m| m.dest == client && m.body.in_reply_to == msg_id)
+    }
+
+    /// Sends a request and runs the simulation until its reply arrives or `timeout` of virtual
+    /// time passes.
+    pub fn call<T: Serialize>(
+        &mut self,
+        client: &str,
+        dest: &str,
+        body: T,
+        timeout: Duration,
+    ) -> Result<Option<MessageBody>> {
+        let msg_id = self.request(client, dest, body)?;
+        let deadline = self.now + timeout;
+        while self.reply_to(client, msg_id).is_none() && self.next_event_at() <= deadline {
+            self.step()?;
+        }
+        Ok(self.reply_to(client, msg_id).map(|m| m.body.clone()))
+    }
+
+    /// Puts a message on the wire. It will be delivered after a latency drawn from the config,
+    /// unless the nemesis decides otherwise.
+    pub fn send(&mut self, message: Message) {
+        let latencies = self.nemesis.on_send(
+            &message.src,
+            &message.dest,
+            &self.config.latency,
+            &mut self.rng,
+        );
+        if latencies.is_empty() {
+            debug!("nemesis dropped {:?}", message);
+        }
+        for latency in latencies {
+            self.seq += 1;
+            self.in_flight.push(Reverse(InFlight {
+                at: self.now + latency,
+                seq: self.seq,
+                message: message.clone(),
+            }));
+        }
+    }
+
+    fn next_event_at(&self) -> Duration {
+        let mut at = self.next_tick;
+        if let Some(Reverse(m)) = self.in_flight.peek() {
+            at = at.min(m.at);
+        }
+        if let Some(fault_at) = self.nemesis.next_fault_at() {
+            at = at.min(fault_at);
+        }
+        at
+    }
+
+    fn wait_for_wall_clock(&mut self, at: Duration) {
+        if !self.config.pace {
+            return;
+        }
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let elapsed = started.elapsed();
+        if at > elapsed {
+            std::thread::sleep(at - elapsed);
+        }
+    }
+
+    /// Processes the next event: a scheduled fault, one message delivery or one clock tick.
+    pub fn step(&mut self) -> Result<()> {
+        let at = self.next_event_at();
+        self.wait_for_wall_clock(at);
+        if self.nemesis.next_fault_at() == Some(at) {
+            let (at, fault) = self.nemesis.pop_fault().unwrap();
+            self.now = at;
+            self.fault(fault);
+            return Ok(());
+        }
+        match self.in_flight.peek() {
+            Some(Reverse(m)) if m.at == at => {
+                let Reverse(m) = self.in_flight.pop().unwrap();
+                self.now = m.at;
+                if self.nemesis.connected(&m.message.src, &m.message.dest) {
+                    self.deliver(m.message)
+                } else {
+                    debug!("partition dropped {:?}", m.message);
+                    Ok(())
+                }
+            }
+            _ => {
+                self.now = self.next_tick;
+                self.next_tick += self.config.tick;
+                let mut out = Vec::new();
+                for process in self.processes.values_mut() {
+                    process.tick(self.now, &mut out)?;
+                }
+                for msg in out {
+                    self.send(msg);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs every event due in the next `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) -> Result<()> {
+        let deadline = self.now + duration;
+        while self.next_event_at() <= deadline {
+            self.step()?;
+        }
+        self.now = deadline;
+        Ok(())
+    }
+
+    fn deliver(&mut self, message: Message) -> Result<()> {
+        let delivery = Delivery {
+            at: self.now,
+            message,
+        };
+        self.journal.push(delivery.clone());
+        let dest = delivery.message.dest.clone();
+        if let Some(process) = self.processes.get_mut(&dest) {
+            let mut out = Vec::new();
+            process.handle(self.now, delivery.message, &mut out)?;
+            for msg in out {
+                self.send(msg);
+            }
+        } else if dest.starts_with('c') {
+            self.client_inbox.push(delivery);
+        } else {
+            debug!("dropping message to unknown destination {}", dest);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Replies to `echo` and forwards every `broadcast` to the other nodes on the next tick.
+    struct Gossiper {
+        id: String,
+        peers: Vec<String>,
+        seen: Vec<u64>,
+        pending: Vec<u64>,
+    }
+
+    impl Process for Gossiper {
+        fn handle(&mut self, _now: Duration, msg: Message, out: &mut Vec<Message>) -> Result<()> {
+            match msg.get_type() {
+                "init" => {
+                    self.id = msg.dest.clone();
+                    let body: maelstrom::protocol::InitMessageBody = msg.body.as_obj()?;
+                    self.peers = body.nodes.into_iter().filter(|n| *n != self.id).collect();
+                    out.push(reply(&msg, json!({}))?);
+                }
+                "broadcast" | "gossip" => {
+                    let m = msg.body.extra["message"].as_u64().unwrap();
+                    if !self.seen.contains(&m) {
+                        self.seen.push(m);
+                        self.pending.push(m);
+                    }
+                    if msg.get_type() == "broadcast" {
+                        out.push(reply(&msg, json!({}))?);
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn tick(&mut self, _now: Duration, out: &mut Vec<Message>) -> Result<()> {
+            for m in self.pending.drain(..) {
+                for peer in &self.peers {
+                    let body = json!({"type": "gossip", "message": m});
+                    out.push(maelstrom::protocol::message(self.id.clone(), peer, body)?);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn run(seed: u64) -> Simulation {
+        let mut sim = Simulation::new(Config {
+            seed,
+            latency: Latency::Uniform(Duration::from_millis(1), Duration::from_millis(50)),
+            ..Config::default()
+        })
+        .with_nemesis(
+            Nemesis::new()
+                .with_drops(0.1)
+                .with_duplicates(0.1)
+                .with_delays(0.2, Latency::Exponential(Duration::from_millis(100)))
+                .partition_every(Duration::from_millis(200), Duration::from_millis(800)),
+        );
+        for i in 0..3 {
+            let node = Gossiper {
+                id: String::new(),
+                peers: vec![],
+                seen: vec![],
+                pending: vec![],
+            };
+            sim.add_node(format!("n{}", i), node);
+        }
+        sim.init().unwrap();
+        for m in 0..10 {
+            let dest = format!("n{}", m % 3);
+            sim.request("c1", dest, json!({"type": "broadcast", "message": m}))
+                .unwrap();
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        sim
+    }
+
+    #[test]
+    fn same_seed_replays_exactly() {
+        let a = run(7);
+        let b = run(7);
+        assert_eq!(a.journal(), b.journal());
+        assert_ne!(a.journal(), run(8).journal());
+    }
+
+    #[test]
+    fn replies_reach_clients() {
+        let sim = run(1);
+        // 3 init_ok + 10 broadcast_ok
+        assert_eq!(13, sim.client_inbox().len());
+        assert_eq!("init_ok", sim.reply_to("c0", 1).unwrap().get_type());
+        // msg ids 1 to 3 went to the init messages
+        assert_eq!("broadcast_ok", sim.reply_to("c1", 4).unwrap().get_type());
+    }
+}
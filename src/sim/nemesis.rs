@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use super::{Latency, Rng};
+
+/// A fault that can be switched on at a given point of a run.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// Splits the listed addresses into groups that can only talk within themselves. Addresses
+    /// that aren't listed (clients, usually) can still reach everyone.
+    Partition(Vec<Vec<String>>),
+    /// Splits the nodes into two random halves, like Maelstrom's `--nemesis partition`
+    RandomHalves,
+    /// Removes any partition
+    Heal,
+}
+
+/// Network faults injected by the [`Simulation`](super::Simulation).
+///
+/// Random faults (drops, duplicates, delays) only ever hit messages between nodes and services:
+/// Maelstrom doesn't lose client requests either, and it keeps `Simulation::call` usable.
+/// Partitions apply to whatever addresses they list.
+#[derive(Clone, Debug, Default)]
+pub struct Nemesis {
+    /// Probability that a message is lost
+    pub drop: f64,
+    /// Probability that a message is delivered twice
+    pub duplicate: f64,
+    /// Probability that a message gets `extra_latency` on top of the normal latency. This is
+    /// how messages get reordered.
+    pub delay: f64,
+    pub extra_latency: Option<Latency>,
+    schedule: Vec<(Duration, Fault)>,
+    partition: Vec<Vec<String>>,
+}
+
+impl Nemesis {
+    pub fn new() -> Self {
+        Nemesis::default()
+    }
+
+    pub fn with_drops(mut self, p: f64) -> Self {
+        self.drop = p;
+        self
+    }
+
+    pub fn with_duplicates(mut self, p: f64) -> Self {
+        self.duplicate = p;
+        self
+    }
+
+    pub fn with_delays(mut self, p: f64, extra_latency: Latency) -> Self {
+        self.delay = p;
+        self.extra_latency = Some(extra_latency);
+        self
+    }
+
+    /// Schedules `fault` to start at virtual time `at`.
+    pub fn at(mut self, at: Duration, fault: Fault) -> Self {
+        self.schedule.push((at, fault));
+        self.schedule.sort_by_key(|(at, _)| *at);
+        self
+    }
+
+    /// Alternates between random partitions and healing every `interval`, until `until`.
+    pub fn partition_every(mut self, interval: Duration, until: Duration) -> Self {
+        let mut at = interval;
+        let mut partitioned = false;
+        while at < until {
+            let fault = if partitioned {
+                Fault::Heal
+            } else {
+                Fault::RandomHalves
+            };
+            self.schedule.push((at, fault));
+            partitioned = !partitioned;
+            at += interval;
+        }
+        self.schedule.push((until, Fault::Heal));
+        self.schedule.sort_by_key(|(at, _)| *at);
+        self
+    }
+
+    pub(super) fn next_fault_at(&self) -> Option<Duration> {
+        self.schedule.first().map(|(at, _)| *at)
+    }
+
+    pub(super) fn pop_fault(&mut self) -> Option<(Duration, Fault)> {
+        if self.schedule.is_empty() {
+            None
+        } else {
+            Some(self.schedule.remove(0))
+        }
+    }
+
+    pub fn apply(&mut self, fault: Fault, nodes: &[String], rng: &mut Rng) {
+        self.partition = match fault {
+            Fault::Partition(groups) => groups,
+            Fault::Heal => Vec::new(),
+            Fault::RandomHalves => {
+                let mut shuffled = nodes.to_vec();
+                // Fisher-Yates
+                for i in (1..shuffled.len()).rev() {
+                    let j = rng.below(i as u64 + 1) as usize;
+                    shuffled.swap(i, j);
+                }
+                let other = shuffled.split_off(shuffled.len() / 2);
+                vec![shuffled, other]
+            }
+        };
+    }
+
+    pub fn partition(&self) -> &[Vec<String>] {
+        &self.partition
+    }
+
+    /// Whether the current partition lets `src` talk to `dest`
+    pub fn connected(&self, src: &str, dest: &str) -> bool {
+        let group_of = |addr: &str| {
+            self.partition
+                .iter()
+                .position(|g| g.iter().any(|a| a == addr))
+        };
+        match (group_of(src), group_of(dest)) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+
+    /// Decides what happens to a message when it is sent: returns the latency of each copy that
+    /// will be delivered (none if it's dropped, two if it's duplicated).
+    pub(super) fn on_send(
+        &self,
+        src: &str,
+        dest: &str,
+        latency: &Latency,
+        rng: &mut Rng,
+    ) -> Vec<Duration> {
+        if is_client(src) || is_client(dest) {
+            return vec![latency.sample(rng)];
+        }
+        if self.drop > 0.0 && rng.chance(self.drop) {
+            return vec![];
+        }
+        let copies = if self.duplicate > 0.0 && rng.chance(self.duplicate) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut d = latency.sample(rng);
+                if let Some(extra) = &self.extra_latency {
+                    if rng.chance(self.delay) {
+                        d += extra.sample(rng);
+                    }
+                }
+                d
+            })
+            .collect()
+    }
+}
+
+fn is_client(addr: &str) -> bool {
+    addr.starts_with('c')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn partitions_and_heal() {
+        let mut nemesis = Nemesis::new();
+        let mut rng = Rng::new(0);
+        let nodes = ids(&["n0", "n1", "n2", "n3"]);
+        nemesis.apply(
+            Fault::Partition(vec![ids(&["n0", "n1"]), ids(&["n2", "n3", "lin-kv"])]),
+            &nodes,
+            &mut rng,
+        );
+        assert!(nemesis.connected("n0", "n1"));
+        assert!(!nemesis.connected("n1", "n2"));
+        assert!(!nemesis.connected("n0", "lin-kv"));
+        assert!(nemesis.connected("c1", "n0"));
+        nemesis.apply(Fault::RandomHalves, &nodes, &mut rng);
+        assert_eq!(2, nemesis.partition().len());
+        assert_eq!(2, nemesis.partition()[0].len());
+        nemesis.apply(Fault::Heal, &nodes, &mut rng);
+        assert!(nemesis.connected("n1", "n2"));
+    }
+
+    #[test]
+    fn clients_are_never_dropped() {
+        let nemesis = Nemesis::new().with_drops(1.0);
+        let mut rng = Rng::new(0);
+        let latency = Latency::Constant(Duration::ZERO);
+        assert!(nemesis.on_send("n0", "n1", &latency, &mut rng).is_empty());
+        assert_eq!(1, nemesis.on_send("c1", "n1", &latency, &mut rng).len());
+    }
+}
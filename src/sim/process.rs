@@ -0,0 +1,190 @@
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use maelstrom::protocol::Message;
+use maelstrom::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Anything that can sit at an address of the simulated network: a node under test, a
+/// Maelstrom service such as `seq-kv`, or a stub written inside a test.
+///
+/// Messages a process wants to send are pushed to `out`. The simulation stamps nothing on them,
+/// so `src`, `dest` and `msg_id` have to be filled in by the process, just like with Maelstrom.
+pub trait Process {
+    /// Handles a message delivered at virtual time `now`.
+    fn handle(&mut self, now: Duration, msg: Message, out: &mut Vec<Message>) -> Result<()>;
+
+    /// Called on every tick of the virtual clock. Processes that need timers (gossip, retries)
+    /// compare `now` against their own deadlines here.
+    fn tick(&mut self, _now: Duration, _out: &mut Vec<Message>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds the reply to `req`, the same way `maelstrom::Runtime::reply` does: the type defaults to
+/// `<request type>_ok` and `in_reply_to` points at the request.
+pub fn reply<T: Serialize>(req: &Message, body: T) -> Result<Message> {
+    let mut msg = maelstrom::protocol::message(req.dest.clone(), req.src.clone(), body)?;
+    msg.body.in_reply_to = req.body.msg_id;
+    if let Some(Value::String(typ)) = msg.body.extra.remove("type") {
+        msg.body.typ = typ;
+    }
+    if msg.body.typ.is_empty() {
+        msg.body.typ = format!("{}_ok", req.body.typ);
+    }
+    Ok(msg)
+}
+
+/// Runs a compiled node (any of `src/bin/*` or the archived binaries) as a child process and
+/// talks JSON lines to it over stdin/stdout, the same way Maelstrom does.
+///
+/// The child runs on the real clock, so only the order in which the simulation delivers
+/// messages is reproducible from the seed. Whatever the node does in its own background tasks
+/// (the gossip loop in `broadcast`, for instance) is not.
+pub struct Binary {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+    settle: Duration,
+    reply_limit: Option<Duration>,
+    /// The requests delivered to the node it hasn't replied to yet, by sender and `msg_id`
+    unanswered: HashSet<(String, u64)>,
+    /// The `msg_id`s of the node's own requests that haven't been replied to yet
+    awaiting: HashSet<u64>,
+}
+
+impl Binary {
+    /// Spawns `command` with piped stdin/stdout. Stderr is left as configured on `command`.
+    pub fn spawn(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (tx, lines) = channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Binary {
+            child,
+            stdin,
+            lines,
+            settle: Duration::from_millis(20),
+            reply_limit: None,
+            unanswered: HashSet::new(),
+            awaiting: HashSet::new(),
+        })
+    }
+
+    /// How long to wait for output after delivering a message before handing control back to
+    /// the simulation. Output arriving later is picked up on the next tick.
+    pub fn with_settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Makes delivering a message wait, for up to `limit` of real time and instead of `settle`,
+    /// until the node replied to every request it was given, or is waiting on replies to its own.
+    /// Either way it's done until the simulation delivers something else, so however long it
+    /// takes on the machine running the test doesn't eat into virtual time. That's all the
+    /// pacing a node that only acts on the messages it gets needs: it runs on the virtual clock.
+    /// Output a node writes on its own timers still comes on the real one, and is only picked up
+    /// on ticks.
+    pub fn waiting_for_replies(mut self, limit: Duration) -> Self {
+        self.reply_limit = Some(limit);
+        self
+    }
+
+    fn collect(&mut self, wait: Duration, out: &mut Vec<Message>) -> Result<()> {
+        loop {
+            let line = if wait.is_zero() {
+                match self.lines.try_recv() {
+                    Ok(line) => line,
+                    Err(_) => return Ok(()),
+                }
+            } else {
+                match self.lines.recv_timeout(wait) {
+                    Ok(line) => line,
+                    Err(RecvTimeoutError::Timeout) => return Ok(()),
+                    Err(RecvTimeoutError::Disconnected) => return Err("node exited".into()),
+                }
+            };
+            self.parse_into(&line, out);
+        }
+    }
+
+    /// Collects output until the node is done with what it was given, see
+    /// [`Binary::waiting_for_replies`], or `limit` has passed
+    fn collect_until_done(&mut self, limit: Duration, out: &mut Vec<Message>) -> Result<()> {
+        let deadline = Instant::now() + limit;
+        while !self.unanswered.is_empty() && self.awaiting.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(left) {
+                Ok(line) => self.parse_into(&line, out),
+                Err(RecvTimeoutError::Timeout) => return Ok(()),
+                Err(RecvTimeoutError::Disconnected) => return Err("node exited".into()),
+            }
+        }
+        // Anything else it had written by then
+        self.collect(Duration::ZERO, out)
+    }
+
+    fn parse_into(&mut self, line: &str, out: &mut Vec<Message>) {
+        if line.trim().is_empty() {
+            return;
+        }
+        match serde_json::from_str::<Message>(line) {
+            Ok(msg) => {
+                if msg.body.in_reply_to != 0 {
+                    self.unanswered
+                        .remove(&(msg.dest.clone(), msg.body.in_reply_to));
+                } else if msg.body.msg_id != 0 {
+                    self.awaiting.insert(msg.body.msg_id);
+                }
+                out.push(msg)
+            }
+            Err(e) => warn!("ignoring unparseable output {:?}: {}", line, e),
+        }
+    }
+}
+
+impl Process for Binary {
+    fn handle(&mut self, _now: Duration, msg: Message, out: &mut Vec<Message>) -> Result<()> {
+        let line = serde_json::to_string(&msg)?;
+        debug!("to child: {}", line);
+        self.stdin.write_all(line.as_bytes())?;
+        self.stdin.write_all(b"\n")?;
+        self.stdin.flush()?;
+        if msg.body.in_reply_to != 0 {
+            self.awaiting.remove(&msg.body.in_reply_to);
+        } else if msg.body.msg_id != 0 {
+            self.unanswered.insert((msg.src, msg.body.msg_id));
+        }
+        match self.reply_limit {
+            Some(limit) => self.collect_until_done(limit, out),
+            None => self.collect(self.settle, out),
+        }
+    }
+
+    fn tick(&mut self, _now: Duration, out: &mut Vec<Message>) -> Result<()> {
+        self.collect(Duration::ZERO, out)
+    }
+}
+
+impl Drop for Binary {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,413 @@
+//! Messages of the txn-rw-register workload (challenges 6a to 6c).
+//!
+//! Micro-operations travel as `["r", key, value]` and `["w", key, value]` triples, with a `null`
+//! value in reads until the node fills it in.
+//!
+//! [`execute`] runs a transaction on a single node's registers, and [`Registers`] is the
+//! replicated store the totally available `txn` node runs transactions against. Both nodes can
+//! run them on [`Mvcc`] instead, for snapshot isolation, and the `txn` node on [`TwoPhase`], for
+//! serializable ones.
+
+use std::collections::HashMap;
+
+use serde::de;
+use serde::{ser::SerializeSeq, Deserialize, Serialize};
+
+pub mod mvcc;
+pub mod two_phase;
+
+pub use mvcc::{Mvcc, Snapshot};
+pub use two_phase::{Decision, TwoPhase, TxnId};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestBody {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    #[serde(rename = "txn")]
+    Transaction { txn: Vec<Operation> },
+    /// A node's registers, for the others to merge into theirs. A list of `[key, value]` pairs
+    /// rather than a map: maelstrom-node can't parse integer keys out of a JSON object.
+    Gossip { registers: Vec<(usize, Versioned)> },
+    /// Asks the owner of `keys` to lock them for transaction `id`, which writes `writes` if it
+    /// commits
+    Prepare {
+        id: TxnId,
+        keys: Vec<usize>,
+        writes: Vec<(usize, usize)>,
+    },
+    /// Tells an owner what became of a transaction it prepared
+    Decide { id: TxnId, decision: Decision },
+    /// Asks the coordinator of a transaction what it decided
+    Status { id: TxnId },
+    /// Asks the certifier to commit a snapshot isolated transaction's writes
+    Certify { writes: Vec<mvcc::Write> },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseBody {
+    #[serde(rename = "txn_ok")]
+    TransactionOk {
+        txn: Vec<Operation>,
+    },
+    /// The values of the prepared keys, `null` for those never written
+    PrepareOk {
+        values: Vec<(usize, Option<usize>)>,
+    },
+    /// The owner finished the transaction it was told the decision for
+    DecideOk,
+    StatusOk {
+        decision: Decision,
+    },
+    /// The versions the certifier installed
+    CertifyOk {
+        versions: Vec<(usize, Versioned)>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Read { key: usize, value: Option<usize> },
+    Write { key: usize, value: usize },
+}
+
+impl Serialize for Operation {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(3))?;
+        match self {
+            Operation::Read { key, value } => {
+                seq.serialize_element("r")?;
+                seq.serialize_element(key)?;
+                seq.serialize_element(value)?;
+            }
+            Operation::Write {
+                key: from_key,
+                value: to_key,
+            } => {
+                seq.serialize_element("w")?;
+                seq.serialize_element(from_key)?;
+                seq.serialize_element(to_key)?;
+            }
+        };
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Operation {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct InnerOperation(char, usize, Option<usize>);
+
+        let inner = InnerOperation::deserialize(deserializer)?;
+        match inner.0 {
+            'r' => Ok(Operation::Read {
+                key: inner.1,
+                value: inner.2,
+            }),
+            'w' => Ok(Operation::Write {
+                key: inner.1,
+                value: inner
+                    .2
+                    .ok_or_else(|| de::Error::custom("write without a value"))?,
+            }),
+            x => Err(de::Error::custom(format!(
+                "found unexpected operation type {}",
+                x
+            ))),
+        }
+    }
+}
+
+/// The isolation a node keeps its transactions at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Isolation {
+    ReadCommitted,
+    Snapshot,
+    Serializable,
+}
+
+impl Isolation {
+    /// Reads `--isolation <read-committed|snapshot|serializable>` from the command line arguments,
+    /// skipping any others. Without it, the node's `default`.
+    pub fn from_args(args: &[String], default: Isolation) -> Result<Self, String> {
+        let Some(i) = args.iter().position(|arg| arg == "--isolation") else {
+            return Ok(default);
+        };
+        match args.get(i + 1).map(String::as_str) {
+            Some("read-committed") => Ok(Isolation::ReadCommitted),
+            Some("snapshot") => Ok(Isolation::Snapshot),
+            Some("serializable") => Ok(Isolation::Serializable),
+            Some(level) => Err(format!("unknown isolation level {}", level)),
+            None => Err(String::from("--isolation needs a value")),
+        }
+    }
+}
+
+/// Runs `ops` against `registers`, in order, filling in the values of its reads: each read sees
+/// the last write to its key before it, in the same transaction or an earlier one. Holding
+/// `registers` for the whole call is what makes the transaction atomic.
+pub fn execute(registers: &mut HashMap<usize, usize>, ops: &mut [Operation]) {
+    for op in ops.iter_mut() {
+        match op {
+            Operation::Read { key, value } => *value = registers.get(key).copied(),
+            Operation::Write { key, value } => {
+                registers.insert(*key, *value);
+            }
+        }
+    }
+}
+
+/// A Lamport timestamp, with the id of the node that took it to break ties. Every transaction
+/// takes one, so they're ordered the same way on every node.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub u64, pub String);
+
+/// The value of a register, with the timestamp of the transaction that wrote it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Versioned {
+    pub value: usize,
+    pub version: Timestamp,
+}
+
+/// Registers that converge by last-writer-wins: merging keeps the value with the latest
+/// timestamp, whatever order the writes arrive in.
+#[derive(Clone, Debug, Default)]
+pub struct Registers {
+    /// The highest counter of any timestamp seen so far
+    clock: u64,
+    values: HashMap<usize, Versioned>,
+}
+
+impl Registers {
+    /// Runs `ops` as a transaction of node `node_id`, filling in the values of its reads. Reads
+    /// see the writes of the transaction before them, and the registers only see the writes, with
+    /// the last value of each key, once all ops ran. Returns those writes.
+    pub fn execute(&mut self, node_id: &str, ops: &mut [Operation]) -> HashMap<usize, Versioned> {
+        let mut writes: HashMap<usize, usize> = HashMap::new();
+        for op in ops.iter_mut() {
+            match op {
+                Operation::Read { key, value } => {
+                    *value = match writes.get(key) {
+                        Some(written) => Some(*written),
+                        None => self.values.get(key).map(|v| v.value),
+                    };
+                }
+                Operation::Write { key, value } => {
+                    writes.insert(*key, *value);
+                }
+            }
+        }
+        self.clock += 1;
+        let version = Timestamp(self.clock, node_id.to_string());
+        let writes: HashMap<usize, Versioned> = writes
+            .into_iter()
+            .map(|(key, value)| {
+                let version = version.clone();
+                (key, Versioned { value, version })
+            })
+            .collect();
+        self.merge(writes.clone());
+        writes
+    }
+
+    /// Merges `values` in, keeping the latest of each key
+    pub fn merge(&mut self, values: impl IntoIterator<Item = (usize, Versioned)>) {
+        for (key, theirs) in values {
+            self.clock = self.clock.max(theirs.version.0);
+            match self.values.get(&key) {
+                Some(ours) if ours.version >= theirs.version => {}
+                _ => {
+                    self.values.insert(key, theirs);
+                }
+            }
+        }
+    }
+
+    pub fn values(&self) -> &HashMap<usize, Versioned> {
+        &self.values
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_operation() {
+        let read_resp = Operation::Read {
+            key: 15,
+            value: Some(10),
+        };
+        assert_eq!(r#"["r",15,10]"#, serde_json::to_string(&read_resp).unwrap());
+        let read_req = Operation::Read {
+            key: 15,
+            value: None,
+        };
+        assert_eq!(
+            r#"["r",15,null]"#,
+            serde_json::to_string(&read_req).unwrap()
+        );
+        let write = Operation::Write { key: 7, value: 12 };
+        assert_eq!(r#"["w",7,12]"#, serde_json::to_string(&write).unwrap());
+    }
+
+    #[test]
+    fn deserialize_operation() {
+        let read_resp = Operation::Read {
+            key: 15,
+            value: Some(10),
+        };
+        let raw = r#"["r",15,10]"#;
+        assert_eq!(read_resp, serde_json::from_str::<Operation>(raw).unwrap());
+        let read_req = Operation::Read {
+            key: 15,
+            value: None,
+        };
+        let raw = r#"["r",15,null]"#;
+        assert_eq!(read_req, serde_json::from_str::<Operation>(raw).unwrap());
+        let write = Operation::Write { key: 7, value: 12 };
+        let raw = r#"["w",7,12]"#;
+        assert_eq!(write, serde_json::from_str::<Operation>(raw).unwrap());
+        let raw = r#"["w",7,null]"#;
+        assert!(serde_json::from_str::<Operation>(raw).is_err());
+    }
+
+    fn read(key: usize) -> Operation {
+        Operation::Read { key, value: None }
+    }
+
+    fn write(key: usize, value: usize) -> Operation {
+        Operation::Write { key, value }
+    }
+
+    #[test]
+    fn transactions_read_their_own_writes() {
+        let mut registers = HashMap::new();
+        let mut ops = vec![read(1), write(1, 10), read(1), read(2)];
+        execute(&mut registers, &mut ops);
+        let expected = vec![
+            read(1),
+            write(1, 10),
+            Operation::Read {
+                key: 1,
+                value: Some(10),
+            },
+            read(2),
+        ];
+        assert_eq!(expected, ops);
+    }
+
+    #[test]
+    fn later_writes_overwrite_earlier_ones() {
+        let mut registers = HashMap::new();
+        let mut ops = vec![write(1, 10), write(1, 11), read(1)];
+        execute(&mut registers, &mut ops);
+        assert_eq!(
+            Operation::Read {
+                key: 1,
+                value: Some(11)
+            },
+            ops[2]
+        );
+        // Across transactions too
+        execute(&mut registers, &mut [write(1, 12)]);
+        let mut ops = vec![read(1)];
+        execute(&mut registers, &mut ops);
+        assert_eq!(
+            Operation::Read {
+                key: 1,
+                value: Some(12)
+            },
+            ops[0]
+        );
+    }
+
+    #[test]
+    fn isolation_from_args() {
+        let args = ["--isolation", "snapshot"].map(String::from);
+        assert_eq!(
+            Ok(Isolation::Snapshot),
+            Isolation::from_args(&args, Isolation::ReadCommitted)
+        );
+        assert_eq!(
+            Ok(Isolation::Serializable),
+            Isolation::from_args(&[], Isolation::Serializable)
+        );
+        let args = ["--isolation", "strict"].map(String::from);
+        assert!(Isolation::from_args(&args, Isolation::Serializable).is_err());
+        let args = [String::from("--isolation")];
+        assert!(Isolation::from_args(&args, Isolation::Serializable).is_err());
+    }
+
+    #[test]
+    fn serialize_gossip() {
+        let versioned = Versioned {
+            value: 2,
+            version: Timestamp(1, String::from("n0")),
+        };
+        let gossip = RequestBody::Gossip {
+            registers: vec![(1, versioned)],
+        };
+        let raw = r#"{"type":"gossip","registers":[[1,{"value":2,"version":[1,"n0"]}]]}"#;
+        assert_eq!(raw, serde_json::to_string(&gossip).unwrap());
+    }
+
+    #[test]
+    fn registers_converge_on_the_last_writer() {
+        let mut a = Registers::default();
+        let mut b = Registers::default();
+        let mut ops = vec![
+            Operation::Write { key: 1, value: 10 },
+            Operation::Read {
+                key: 1,
+                value: None,
+            },
+            Operation::Write { key: 1, value: 11 },
+        ];
+        let from_a = a.execute("n0", &mut ops);
+        assert_eq!(
+            Operation::Read {
+                key: 1,
+                value: Some(10)
+            },
+            ops[1]
+        );
+        assert_eq!(11, a.values()[&1].value);
+
+        // b wrote the key concurrently, and then heard of a's write
+        let mut ops = vec![Operation::Write { key: 1, value: 20 }];
+        let from_b = b.execute("n1", &mut ops);
+        b.merge(from_a);
+        a.merge(from_b);
+        assert_eq!(a.values(), b.values());
+        // Same counter, so the node id decides
+        assert_eq!(20, a.values()[&1].value);
+
+        // Having seen b's write, a's next one wins over it
+        let mut ops = vec![Operation::Write { key: 1, value: 12 }];
+        let from_a = a.execute("n0", &mut ops);
+        b.merge(from_a);
+        assert_eq!(12, b.values()[&1].value);
+        let mut ops = vec![Operation::Read {
+            key: 2,
+            value: None,
+        }];
+        a.execute("n0", &mut ops);
+        assert_eq!(
+            Operation::Read {
+                key: 2,
+                value: None
+            },
+            ops[0]
+        );
+    }
+}
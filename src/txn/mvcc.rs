@@ -0,0 +1,441 @@
+//! A multi-version register store, for snapshot isolation.
+//!
+//! Every version a node learns of, whether committed there or gossiped from another node, is
+//! installed with the next number of a local sequence. A transaction's snapshot is the sequence
+//! number when it began: it reads the latest of the versions installed up to there, so later
+//! installs never change what it sees. On commit, a key it wrote whose latest version isn't the
+//! one its snapshot saw means someone else committed first, and it's rejected with txn-conflict.
+//!
+//! Across nodes, the first node of the cluster certifies every commit with writes: the others
+//! send it what their transaction writes along with the versions it saw, and it runs the same
+//! check against its own versions. Since it installs what it commits right away, two
+//! transactions writing the same key can't both get through, wherever they ran. Gossip spreads
+//! the versions from there. A node that can't reach the certifier only commits transactions that
+//! write nothing.
+//!
+//! Versions only a finished snapshot could read are dropped: for each key, that's all but the
+//! latest one the oldest snapshot still in use sees.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use maelstrom::{Result, Runtime};
+use tokio_context::context::Context;
+
+use super::{Operation, RequestBody, ResponseBody, Timestamp, Versioned};
+use crate::error::{self, ErrorCode};
+
+/// How long a node waits for the certifier to answer about a commit
+pub const CALL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A write of a transaction to commit: the key, the value and the version of the key the
+/// transaction's snapshot saw, `None` if it was never written by then
+pub type Write = (usize, usize, Option<Timestamp>);
+
+/// Keeps the versions of every register that some snapshot can still read
+#[derive(Debug, Default)]
+pub struct Mvcc {
+    /// The highest counter of any timestamp seen so far
+    clock: u64,
+    /// How many versions were installed so far
+    installed: u64,
+    /// The snapshots in use, with how many transactions use each
+    live: BTreeMap<u64, usize>,
+    /// The versions of each key, oldest first. A version is only installed if it's newer than
+    /// the latest of its key, so the last one is the latest.
+    versions: HashMap<usize, Vec<Installed>>,
+    /// The keys with more than one version, which may be dropped once the oldest snapshot ends
+    superseded: HashSet<usize>,
+}
+
+#[derive(Debug)]
+struct Installed {
+    versioned: Versioned,
+    /// The value of `installed` once it was
+    at: u64,
+}
+
+/// What a transaction sees: every version installed up to `at`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    at: u64,
+}
+
+impl Mvcc {
+    /// Takes a snapshot of what's installed now, which keeps the versions it sees until
+    /// [`Mvcc::end`]
+    pub fn begin(&mut self) -> Snapshot {
+        let at = self.installed;
+        *self.live.entry(at).or_default() += 1;
+        Snapshot { at }
+    }
+
+    /// Lets go of `snapshot`, dropping the versions nobody can read any more
+    pub fn end(&mut self, snapshot: Snapshot) {
+        let Some(users) = self.live.get_mut(&snapshot.at) else {
+            return;
+        };
+        *users -= 1;
+        if *users > 0 {
+            return;
+        }
+        self.live.remove(&snapshot.at);
+        // Only the oldest snapshot holds versions back
+        if self
+            .live
+            .keys()
+            .next()
+            .is_none_or(|oldest| *oldest > snapshot.at)
+        {
+            for key in self.superseded.clone() {
+                self.prune(key);
+            }
+        }
+    }
+
+    /// The value of `key` as of `snapshot`, if it was written by then
+    pub fn read(&self, snapshot: Snapshot, key: usize) -> Option<usize> {
+        Some(self.visible(snapshot, key)?.value)
+    }
+
+    /// Commits `writes` of a transaction that began at `snapshot` as one of node `node_id`,
+    /// unless another one wrote any of the keys since. Returns the versions it installed.
+    pub fn commit(
+        &mut self,
+        node_id: &str,
+        snapshot: Snapshot,
+        writes: HashMap<usize, usize>,
+    ) -> std::result::Result<Vec<(usize, Versioned)>, error::Error> {
+        let writes: Vec<Write> = writes
+            .into_iter()
+            .map(|(key, value)| (key, value, self.seen(snapshot, key)))
+            .collect();
+        self.certify(node_id, writes)
+    }
+
+    /// Commits `writes` as a transaction of node `node_id`, unless the latest version of any of
+    /// the keys isn't the one the transaction saw. Returns the versions it installed.
+    pub fn certify(
+        &mut self,
+        node_id: &str,
+        writes: Vec<Write>,
+    ) -> std::result::Result<Vec<(usize, Versioned)>, error::Error> {
+        if writes.is_empty() {
+            return Ok(Vec::new());
+        }
+        for (key, _, seen) in &writes {
+            let latest = self.latest_of(*key).map(|latest| &latest.version);
+            if latest != seen.as_ref() {
+                return Err(error::Error::new(
+                    ErrorCode::TxnConflict,
+                    format!("key {} was written since the transaction began", key),
+                ));
+            }
+        }
+        self.clock += 1;
+        let version = Timestamp(self.clock, node_id.to_string());
+        let writes: Vec<(usize, Versioned)> = writes
+            .into_iter()
+            .map(|(key, value, _)| {
+                let version = version.clone();
+                (key, Versioned { value, version })
+            })
+            .collect();
+        self.install(writes.clone());
+        Ok(writes)
+    }
+
+    /// Installs the versions in `values` that are newer than what we have, as if committed now
+    pub fn merge(&mut self, values: impl IntoIterator<Item = (usize, Versioned)>) {
+        let newer: Vec<(usize, Versioned)> = values
+            .into_iter()
+            .filter(|(key, theirs)| {
+                self.latest_of(*key)
+                    .is_none_or(|ours| ours.version < theirs.version)
+            })
+            .collect();
+        if !newer.is_empty() {
+            self.install(newer);
+        }
+    }
+
+    /// The latest version of every key, for gossip
+    pub fn latest(&self) -> Vec<(usize, Versioned)> {
+        self.versions
+            .keys()
+            .filter_map(|key| Some((*key, self.latest_of(*key)?.clone())))
+            .collect()
+    }
+
+    fn latest_of(&self, key: usize) -> Option<&Versioned> {
+        Some(&self.versions.get(&key)?.last()?.versioned)
+    }
+
+    /// The version of `key` that `snapshot` sees
+    fn visible(&self, snapshot: Snapshot, key: usize) -> Option<&Versioned> {
+        self.versions
+            .get(&key)?
+            .iter()
+            .rev()
+            .find(|installed| installed.at <= snapshot.at)
+            .map(|installed| &installed.versioned)
+    }
+
+    fn seen(&self, snapshot: Snapshot, key: usize) -> Option<Timestamp> {
+        Some(self.visible(snapshot, key)?.version.clone())
+    }
+
+    /// Installs `values` together, as one step of the sequence
+    fn install(&mut self, values: Vec<(usize, Versioned)>) {
+        self.installed += 1;
+        for (key, versioned) in values {
+            self.clock = self.clock.max(versioned.version.0);
+            let installed = Installed {
+                versioned,
+                at: self.installed,
+            };
+            self.versions.entry(key).or_default().push(installed);
+            self.prune(key);
+        }
+    }
+
+    /// Drops the versions of `key` older than the one the oldest snapshot in use sees, or than
+    /// the latest if none is in use
+    fn prune(&mut self, key: usize) {
+        let oldest = self.live.keys().next().copied().unwrap_or(self.installed);
+        let Some(versions) = self.versions.get_mut(&key) else {
+            return;
+        };
+        if let Some(seen) = versions
+            .iter()
+            .rposition(|installed| installed.at <= oldest)
+        {
+            versions.drain(..seen);
+        }
+        if versions.len() > 1 {
+            self.superseded.insert(key);
+        } else {
+            self.superseded.remove(&key);
+        }
+    }
+}
+
+/// Runs `ops` as a snapshot isolated transaction, filling in the values of its reads. `mvcc` is
+/// only locked to begin and for each read, so other transactions run in between. Returns its
+/// writes, to [`Mvcc::certify`].
+pub fn run(mvcc: &Mutex<Mvcc>, ops: &mut [Operation]) -> Vec<Write> {
+    let snapshot = mvcc.lock().unwrap().begin();
+    let mut writes: HashMap<usize, usize> = HashMap::new();
+    for op in ops.iter_mut() {
+        match op {
+            Operation::Read { key, value } => {
+                *value = match writes.get(key) {
+                    Some(written) => Some(*written),
+                    None => mvcc.lock().unwrap().read(snapshot, *key),
+                };
+            }
+            Operation::Write { key, value } => {
+                writes.insert(*key, *value);
+            }
+        }
+    }
+    let mut mvcc = mvcc.lock().unwrap();
+    let writes = writes
+        .into_iter()
+        .map(|(key, value)| (key, value, mvcc.seen(snapshot, key)))
+        .collect();
+    mvcc.end(snapshot);
+    writes
+}
+
+/// [`run`]s `ops` and has the certifier, the first node of the cluster, commit its writes.
+/// Returns the versions it installed.
+pub async fn execute(
+    mvcc: &Mutex<Mvcc>,
+    runtime: &Runtime,
+    ops: &mut [Operation],
+) -> Result<Vec<(usize, Versioned)>> {
+    let writes = run(mvcc, ops);
+    if writes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let certifier = runtime
+        .nodes()
+        .first()
+        .ok_or_else(|| error::Error::crash("no node certifies commits before init"))?;
+    if certifier == runtime.node_id() {
+        return Ok(mvcc.lock().unwrap().certify(runtime.node_id(), writes)?);
+    }
+    // The handle cancels the call when dropped, so it has to outlive it
+    let (ctx, _handle) = Context::with_timeout(CALL_TIMEOUT);
+    let reply = match runtime
+        .call(ctx, certifier.clone(), RequestBody::Certify { writes })
+        .await
+    {
+        Ok(reply) => reply,
+        Err(e) if error::failed_with(e.as_ref(), ErrorCode::Timeout) => {
+            let text = format!(
+                "{} didn't answer, the transaction may have committed",
+                certifier
+            );
+            return Err(error::Error::crash(text).into());
+        }
+        Err(e) => return Err(e),
+    };
+    match reply.body.as_obj()? {
+        ResponseBody::CertifyOk { versions } => {
+            mvcc.lock().unwrap().merge(versions.clone());
+            Ok(versions)
+        }
+        other => {
+            let text = format!(
+                "unexpected reply to certify from {}: {:?}",
+                certifier, other
+            );
+            Err(error::Error::crash(text).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn writes(writes: &[(usize, usize)]) -> HashMap<usize, usize> {
+        writes.iter().copied().collect()
+    }
+
+    /// Commits `values` in a transaction of its own
+    fn commit(
+        mvcc: &mut Mvcc,
+        node_id: &str,
+        values: &[(usize, usize)],
+    ) -> Vec<(usize, Versioned)> {
+        let snapshot = mvcc.begin();
+        let installed = mvcc.commit(node_id, snapshot, writes(values));
+        mvcc.end(snapshot);
+        installed.unwrap()
+    }
+
+    fn read_now(mvcc: &mut Mvcc, key: usize) -> Option<usize> {
+        let snapshot = mvcc.begin();
+        let value = mvcc.read(snapshot, key);
+        mvcc.end(snapshot);
+        value
+    }
+
+    #[test]
+    fn snapshots_dont_see_later_commits() {
+        let mut mvcc = Mvcc::default();
+        let before = mvcc.begin();
+        mvcc.commit("n0", before, writes(&[(1, 10), (2, 20)]))
+            .unwrap();
+        let snapshot = mvcc.begin();
+        commit(&mut mvcc, "n0", &[(1, 11)]);
+        assert_eq!(None, mvcc.read(before, 1));
+        assert_eq!(Some(10), mvcc.read(snapshot, 1));
+        assert_eq!(Some(20), mvcc.read(snapshot, 2));
+        assert_eq!(Some(11), read_now(&mut mvcc, 1));
+    }
+
+    #[test]
+    fn first_committer_wins() {
+        let mut mvcc = Mvcc::default();
+        let first = mvcc.begin();
+        let second = mvcc.begin();
+        mvcc.commit("n0", first, writes(&[(1, 10)])).unwrap();
+        let err = mvcc.commit("n0", second, writes(&[(1, 11), (2, 21)]));
+        assert_eq!(ErrorCode::TxnConflict, err.unwrap_err().code);
+        assert_eq!(None, read_now(&mut mvcc, 2));
+        // Disjoint writes don't conflict, and neither do reads
+        mvcc.commit("n0", second, writes(&[(2, 22)])).unwrap();
+        mvcc.commit("n0", second, writes(&[])).unwrap();
+    }
+
+    #[test]
+    fn the_certifier_rejects_writes_over_versions_it_has_replaced() {
+        let mut certifier = Mvcc::default();
+        let first = certifier.certify("n0", vec![(1, 10, None)]).unwrap();
+        let seen = Some(first[0].1.version.clone());
+        // Another node's txn that began before hearing of it
+        let err = certifier.certify("n0", vec![(1, 11, None)]);
+        assert_eq!(ErrorCode::TxnConflict, err.unwrap_err().code);
+        certifier
+            .certify("n0", vec![(1, 12, seen.clone())])
+            .unwrap();
+        let err = certifier.certify("n0", vec![(1, 13, seen)]);
+        assert_eq!(ErrorCode::TxnConflict, err.unwrap_err().code);
+        assert_eq!(Some(12), read_now(&mut certifier, 1));
+    }
+
+    #[test]
+    fn gossiped_versions_are_installed_like_commits() {
+        let mut a = Mvcc::default();
+        let mut b = Mvcc::default();
+        let snapshot = b.begin();
+        let from_a = commit(&mut a, "n0", &[(1, 10)]);
+        b.merge(from_a.clone());
+        assert_eq!(None, b.read(snapshot, 1));
+        assert_eq!(Some(10), read_now(&mut b, 1));
+        // b's txn that began before hearing of it conflicts with it
+        let err = b.commit("n1", snapshot, writes(&[(1, 11)]));
+        assert_eq!(ErrorCode::TxnConflict, err.unwrap_err().code);
+        // Hearing of it again changes nothing
+        let installed = b.installed;
+        b.merge(from_a);
+        assert_eq!(installed, b.installed);
+
+        // Writes committed on both sides settle on the last writer
+        let from_a = commit(&mut a, "n0", &[(2, 20)]);
+        let from_b = commit(&mut b, "n1", &[(2, 21)]);
+        a.merge(from_b);
+        b.merge(from_a);
+        assert_eq!(read_now(&mut a, 2), read_now(&mut b, 2));
+    }
+
+    #[test]
+    fn versions_are_kept_only_while_a_snapshot_sees_them() {
+        let mut mvcc = Mvcc::default();
+        commit(&mut mvcc, "n0", &[(1, 10)]);
+        commit(&mut mvcc, "n0", &[(1, 11)]);
+        assert_eq!(1, mvcc.versions[&1].len());
+
+        let old = mvcc.begin();
+        commit(&mut mvcc, "n0", &[(1, 12)]);
+        let newer = mvcc.begin();
+        commit(&mut mvcc, "n0", &[(1, 13)]);
+        commit(&mut mvcc, "n0", &[(1, 14)]);
+        assert_eq!(4, mvcc.versions[&1].len());
+        assert_eq!(Some(11), mvcc.read(old, 1));
+
+        // Ending the newer one first frees nothing, the old one still sees 11
+        mvcc.end(newer);
+        assert_eq!(4, mvcc.versions[&1].len());
+        mvcc.end(old);
+        assert_eq!(1, mvcc.versions[&1].len());
+        assert_eq!(Some(14), read_now(&mut mvcc, 1));
+        assert!(mvcc.live.is_empty() && mvcc.superseded.is_empty());
+    }
+
+    #[test]
+    fn transactions_read_their_own_writes() {
+        let mvcc = Mutex::new(Mvcc::default());
+        let mut ops = vec![
+            Operation::Write { key: 1, value: 10 },
+            Operation::Read {
+                key: 1,
+                value: None,
+            },
+        ];
+        let writes = run(&mvcc, &mut ops);
+        assert_eq!(
+            Operation::Read {
+                key: 1,
+                value: Some(10)
+            },
+            ops[1]
+        );
+        assert_eq!(vec![(1, 10, None)], writes);
+    }
+}
@@ -0,0 +1,679 @@
+//! Two-phase commit over registers partitioned across nodes, for serializable transactions.
+//!
+//! Each key belongs to one node, picked with [`owner_of`], which is the only one to keep its
+//! value. The node a client sends a transaction to coordinates it: it asks the owner of every key
+//! the transaction touches to prepare, which locks the keys and hands back their values, fills in
+//! the reads, and then tells the owners to commit the writes, or to abort if any of them couldn't
+//! prepare. Every key stays locked from prepare to decision, so transactions are strictly
+//! serializable. An owner fails a prepare that finds a key locked instead of waiting for it, so
+//! there are no deadlocks, and the client gets txn-conflict.
+//!
+//! The coordinator records its decision before telling the owners, and keeps it until they all
+//! acknowledged it, telling the ones that didn't again. An owner that hasn't heard the decision
+//! [`IN_DOUBT_AFTER`] preparing, because the coordinator or the message got stuck behind a
+//! partition, asks the coordinator for it. A coordinator asked before deciding aborts, so that
+//! owners are never left holding locks for a decision that might never come. Owners keep the
+//! decisions they hear of for [`FORGET_AFTER`], so that a prepare arriving late, or twice, for a
+//! finished transaction doesn't lock its keys again.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use maelstrom::{Result, Runtime};
+use serde::{Deserialize, Serialize};
+use tokio_context::context::Context;
+
+use super::{Operation, RequestBody, ResponseBody};
+use crate::error::{self, ErrorCode};
+
+/// How long a coordinator waits for an owner to prepare, or to answer about a decision
+pub const CALL_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long an owner holds a prepared transaction's locks before asking the coordinator about it
+pub const IN_DOUBT_AFTER: Duration = Duration::from_secs(1);
+/// How long an owner remembers what became of a transaction. Well over [`IN_DOUBT_AFTER`] plus
+/// the longest Maelstrom takes to deliver a message, so that no prepare for it is still on its
+/// way. One that is anyway locks its keys until the owner asks about it, and the coordinator,
+/// which forgot it too, has it aborted.
+pub const FORGET_AFTER: Duration = Duration::from_secs(10);
+
+/// A transaction, numbered by the node that coordinates it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TxnId(pub u64, pub String);
+
+impl TxnId {
+    pub fn coordinator(&self) -> &str {
+        &self.1
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+/// The node that owns `key` among `node_ids`, or `None` if there are none
+pub fn owner_of(key: usize, node_ids: &[String]) -> Option<&String> {
+    if node_ids.is_empty() {
+        return None;
+    }
+    node_ids.get(key % node_ids.len())
+}
+
+/// The keys a node owns, with the locks of the transactions prepared on them
+#[derive(Debug, Default)]
+pub struct Partition {
+    values: HashMap<usize, usize>,
+    locks: HashMap<usize, TxnId>,
+    prepared: HashMap<TxnId, Prepared>,
+    /// What became of the transactions decided here, and when they finished. Dropped after
+    /// [`FORGET_AFTER`].
+    finished: HashMap<TxnId, (Decision, Instant)>,
+}
+
+#[derive(Debug)]
+struct Prepared {
+    keys: Vec<usize>,
+    writes: Vec<(usize, usize)>,
+    since: Instant,
+}
+
+impl Partition {
+    /// Locks `keys`, which include every key in `writes`, for transaction `id` and returns their
+    /// values, `None` for keys never written. Fails with txn-conflict, locking nothing, if another
+    /// transaction holds any of them.
+    ///
+    /// A transaction already finished here locks nothing: if it committed, the values are the
+    /// ones now, and if it aborted, the prepare fails with txn-conflict.
+    pub fn prepare(
+        &mut self,
+        id: &TxnId,
+        keys: Vec<usize>,
+        writes: Vec<(usize, usize)>,
+        now: Instant,
+    ) -> std::result::Result<Vec<(usize, Option<usize>)>, error::Error> {
+        match self.finished.get(id) {
+            None => {}
+            Some((Decision::Commit, _)) => return Ok(self.values_of(&keys)),
+            Some((Decision::Abort, _)) => {
+                return Err(error::Error::new(
+                    ErrorCode::TxnConflict,
+                    "the transaction was aborted",
+                ))
+            }
+        }
+        let locked = keys
+            .iter()
+            .find(|key| self.locks.get(key).is_some_and(|holder| holder != id));
+        if let Some(key) = locked {
+            return Err(error::Error::new(
+                ErrorCode::TxnConflict,
+                format!("key {} is locked by another transaction", key),
+            ));
+        }
+        for key in &keys {
+            self.locks.insert(*key, id.clone());
+        }
+        let values = self.values_of(&keys);
+        let prepared = Prepared {
+            keys,
+            writes,
+            since: now,
+        };
+        self.prepared.insert(id.clone(), prepared);
+        Ok(values)
+    }
+
+    /// Applies the writes of transaction `id` if `decision` is to commit, and releases its locks.
+    /// Only records the decision if it isn't prepared here, or not anymore.
+    pub fn finish(&mut self, id: &TxnId, decision: Decision, now: Instant) {
+        self.finished.entry(id.clone()).or_insert((decision, now));
+        let Some(prepared) = self.prepared.remove(id) else {
+            return;
+        };
+        if decision == Decision::Commit {
+            self.values.extend(prepared.writes);
+        }
+        for key in prepared.keys {
+            self.locks.remove(&key);
+        }
+    }
+
+    fn values_of(&self, keys: &[usize]) -> Vec<(usize, Option<usize>)> {
+        keys.iter()
+            .map(|key| (*key, self.values.get(key).copied()))
+            .collect()
+    }
+
+    /// Forgets the transactions that finished here more than [`FORGET_AFTER`] before `now`
+    pub fn forget(&mut self, now: Instant) {
+        self.finished
+            .retain(|_, (_, at)| now.duration_since(*at) <= FORGET_AFTER);
+    }
+
+    /// The transactions that prepared here more than `timeout` before `now`, still undecided
+    pub fn in_doubt(&self, timeout: Duration, now: Instant) -> Vec<TxnId> {
+        self.prepared
+            .iter()
+            .filter(|(_, prepared)| now.duration_since(prepared.since) > timeout)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
+
+/// The decisions of the transactions a node coordinates. Each is kept until every owner that
+/// was told acknowledged it, so that an owner can always find out. Owners cut off by a partition
+/// hold on to theirs until it heals.
+#[derive(Debug, Default)]
+pub struct Decisions {
+    next: u64,
+    /// Begun, and not decided by their coordinator yet
+    undecided: HashSet<TxnId>,
+    decided: HashMap<TxnId, Decided>,
+}
+
+#[derive(Debug)]
+struct Decided {
+    decision: Decision,
+    /// The owners that were told and haven't acknowledged it yet
+    unacknowledged: BTreeSet<String>,
+    /// When to tell them again
+    retry_at: Instant,
+}
+
+impl Decisions {
+    pub fn begin(&mut self, node_id: &str) -> TxnId {
+        self.next += 1;
+        let id = TxnId(self.next, node_id.to_string());
+        self.undecided.insert(id.clone());
+        id
+    }
+
+    /// Decides `decision` for transaction `id`, which this node began, unless an owner asking
+    /// about it had it aborted already. Returns the decision that stands, to tell `owners`.
+    pub fn decide(
+        &mut self,
+        id: TxnId,
+        decision: Decision,
+        owners: impl IntoIterator<Item = String>,
+        now: Instant,
+    ) -> Decision {
+        self.undecided.remove(&id);
+        let decided = self.decided.entry(id.clone()).or_insert(Decided {
+            decision,
+            unacknowledged: BTreeSet::new(),
+            retry_at: now + CALL_TIMEOUT,
+        });
+        decided.unacknowledged.extend(owners);
+        let decision = decided.decision;
+        self.forget_if_acknowledged(&id);
+        decision
+    }
+
+    /// The decision for transaction `id` that `owner` asked about. If it isn't decided yet, it's
+    /// aborted. If it was forgotten, every owner acknowledged it already, and `owner` is asking
+    /// about a prepare that came too late, which it can abort too.
+    pub fn status(&mut self, id: TxnId, owner: &str, now: Instant) -> Decision {
+        if !self.decided.contains_key(&id) && !self.undecided.contains(&id) {
+            return Decision::Abort;
+        }
+        let decided = self.decided.entry(id).or_insert(Decided {
+            decision: Decision::Abort,
+            unacknowledged: BTreeSet::new(),
+            retry_at: now + CALL_TIMEOUT,
+        });
+        // The answer may not make it, so it's told again until it acknowledges
+        decided.unacknowledged.insert(owner.to_string());
+        decided.decision
+    }
+
+    /// Records that `owner` acknowledged the decision for transaction `id`
+    pub fn acknowledge(&mut self, id: &TxnId, owner: &str) {
+        if let Some(decided) = self.decided.get_mut(id) {
+            decided.unacknowledged.remove(owner);
+        }
+        self.forget_if_acknowledged(id);
+    }
+
+    /// The decisions to tell the owners that haven't acknowledged them again, the ones last told
+    /// more than [`CALL_TIMEOUT`] before `now`
+    pub fn unacknowledged(&mut self, now: Instant) -> Vec<(TxnId, String, Decision)> {
+        let mut retries = Vec::new();
+        for (id, decided) in &mut self.decided {
+            if decided.retry_at > now {
+                continue;
+            }
+            decided.retry_at = now + CALL_TIMEOUT;
+            for owner in &decided.unacknowledged {
+                retries.push((id.clone(), owner.clone(), decided.decision));
+            }
+        }
+        retries
+    }
+
+    fn forget_if_acknowledged(&mut self, id: &TxnId) {
+        let acknowledged = self
+            .decided
+            .get(id)
+            .is_some_and(|decided| decided.unacknowledged.is_empty());
+        if acknowledged && !self.undecided.contains(id) {
+            self.decided.remove(id);
+        }
+    }
+}
+
+/// A node's part in two-phase commit: owner of some of the keys, and coordinator of the
+/// transactions its clients send
+#[derive(Debug, Default)]
+pub struct TwoPhase {
+    partition: Mutex<Partition>,
+    /// Shared with the tasks telling owners the decisions
+    decisions: Arc<Mutex<Decisions>>,
+}
+
+impl TwoPhase {
+    /// Runs `ops` as a transaction coordinated by this node, filling in the values of its reads.
+    /// Fails, having written nothing, if an owner couldn't prepare.
+    pub async fn execute(&self, runtime: &Runtime, ops: &mut [Operation]) -> Result<()> {
+        // The keys to lock on each owner, and the last value written to each key
+        let mut keys: BTreeMap<String, BTreeSet<usize>> = BTreeMap::new();
+        let mut writes: HashMap<usize, usize> = HashMap::new();
+        for op in ops.iter() {
+            let key = match op {
+                Operation::Read { key, .. } => *key,
+                Operation::Write { key, value } => {
+                    writes.insert(*key, *value);
+                    *key
+                }
+            };
+            let owner = owner_of(key, runtime.nodes())
+                .ok_or_else(|| error::Error::crash("no node owns any key before init"))?;
+            keys.entry(owner.clone()).or_default().insert(key);
+        }
+        let id = self.decisions.lock().unwrap().begin(runtime.node_id());
+
+        // Every owner prepares at once, so that however many of them are unreachable, the
+        // transaction is decided within CALL_TIMEOUT, well before they take it for in doubt
+        let mut local = None;
+        let mut prepares = Vec::new();
+        for (owner, keys) in keys {
+            let owned_writes = keys
+                .iter()
+                .filter_map(|key| Some((*key, *writes.get(key)?)))
+                .collect();
+            let keys = keys.into_iter().collect();
+            if owner == runtime.node_id() {
+                local = Some((owner, keys, owned_writes));
+                continue;
+            }
+            let prepare = prepare_on(
+                runtime.clone(),
+                id.clone(),
+                owner.clone(),
+                keys,
+                owned_writes,
+            );
+            prepares.push((owner, tokio::spawn(prepare)));
+        }
+        let mut prepared = Vec::new();
+        if let Some((owner, keys, writes)) = local {
+            let result = self.prepare(&id, keys, writes).map_err(Into::into);
+            prepared.push((owner, result));
+        }
+        for (owner, prepare) in prepares {
+            let result = prepare
+                .await
+                .unwrap_or_else(|e| Err(error::Error::crash(e.to_string()).into()));
+            prepared.push((owner, result));
+        }
+
+        let mut values: HashMap<usize, Option<usize>> = HashMap::new();
+        let mut asked = Vec::new();
+        let mut failure = None;
+        for (owner, result) in prepared {
+            asked.push(owner);
+            match result {
+                Ok(owned) => values.extend(owned),
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        let wanted = match failure {
+            None => Decision::Commit,
+            Some(_) => Decision::Abort,
+        };
+        let decision = self.decisions.lock().unwrap().decide(
+            id.clone(),
+            wanted,
+            asked.iter().cloned(),
+            Instant::now(),
+        );
+        debug!("{:?} decided {:?}", id, decision);
+        for owner in asked {
+            self.decide_on(runtime, &id, owner, decision);
+        }
+        if decision == Decision::Abort {
+            return Err(failure.unwrap_or_else(|| {
+                let text = "an owner gave up waiting for the transaction to commit";
+                error::Error::new(ErrorCode::TxnConflict, text).into()
+            }));
+        }
+
+        let mut written: HashMap<usize, usize> = HashMap::new();
+        for op in ops.iter_mut() {
+            match op {
+                Operation::Read { key, value } => {
+                    *value = match written.get(key) {
+                        Some(written) => Some(*written),
+                        None => values.get(key).copied().flatten(),
+                    };
+                }
+                Operation::Write { key, value } => {
+                    written.insert(*key, *value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepares transaction `id` on the keys this node owns, see [`Partition::prepare`]
+    pub fn prepare(
+        &self,
+        id: &TxnId,
+        keys: Vec<usize>,
+        writes: Vec<(usize, usize)>,
+    ) -> std::result::Result<Vec<(usize, Option<usize>)>, error::Error> {
+        let mut partition = self.partition.lock().unwrap();
+        partition.prepare(id, keys, writes, Instant::now())
+    }
+
+    /// Finishes transaction `id` on the keys this node owns, see [`Partition::finish`]
+    pub fn finish(&self, id: &TxnId, decision: Decision) {
+        let mut partition = self.partition.lock().unwrap();
+        partition.finish(id, decision, Instant::now())
+    }
+
+    /// The decision for transaction `id`, which this node coordinates, for `owner`, see
+    /// [`Decisions::status`]
+    pub fn status(&self, id: TxnId, owner: &str) -> Decision {
+        let mut decisions = self.decisions.lock().unwrap();
+        decisions.status(id, owner, Instant::now())
+    }
+
+    /// Asks the coordinators of the transactions in doubt here for their decisions, and finishes
+    /// the ones that answer. The others are asked again next time. Also tells the owners that
+    /// haven't acknowledged the decisions of this node's transactions again, and forgets the
+    /// transactions that finished here long enough ago.
+    pub async fn recover(&self, runtime: &Runtime) {
+        let now = Instant::now();
+        let retries = self.decisions.lock().unwrap().unacknowledged(now);
+        for (id, owner, decision) in retries {
+            self.decide_on(runtime, &id, owner, decision);
+        }
+        let in_doubt = {
+            let mut partition = self.partition.lock().unwrap();
+            partition.forget(now);
+            partition.in_doubt(IN_DOUBT_AFTER, now)
+        };
+        for id in in_doubt {
+            let decision = if id.coordinator() == runtime.node_id() {
+                self.status(id.clone(), runtime.node_id())
+            } else {
+                let status = RequestBody::Status { id: id.clone() };
+                let (ctx, _handle) = Context::with_timeout(CALL_TIMEOUT);
+                match runtime.call(ctx, id.coordinator(), status).await {
+                    Ok(reply) => match reply.body.as_obj() {
+                        Ok(ResponseBody::StatusOk { decision }) => decision,
+                        _ => continue,
+                    },
+                    Err(_) => continue,
+                }
+            };
+            debug!("{:?} was in doubt, {:?}", id, decision);
+            self.finish(&id, decision);
+        }
+    }
+
+    /// Tells `owner` the decision for transaction `id`. If the message is lost, the owner asks,
+    /// and is told again until it acknowledges.
+    fn decide_on(&self, runtime: &Runtime, id: &TxnId, owner: String, decision: Decision) {
+        if owner == runtime.node_id() {
+            self.finish(id, decision);
+            return self.decisions.lock().unwrap().acknowledge(id, &owner);
+        }
+        let decide = decide_on(
+            runtime.clone(),
+            self.decisions.clone(),
+            id.clone(),
+            owner,
+            decision,
+        );
+        tokio::spawn(decide);
+    }
+}
+
+/// Tells `owner`, another node, the decision for transaction `id`, and records it in `decisions`
+/// if it acknowledges
+async fn decide_on(
+    runtime: Runtime,
+    decisions: Arc<Mutex<Decisions>>,
+    id: TxnId,
+    owner: String,
+    decision: Decision,
+) {
+    let decide = RequestBody::Decide {
+        id: id.clone(),
+        decision,
+    };
+    let (ctx, _handle) = Context::with_timeout(CALL_TIMEOUT);
+    match runtime.call(ctx, owner.clone(), decide).await {
+        Ok(reply) => match reply.body.as_obj() {
+            Ok(ResponseBody::DecideOk) => decisions.lock().unwrap().acknowledge(&id, &owner),
+            other => debug!("unexpected reply to decide from {}: {:?}", owner, other),
+        },
+        Err(e) => debug!("{} didn't acknowledge {:?}: {}", owner, id, e),
+    }
+}
+
+/// Asks `owner`, another node, to prepare transaction `id`, see [`Partition::prepare`]
+async fn prepare_on(
+    runtime: Runtime,
+    id: TxnId,
+    owner: String,
+    keys: Vec<usize>,
+    writes: Vec<(usize, usize)>,
+) -> Result<Vec<(usize, Option<usize>)>> {
+    let prepare = RequestBody::Prepare { id, keys, writes };
+    // The handle cancels the call when dropped, so it has to outlive it
+    let (ctx, _handle) = Context::with_timeout(CALL_TIMEOUT);
+    let reply = match runtime.call(ctx, owner.clone(), prepare).await {
+        Ok(reply) => reply,
+        // The transaction gets aborted, so unlike the timeout this is definite
+        Err(e) if error::failed_with(e.as_ref(), ErrorCode::Timeout) => {
+            let text = format!("{} didn't prepare in time", owner);
+            return Err(error::Error::new(ErrorCode::TemporarilyUnavailable, text).into());
+        }
+        Err(e) => return Err(e),
+    };
+    match reply.body.as_obj()? {
+        ResponseBody::PrepareOk { values } => Ok(values),
+        other => {
+            let text = format!("unexpected reply to prepare from {}: {:?}", owner, other);
+            Err(error::Error::crash(text).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(n: u64) -> TxnId {
+        TxnId(n, String::from("n0"))
+    }
+
+    #[test]
+    fn keys_are_spread_over_the_nodes() {
+        let nodes = ["n0", "n1", "n2"].map(String::from);
+        assert_eq!(Some(&nodes[0]), owner_of(3, &nodes));
+        assert_eq!(Some(&nodes[2]), owner_of(5, &nodes));
+        assert_eq!(None, owner_of(5, &[]));
+    }
+
+    #[test]
+    fn prepared_keys_stay_locked_until_decided() {
+        let mut partition = Partition::default();
+        let now = Instant::now();
+        let values = partition.prepare(&id(1), vec![1, 2], vec![(1, 10)], now);
+        assert_eq!(Ok(vec![(1, None), (2, None)]), values);
+        let err = partition.prepare(&id(2), vec![2, 3], vec![], now);
+        assert_eq!(ErrorCode::TxnConflict, err.unwrap_err().code);
+        // The failed prepare didn't lock 3
+        partition.prepare(&id(3), vec![3], vec![], now).unwrap();
+
+        partition.finish(&id(1), Decision::Commit, now);
+        let values = partition.prepare(&id(2), vec![1, 2], vec![], now);
+        assert_eq!(Ok(vec![(1, Some(10)), (2, None)]), values);
+    }
+
+    #[test]
+    fn aborted_writes_are_dropped() {
+        let mut partition = Partition::default();
+        let now = Instant::now();
+        partition
+            .prepare(&id(1), vec![1], vec![(1, 10)], now)
+            .unwrap();
+        partition.finish(&id(1), Decision::Abort, now);
+        // Deciding again changes nothing
+        partition.finish(&id(1), Decision::Commit, now);
+        let values = partition.prepare(&id(2), vec![1], vec![], now);
+        assert_eq!(Ok(vec![(1, None)]), values);
+    }
+
+    #[test]
+    fn finished_transactions_dont_prepare_again() {
+        let mut partition = Partition::default();
+        let now = Instant::now();
+        partition
+            .prepare(&id(1), vec![1], vec![(1, 10)], now)
+            .unwrap();
+        partition.finish(&id(1), Decision::Commit, now);
+        partition
+            .prepare(&id(2), vec![1], vec![(1, 20)], now)
+            .unwrap();
+        partition.finish(&id(2), Decision::Commit, now);
+        // A duplicate prepare of the first locks nothing, and recovery has nothing to redo
+        let values = partition.prepare(&id(1), vec![1], vec![(1, 10)], now);
+        assert_eq!(Ok(vec![(1, Some(20))]), values);
+        let later = now + IN_DOUBT_AFTER * 2;
+        assert!(partition.in_doubt(IN_DOUBT_AFTER, later).is_empty());
+        partition.prepare(&id(3), vec![1], vec![], now).unwrap();
+
+        // Nor does one arriving after the abort it was too late for
+        partition.finish(&id(4), Decision::Abort, now);
+        let err = partition.prepare(&id(4), vec![2], vec![(2, 40)], now);
+        assert_eq!(ErrorCode::TxnConflict, err.unwrap_err().code);
+        partition.prepare(&id(5), vec![2], vec![], now).unwrap();
+    }
+
+    #[test]
+    fn finished_transactions_are_forgotten_after_a_while() {
+        let mut partition = Partition::default();
+        let now = Instant::now();
+        partition.prepare(&id(1), vec![1], vec![], now).unwrap();
+        partition.finish(&id(1), Decision::Commit, now);
+        partition.forget(now + FORGET_AFTER / 2);
+        let later = now + FORGET_AFTER * 2;
+        partition.prepare(&id(1), vec![1], vec![], later).unwrap();
+        assert!(partition.in_doubt(IN_DOUBT_AFTER, later).is_empty());
+
+        partition.forget(later);
+        // Far too late a prepare locks the keys again, until the owner asks about it
+        partition.prepare(&id(1), vec![1], vec![], later).unwrap();
+        let even_later = later + IN_DOUBT_AFTER * 2;
+        assert_eq!(vec![id(1)], partition.in_doubt(IN_DOUBT_AFTER, even_later));
+    }
+
+    #[test]
+    fn undecided_transactions_are_in_doubt_after_a_while() {
+        let mut partition = Partition::default();
+        let now = Instant::now();
+        partition.prepare(&id(1), vec![1], vec![], now).unwrap();
+        partition.prepare(&id(2), vec![2], vec![], now).unwrap();
+        partition.finish(&id(2), Decision::Commit, now);
+        assert!(partition.in_doubt(IN_DOUBT_AFTER, now).is_empty());
+        let later = now + IN_DOUBT_AFTER * 2;
+        assert_eq!(vec![id(1)], partition.in_doubt(IN_DOUBT_AFTER, later));
+    }
+
+    #[test]
+    fn the_first_decision_stands() {
+        let mut decisions = Decisions::default();
+        let now = Instant::now();
+        let first = decisions.begin("n1");
+        let second = decisions.begin("n1");
+        assert_ne!(first, second);
+        assert_eq!("n1", first.coordinator());
+        let owners = || [String::from("n2")];
+        assert_eq!(
+            Decision::Commit,
+            decisions.decide(first.clone(), Decision::Commit, owners(), now)
+        );
+        assert_eq!(
+            Decision::Commit,
+            decisions.decide(first, Decision::Abort, owners(), now)
+        );
+        // An owner asking before the coordinator decided aborts the transaction
+        assert_eq!(Decision::Abort, decisions.status(second.clone(), "n2", now));
+        assert_eq!(
+            Decision::Abort,
+            decisions.decide(second, Decision::Commit, owners(), now)
+        );
+    }
+
+    #[test]
+    fn decisions_are_kept_until_acknowledged() {
+        let mut decisions = Decisions::default();
+        let now = Instant::now();
+        let id = decisions.begin("n1");
+        let owners = ["n1", "n2", "n3"].map(String::from);
+        decisions.decide(id.clone(), Decision::Commit, owners, now);
+        decisions.acknowledge(&id, "n1");
+        assert!(decisions.unacknowledged(now).is_empty());
+        let later = now + CALL_TIMEOUT * 2;
+        let retries = decisions.unacknowledged(later);
+        let told: Vec<&str> = retries.iter().map(|(_, owner, _)| owner.as_str()).collect();
+        assert_eq!(vec!["n2", "n3"], told);
+        // Not again until the retries had time to get an answer
+        assert!(decisions.unacknowledged(later).is_empty());
+
+        decisions.acknowledge(&id, "n2");
+        assert_eq!(Decision::Commit, decisions.status(id.clone(), "n3", later));
+        decisions.acknowledge(&id, "n3");
+        assert!(decisions.decided.is_empty());
+        // Only a prepare that came too late can still be asked about, and it's aborted
+        assert_eq!(Decision::Abort, decisions.status(id, "n3", later));
+        assert!(decisions.decided.is_empty());
+    }
+
+    #[test]
+    fn serialize_prepare() {
+        let prepare = RequestBody::Prepare {
+            id: id(1),
+            keys: vec![1, 2],
+            writes: vec![(1, 10)],
+        };
+        let raw = r#"{"type":"prepare","id":[1,"n0"],"keys":[1,2],"writes":[[1,10]]}"#;
+        assert_eq!(raw, serde_json::to_string(&prepare).unwrap());
+        let decide = RequestBody::Decide {
+            id: id(1),
+            decision: Decision::Commit,
+        };
+        let raw = r#"{"type":"decide","id":[1,"n0"],"decision":"commit"}"#;
+        assert_eq!(raw, serde_json::to_string(&decide).unwrap());
+    }
+}
@@ -0,0 +1,1112 @@
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use gossip_glomers::checker::linearizable::{self, Counter};
+use gossip_glomers::checker::txn::{self, IsolationLevel};
+use gossip_glomers::checker::{kafka, History};
+use gossip_glomers::kv::{Consistency, Kv};
+use gossip_glomers::sim::{reply, Binary, Config, Fault, Latency, Nemesis, Process, Simulation};
+use maelstrom::protocol::Message;
+use maelstrom::Result;
+use serde_json::{json, Value};
+
+fn binary(path: &str) -> Binary {
+    binary_with_args(path, &[])
+}
+
+fn binary_with_args(path: &str, args: &[&str]) -> Binary {
+    let mut command = Command::new(path);
+    command.args(args).stderr(Stdio::null());
+    Binary::spawn(command)
+        .unwrap()
+        .waiting_for_replies(Duration::from_secs(5))
+}
+
+#[test]
+fn echo_binary_in_simulation() {
+    let mut sim = Simulation::new(Config::default());
+    sim.add_node("n0", binary(env!("CARGO_BIN_EXE_echo")));
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    assert_eq!("init_ok", sim.reply_to("c0", 1).unwrap().get_type());
+
+    let reply = sim
+        .call(
+            "c1",
+            "n0",
+            json!({"type": "echo", "echo": "hi"}),
+            Duration::from_secs(1),
+        )
+        .unwrap()
+        .expect("echo should be answered");
+    assert_eq!("echo_ok", reply.typ);
+    assert_eq!(json!("hi"), reply.extra["echo"]);
+}
+
+#[test]
+fn counter_reads_can_be_stale_on_seq_kv() {
+    // Some latency, so that ops don't all happen at the same instant and overlap
+    let mut sim = Simulation::new(Config {
+        latency: Latency::Constant(Duration::from_millis(1)),
+        ..Config::default()
+    });
+    for node in ["n0", "n1"] {
+        sim.add_node(node, binary(env!("CARGO_BIN_EXE_counter")));
+    }
+    sim.add_service(
+        "seq-kv",
+        Kv::new(Consistency::Sequential, 1).with_stale_reads(0.5),
+    );
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let mut reads = Vec::new();
+    for total in 1..=10 {
+        let reply = sim
+            .call(
+                "c1",
+                "n0",
+                json!({"type": "add", "delta": 1}),
+                Duration::from_secs(1),
+            )
+            .unwrap();
+        assert_eq!("add_ok", reply.unwrap().typ);
+        let reply = sim
+            .call("c2", "n1", json!({"type": "read"}), Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        reads.push((total, reply.extra["value"].as_u64().unwrap()));
+    }
+    // n1 never writes after init, so seq-kv is free to hand it old values. They only ever move
+    // forward, though.
+    assert!(reads.iter().any(|(total, read)| read < total));
+    assert!(reads.windows(2).all(|w| w[0].1 <= w[1].1));
+
+    // Which the linearizability checker catches
+    let history = History::from_journal(sim.journal());
+    let ops: Vec<_> = history.ops.iter().collect();
+    assert!(linearizable::check(&Counter, &ops).is_err());
+}
+
+/// For tests whose nodes have to act on their own timers, which run on the real clock: gossip,
+/// recovery, or calls that time out because a partition dropped them. Everything else only acts
+/// on the messages it gets, and runs on the virtual clock alone.
+fn paced() -> Config {
+    Config {
+        pace: true,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn broadcast_tree_recovers_from_partition() {
+    // With 5 nodes pick_neighbours makes n0 the only leader, and n1..n4 only talk to it
+    let nemesis = Nemesis::new()
+        .at(
+            Duration::from_millis(100),
+            Fault::Partition(vec![
+                vec!["n0".to_string()],
+                ["n1", "n2", "n3", "n4"].map(String::from).to_vec(),
+            ]),
+        )
+        .at(Duration::from_millis(1000), Fault::Heal);
+    let mut sim = Simulation::new(paced()).with_nemesis(nemesis);
+    for i in 0..5 {
+        sim.add_node(format!("n{}", i), binary(env!("CARGO_BIN_EXE_broadcast")));
+    }
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(200)).unwrap();
+
+    let timeout = Duration::from_millis(100);
+    let reply = sim
+        .call(
+            "c1",
+            "n1",
+            json!({"type": "broadcast", "message": 42}),
+            timeout,
+        )
+        .unwrap();
+    assert_eq!("broadcast_ok", reply.unwrap().typ);
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    let read = sim
+        .call("c1", "n2", json!({"type": "read"}), timeout)
+        .unwrap();
+    assert_eq!(json!([]), read.unwrap().extra["messages"]);
+
+    // After healing, n1's gossip reaches n0 and n0 passes it on to the rest
+    sim.run_for(Duration::from_millis(1000)).unwrap();
+    let read = sim
+        .call("c1", "n2", json!({"type": "read"}), timeout)
+        .unwrap();
+    assert_eq!(json!([42]), read.unwrap().extra["messages"]);
+}
+
+#[test]
+fn kv_kafka_send_through_partitioned_node() {
+    let mut sim = Simulation::new(paced());
+    for node in ["n0", "n1"] {
+        sim.add_node(
+            node,
+            binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), &["--kv"]),
+        );
+    }
+    sim.add_service("lin-kv", Kv::lin());
+    sim.add_service("seq-kv", Kv::new(Consistency::Sequential, 0));
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    let send = |msg: u64| json!({"type": "send", "key": "k", "msg": msg});
+    let offset = |body: maelstrom::protocol::MessageBody| body.extra["offset"].as_u64().unwrap();
+
+    sim.fault(Fault::Partition(vec![
+        vec!["n1".to_string()],
+        vec!["n0".to_string(), "lin-kv".to_string(), "seq-kv".to_string()],
+    ]));
+    // n0 can still reach lin-kv
+    let first = sim.call("c1", "n0", send(1), timeout).unwrap().unwrap();
+    assert_eq!(1, offset(first));
+    // n1 can't, and its request is left hanging
+    assert!(sim.call("c2", "n1", send(2), timeout).unwrap().is_none());
+
+    sim.fault(Fault::Heal);
+    let second = sim.call("c2", "n1", send(3), timeout).unwrap().unwrap();
+    assert_eq!(2, offset(second));
+}
+
+#[test]
+fn kv_kafka_history_checks_out() {
+    let mut sim = Simulation::new(Config::default());
+    for node in ["n0", "n1"] {
+        sim.add_node(
+            node,
+            binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), &["--kv"]),
+        );
+    }
+    sim.add_service("lin-kv", Kv::lin());
+    sim.add_service("seq-kv", Kv::lin());
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    for i in 0..4 {
+        let (client, node) = if i % 2 == 0 {
+            ("c1", "n0")
+        } else {
+            ("c2", "n1")
+        };
+        let send = json!({"type": "send", "key": "k", "msg": 100 + i});
+        sim.call(client, node, send, timeout).unwrap().unwrap();
+    }
+    let poll = json!({"type": "poll", "offsets": {"k": 2}});
+    let journal_len = sim.journal().len();
+    let polled = sim.call("c1", "n1", poll, timeout).unwrap().unwrap();
+    assert_eq!(
+        json!([[2, 101], [3, 102], [4, 103]]),
+        polled.extra["msgs"]["k"]
+    );
+    // The poll reads the counter and the entries from offset 2 on, not the whole log
+    let reads: Vec<_> = sim.journal()[journal_len..]
+        .iter()
+        .filter(|d| d.message.dest == "lin-kv")
+        .map(|d| d.message.body.extra["key"].clone())
+        .collect();
+    assert_eq!(
+        vec![
+            json!("offset/k"),
+            json!("log/k/2"),
+            json!("log/k/3"),
+            json!("log/k/4")
+        ],
+        reads
+    );
+    let commit = json!({"type": "commit_offsets", "offsets": {"k": 3}});
+    sim.call("c1", "n0", commit, timeout).unwrap().unwrap();
+    let list = json!({"type": "list_committed_offsets", "keys": ["k"]});
+    sim.call("c2", "n1", list, timeout).unwrap().unwrap();
+
+    let history = History::from_journal(sim.journal());
+    assert_eq!(7, history.ops.len());
+    assert_eq!(Vec::<kafka::Anomaly>::new(), kafka::check(&history));
+    // lin-kv's own history is linearizable
+    let lin_kv = History::from_journal_to(sim.journal(), "lin-kv");
+    assert!(linearizable::check_registers(&lin_kv).is_empty());
+}
+
+#[test]
+fn kv_kafka_concurrent_commits_to_different_keys_survive() {
+    let mut sim = Simulation::new(Config::default());
+    for node in ["n0", "n1"] {
+        sim.add_node(
+            node,
+            binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), &["--kv"]),
+        );
+    }
+    sim.add_service("lin-kv", Kv::lin());
+    sim.add_service("seq-kv", Kv::lin());
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    // Both commits are in flight at once, through different nodes
+    let commit =
+        |key: &str, offset: u64| json!({"type": "commit_offsets", "offsets": {key: offset}});
+    let first = sim.request("c1", "n0", commit("a", 2)).unwrap();
+    let second = sim.request("c2", "n1", commit("b", 3)).unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    assert_eq!(
+        "commit_offsets_ok",
+        sim.reply_to("c1", first).unwrap().body.typ
+    );
+    assert_eq!(
+        "commit_offsets_ok",
+        sim.reply_to("c2", second).unwrap().body.typ
+    );
+
+    // A late commit of an older offset doesn't go back
+    let timeout = Duration::from_millis(500);
+    sim.call("c1", "n1", commit("a", 1), timeout)
+        .unwrap()
+        .unwrap();
+    let list = json!({"type": "list_committed_offsets", "keys": ["a", "b", "c"]});
+    let listed = sim.call("c1", "n0", list, timeout).unwrap().unwrap();
+    assert_eq!(json!({"a": 2, "b": 3}), listed.extra["offsets"]);
+}
+
+#[test]
+fn partitioned_kafka_forwards_to_owners() {
+    let mut sim = Simulation::new(Config::default());
+    let node_ids: Vec<String> = ["n0", "n1", "n2"].map(String::from).to_vec();
+    for node in &node_ids {
+        sim.add_node(node, binary(env!("CARGO_BIN_EXE_multi-node-kafka")));
+    }
+    sim.add_service("lin-kv", Kv::lin());
+    sim.add_service("seq-kv", Kv::lin());
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    let keys: Vec<String> = (0..6).map(|key| key.to_string()).collect();
+    for (i, key) in keys.iter().enumerate() {
+        // Every node gets sends for keys it doesn't own too
+        for node in &node_ids {
+            let send = json!({"type": "send", "key": key, "msg": i});
+            sim.call("c1", node, send, timeout).unwrap().unwrap();
+        }
+    }
+    let offsets: serde_json::Map<_, _> = keys.iter().map(|key| (key.clone(), json!(2))).collect();
+    let commit = json!({"type": "commit_offsets", "offsets": offsets});
+    sim.call("c2", "n0", commit, timeout).unwrap().unwrap();
+    for node in &node_ids {
+        let poll = json!({"type": "poll", "offsets": {"0": 1, "5": 3}});
+        let polled = sim.call("c2", node, poll, timeout).unwrap().unwrap();
+        assert_eq!(
+            json!({"0": [[1, 0], [2, 0], [3, 0]], "5": [[3, 5]]}),
+            polled.extra["msgs"]
+        );
+        let list = json!({"type": "list_committed_offsets", "keys": keys});
+        let listed = sim.call("c2", node, list, timeout).unwrap().unwrap();
+        assert_eq!(json!(offsets), listed.extra["offsets"]);
+    }
+
+    // Owners keep their keys to themselves, so nothing goes through the KV services
+    let kv_messages = sim
+        .journal()
+        .iter()
+        .filter(|d| d.message.dest.ends_with("-kv"))
+        .count();
+    assert_eq!(0, kv_messages);
+    let history = History::from_journal(sim.journal());
+    assert_eq!(Vec::<kafka::Anomaly>::new(), kafka::check(&history));
+}
+
+#[test]
+fn kafka_consumer_groups_keep_their_own_offsets() {
+    for args in [&[][..], &["--kv"][..]] {
+        let mut sim = Simulation::new(Config::default());
+        for node in ["n0", "n1", "n2"] {
+            sim.add_node(
+                node,
+                binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), args),
+            );
+        }
+        sim.add_service("lin-kv", Kv::lin());
+        sim.init().unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        let timeout = Duration::from_millis(500);
+        let commit = json!({"type": "commit_offsets", "offsets": {"0": 2, "5": 3}});
+        sim.call("c1", "n0", commit, timeout).unwrap().unwrap();
+        // Through another node, which forwards it to the owners along with the group when the
+        // keys are partitioned
+        let commit = json!({"type": "commit_offsets", "offsets": {"0": 1, "5": 1}, "group": "g"});
+        sim.call("c2", "n1", commit, timeout).unwrap().unwrap();
+
+        let list = |group: Option<&str>| {
+            let mut list = json!({"type": "list_committed_offsets", "keys": ["0", "5", "7"]});
+            if let Some(group) = group {
+                list["group"] = json!(group);
+            }
+            list
+        };
+        let listed = sim
+            .call("c3", "n2", list(Some("g")), timeout)
+            .unwrap()
+            .unwrap();
+        assert_eq!(json!({"0": 1, "5": 1}), listed.extra["offsets"]);
+        // Requests without a group have the default one, which the other commit didn't touch
+        let listed = sim.call("c3", "n2", list(None), timeout).unwrap().unwrap();
+        assert_eq!(json!({"0": 2, "5": 3}), listed.extra["offsets"]);
+        let listed = sim
+            .call("c3", "n0", list(Some("h")), timeout)
+            .unwrap()
+            .unwrap();
+        assert_eq!(json!({}), listed.extra["offsets"]);
+    }
+}
+
+#[test]
+fn kafka_send_batch_spans_owners() {
+    for args in [&[][..], &["--kv"][..]] {
+        let mut sim = Simulation::new(Config::default());
+        for node in ["n0", "n1", "n2"] {
+            sim.add_node(
+                node,
+                binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), args),
+            );
+        }
+        sim.add_service("lin-kv", Kv::lin());
+        sim.init().unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        let timeout = Duration::from_millis(500);
+        let send = json!({"type": "send", "key": "0", "msg": 1});
+        sim.call("c1", "n1", send, timeout).unwrap().unwrap();
+        let batch = json!({"type": "send_batch", "msgs": {"0": [2, 3], "1": [4], "2": [5, 6, 7]}});
+        let sent = sim.call("c1", "n0", batch, timeout).unwrap().unwrap();
+        assert_eq!(
+            json!({"0": [2, 3], "1": [1], "2": [1, 2, 3]}),
+            sent.extra["offsets"]
+        );
+
+        let poll = json!({"type": "poll", "offsets": {"0": 1, "1": 1, "2": 2}});
+        let polled = sim.call("c2", "n2", poll, timeout).unwrap().unwrap();
+        assert_eq!(
+            json!({"0": [[1, 1], [2, 2], [3, 3]], "1": [[1, 4]], "2": [[2, 6], [3, 7]]}),
+            polled.extra["msgs"]
+        );
+        let history = History::from_journal(sim.journal());
+        assert_eq!(Vec::<kafka::Anomaly>::new(), kafka::check(&history));
+        if args.is_empty() {
+            continue;
+        }
+        // One CaS reserves the offsets of each key of the batch
+        let cas_on_counters = sim
+            .journal()
+            .iter()
+            .filter(|d| d.message.dest == "lin-kv" && d.message.body.typ == "cas")
+            .filter(|d| {
+                d.message.body.extra["key"]
+                    .as_str()
+                    .is_some_and(|key| key.starts_with("offset/"))
+            })
+            .count();
+        assert_eq!(4, cas_on_counters);
+    }
+}
+
+#[test]
+fn kafka_messages_can_be_any_json() {
+    for args in [&[][..], &["--kv"][..]] {
+        let mut sim = Simulation::new(Config::default());
+        for node in ["n0", "n1"] {
+            sim.add_node(
+                node,
+                binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), args),
+            );
+        }
+        sim.add_service("lin-kv", Kv::lin());
+        sim.init().unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        let timeout = Duration::from_millis(500);
+        let msgs = [json!({"id": "a", "tags": [1, null]}), json!("b"), json!(3)];
+        for (node, msg) in ["n0", "n1", "n0"].into_iter().zip(&msgs) {
+            let send = json!({"type": "send", "key": "k", "msg": msg});
+            sim.call("c1", node, send, timeout).unwrap().unwrap();
+        }
+        let poll = json!({"type": "poll", "offsets": {"k": 1}});
+        let polled = sim.call("c2", "n1", poll, timeout).unwrap().unwrap();
+        assert_eq!(
+            json!({"k": [[1, msgs[0]], [2, msgs[1]], [3, msgs[2]]]}),
+            polled.extra["msgs"]
+        );
+    }
+}
+
+#[test]
+fn single_node_kafka_history_checks_out() {
+    let mut sim = Simulation::new(Config::default());
+    sim.add_node("n0", binary(env!("CARGO_BIN_EXE_single-node-kafka")));
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    for (i, key) in ["k", "k", "j"].into_iter().enumerate() {
+        let send = json!({"type": "send", "key": key, "msg": 100 + i});
+        sim.call("c1", "n0", send, timeout).unwrap().unwrap();
+    }
+    let poll = json!({"type": "poll", "offsets": {"k": 2, "j": 1, "missing": 1}});
+    let polled = sim.call("c1", "n0", poll, timeout).unwrap().unwrap();
+    assert_eq!(
+        json!({"k": [[2, 101]], "j": [[1, 102]]}),
+        polled.extra["msgs"]
+    );
+
+    let history = History::from_journal(sim.journal());
+    assert_eq!(Vec::<kafka::Anomaly>::new(), kafka::check(&history));
+}
+
+#[test]
+fn kafka_poll_from_truncated_offset() {
+    let mut sim = Simulation::new(Config::default());
+    let bin = env!("CARGO_BIN_EXE_single-node-kafka");
+    sim.add_node("n0", binary_with_args(bin, &["--retain-messages", "2"]));
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    for msg in 0..4 {
+        let send = json!({"type": "send", "key": "k", "msg": msg});
+        sim.call("c1", "n0", send, timeout).unwrap().unwrap();
+    }
+    let poll = json!({"type": "poll", "offsets": {"k": 1}});
+    let polled = sim.call("c1", "n0", poll, timeout).unwrap().unwrap();
+    assert_eq!(json!({"k": [[3, 2], [4, 3]]}), polled.extra["msgs"]);
+    assert_eq!(json!({"k": 3}), polled.extra["earliest_offsets"]);
+}
+
+#[test]
+fn kv_kafka_retention_reclaims_entries() {
+    let mut sim = Simulation::new(Config::default());
+    let args = ["--kv", "--retain-messages", "2", "--compact-committed"];
+    for node in ["n0", "n1"] {
+        sim.add_node(
+            node,
+            binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), &args),
+        );
+    }
+    sim.add_service("lin-kv", Kv::lin());
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    for (msg, node) in [(0, "n0"), (1, "n1"), (2, "n0"), (3, "n1")] {
+        let send = json!({"type": "send", "key": "k", "msg": msg});
+        sim.call("c1", node, send, timeout).unwrap().unwrap();
+    }
+    for msg in 0..2 {
+        let send = json!({"type": "send", "key": "j", "msg": msg});
+        sim.call("c1", "n0", send, timeout).unwrap().unwrap();
+    }
+    let commit = json!({"type": "commit_offsets", "offsets": {"j": 2}});
+    sim.call("c1", "n1", commit, timeout).unwrap().unwrap();
+
+    let poll = json!({"type": "poll", "offsets": {"k": 1, "j": 1}});
+    let polled = sim.call("c2", "n0", poll, timeout).unwrap().unwrap();
+    assert_eq!(
+        json!({"k": [[3, 2], [4, 3]], "j": [[2, 1]]}),
+        polled.extra["msgs"]
+    );
+    assert_eq!(json!({"k": 3, "j": 2}), polled.extra["earliest_offsets"]);
+    // What's below the earliest offsets doesn't hold on to its message anymore
+    for key in ["log/k/1", "log/k/2", "log/j/1"] {
+        let read = json!({"type": "read", "key": key});
+        let entry = sim.call("c3", "lin-kv", read, timeout).unwrap().unwrap();
+        assert_eq!(json!("dropped"), entry.extra["value"]);
+    }
+}
+
+#[test]
+fn kafka_poll_is_paginated() {
+    let mut sim = Simulation::new(Config::default());
+    let bin = env!("CARGO_BIN_EXE_multi-node-kafka");
+    let args = ["--poll-max-per-key", "2", "--poll-max-total", "3"];
+    for node in ["n0", "n1"] {
+        sim.add_node(node, binary_with_args(bin, &args));
+    }
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    for key in ["a", "b"] {
+        for msg in 0..3 {
+            let send = json!({"type": "send", "key": key, "msg": msg});
+            sim.call("c1", "n0", send, timeout).unwrap().unwrap();
+        }
+    }
+    let poll = json!({"type": "poll", "offsets": {"a": 1, "b": 1}});
+    let polled = sim.call("c1", "n1", poll, timeout).unwrap().unwrap();
+    // Two from one key and the one left for the other, each resuming after what it got
+    let mut counts = Vec::new();
+    for key in ["a", "b"] {
+        let msgs = polled.extra["msgs"][key].as_array().unwrap();
+        assert_eq!(json!(msgs.len() + 1), polled.extra["next_offsets"][key]);
+        counts.push(msgs.len());
+    }
+    counts.sort();
+    assert_eq!(vec![1, 2], counts);
+
+    // The request can ask for fewer
+    let poll = json!({"type": "poll", "offsets": {"a": 2}, "max_messages": 1});
+    let polled = sim.call("c1", "n0", poll, timeout).unwrap().unwrap();
+    assert_eq!(json!({"a": [[2, 1]]}), polled.extra["msgs"]);
+    assert_eq!(json!({"a": 3}), polled.extra["next_offsets"]);
+}
+
+#[test]
+fn single_node_kafka_survives_restart() {
+    let dir = std::env::temp_dir().join(format!("kafka-{}", ulid::Ulid::new()));
+    let bin = env!("CARGO_BIN_EXE_single-node-kafka");
+    let args = ["--data-dir", dir.to_str().unwrap()];
+    // Every send and commit waits for fsync, however long that takes here
+    let node = || binary_with_args(bin, &args);
+    let timeout = Duration::from_millis(100);
+
+    let mut sim = Simulation::new(Config::default());
+    sim.add_node("n0", node());
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    for msg in 0..3 {
+        let send = json!({"type": "send", "key": "k", "msg": msg});
+        sim.call("c1", "n0", send, timeout).unwrap().unwrap();
+    }
+    let commit = json!({"type": "commit_offsets", "offsets": {"k": 2}});
+    sim.call("c1", "n0", commit, timeout).unwrap().unwrap();
+    // Kills the node
+    drop(sim);
+
+    let mut sim = Simulation::new(Config::default());
+    sim.add_node("n0", node());
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    let poll = json!({"type": "poll", "offsets": {"k": 2}});
+    let polled = sim.call("c1", "n0", poll, timeout).unwrap().unwrap();
+    assert_eq!(json!({"k": [[2, 1], [3, 2]]}), polled.extra["msgs"]);
+    let list = json!({"type": "list_committed_offsets", "keys": ["k"]});
+    let listed = sim.call("c1", "n0", list, timeout).unwrap().unwrap();
+    assert_eq!(json!({"k": 2}), listed.extra["offsets"]);
+    let send = json!({"type": "send", "key": "k", "msg": 3});
+    let sent = sim.call("c1", "n0", send, timeout).unwrap().unwrap();
+    assert_eq!(json!(4), sent.extra["offset"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn kafka_retried_sends_keep_their_offset() {
+    for args in [&[][..], &["--kv"][..]] {
+        let mut sim = Simulation::new(Config::default());
+        for node in ["n0", "n1"] {
+            sim.add_node(
+                node,
+                binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), args),
+            );
+        }
+        sim.add_service("lin-kv", Kv::lin());
+        sim.init().unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        let timeout = Duration::from_millis(500);
+        let send = |msg: u64, seq: u64| json!({"type": "send", "key": "k", "msg": msg, "producer_id": "p", "seq": seq});
+        let offset = |body: maelstrom::protocol::MessageBody| body.extra["offset"].clone();
+        let first = sim.call("c1", "n0", send(10, 1), timeout).unwrap().unwrap();
+        assert_eq!(json!(1), offset(first));
+        // The client timed out and tries again, through another node
+        let retry = sim.call("c1", "n1", send(10, 1), timeout).unwrap().unwrap();
+        assert_eq!(json!(1), offset(retry));
+        let next = sim.call("c1", "n1", send(11, 2), timeout).unwrap().unwrap();
+        assert_eq!(json!(2), offset(next));
+
+        let poll = json!({"type": "poll", "offsets": {"k": 1}});
+        let polled = sim.call("c2", "n0", poll, timeout).unwrap().unwrap();
+        assert_eq!(json!({"k": [[1, 10], [2, 11]]}), polled.extra["msgs"]);
+
+        let half = json!({"type": "send", "key": "k", "msg": 12, "seq": 3});
+        let err = sim.call("c1", "n0", half, timeout).unwrap().unwrap();
+        assert_eq!(json!(12), err.extra["code"]);
+
+        if args.is_empty() {
+            continue;
+        }
+        // A first attempt at seq 3 that died after taking offset 3, before writing its message
+        let died = [
+            json!({"type": "write", "key": "offset/k", "value": 3}),
+            json!({"type": "write", "key": "producer/k/\"p\"", "value": [[1, 1], [2, 2], [3, 3]]}),
+        ];
+        for write in died {
+            sim.call("c3", "lin-kv", write, timeout).unwrap().unwrap();
+        }
+        let retry = sim.call("c1", "n1", send(12, 3), timeout).unwrap().unwrap();
+        assert_eq!(json!(3), offset(retry));
+        let poll = json!({"type": "poll", "offsets": {"k": 3}});
+        let polled = sim.call("c2", "n0", poll, timeout).unwrap().unwrap();
+        assert_eq!(json!({"k": [[3, 12]]}), polled.extra["msgs"]);
+    }
+}
+
+/// lin-kv, except that the first write to each of `fail` errors out without writing anything
+struct FailingWrites {
+    kv: Kv,
+    fail: Vec<&'static str>,
+}
+
+impl Process for FailingWrites {
+    fn handle(&mut self, now: Duration, msg: Message, out: &mut Vec<Message>) -> Result<()> {
+        let key = msg.body.extra.get("key").and_then(Value::as_str);
+        let failing = self.fail.iter().position(|fail| Some(*fail) == key);
+        match failing {
+            Some(i) if msg.body.typ == "cas" || msg.body.typ == "write" => {
+                self.fail.remove(i);
+                let error = json!({"type": "error", "code": 11, "text": "write failed"});
+                out.push(reply(&msg, error)?);
+                Ok(())
+            }
+            _ => self.kv.handle(now, msg, out),
+        }
+    }
+}
+
+#[test]
+fn kv_kafka_polls_get_past_failed_writes() {
+    let mut sim = Simulation::new(Config::default());
+    for node in ["n0", "n1"] {
+        sim.add_node(
+            node,
+            binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), &["--kv"]),
+        );
+    }
+    let kv = FailingWrites {
+        kv: Kv::lin(),
+        fail: vec!["log/k/2", "log/k/4"],
+    };
+    sim.add_service("lin-kv", kv);
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    let send = |msg: u64| json!({"type": "send", "key": "k", "msg": msg});
+    let code = |body: maelstrom::protocol::MessageBody| body.extra["code"].clone();
+    sim.call("c1", "n0", send(10), timeout).unwrap().unwrap();
+    let failed = sim.call("c1", "n0", send(11), timeout).unwrap().unwrap();
+    assert_eq!(json!(11), code(failed));
+    let batch = json!({"type": "send_batch", "msgs": {"k": [12, 13]}});
+    let failed = sim.call("c1", "n1", batch, timeout).unwrap().unwrap();
+    assert_eq!(json!(11), code(failed));
+    let sent = sim.call("c1", "n1", send(14), timeout).unwrap().unwrap();
+    assert_eq!(json!(5), sent.extra["offset"]);
+
+    // The offsets whose message didn't make it are skipped rather than waited for
+    let poll = json!({"type": "poll", "offsets": {"k": 1}});
+    let polled = sim.call("c2", "n0", poll, timeout).unwrap().unwrap();
+    assert_eq!(
+        json!({"k": [[1, 10], [3, 12], [5, 14]]}),
+        polled.extra["msgs"]
+    );
+}
+
+#[test]
+fn kv_kafka_retries_never_write_over_messages() {
+    let mut sim = Simulation::new(Config::default());
+    for node in ["n0", "n1"] {
+        sim.add_node(
+            node,
+            binary_with_args(env!("CARGO_BIN_EXE_multi-node-kafka"), &["--kv"]),
+        );
+    }
+    let kv = FailingWrites {
+        kv: Kv::lin(),
+        fail: vec!["log/k/1"],
+    };
+    sim.add_service("lin-kv", kv);
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let timeout = Duration::from_millis(500);
+    let send = |msg: u64, seq: u64| json!({"type": "send", "key": "k", "msg": msg, "producer_id": "p", "seq": seq});
+    let failed = sim.call("c1", "n0", send(10, 1), timeout).unwrap().unwrap();
+    assert_eq!(json!(11), failed.extra["code"]);
+    // Offset 1 was given up on, so the retry needs another one
+    let retry = sim.call("c1", "n1", send(10, 1), timeout).unwrap().unwrap();
+    assert_eq!(json!(2), retry.extra["offset"]);
+    // A retry that doesn't match what was sent gets the offset of the first attempt, whose
+    // message stays
+    let mismatched = sim.call("c1", "n0", send(11, 1), timeout).unwrap().unwrap();
+    assert_eq!(json!(2), mismatched.extra["offset"]);
+
+    let poll = json!({"type": "poll", "offsets": {"k": 1}});
+    let polled = sim.call("c2", "n0", poll, timeout).unwrap().unwrap();
+    assert_eq!(json!({"k": [[2, 10]]}), polled.extra["msgs"]);
+}
+
+/// A txn request of `ops` reads and writes over keys below `keys`. Writes take their values from
+/// `next_value`, so that every write in a test has a value of its own.
+fn random_txn(sim: &mut Simulation, keys: u64, ops: usize, next_value: &mut usize) -> Value {
+    let mut txn = Vec::new();
+    for _ in 0..ops {
+        let key = sim.rng().below(keys);
+        if sim.rng().chance(0.5) {
+            txn.push(json!(["r", key, null]));
+        } else {
+            *next_value += 1;
+            txn.push(json!(["w", key, *next_value]));
+        }
+    }
+    json!({"type": "txn", "txn": txn})
+}
+
+#[test]
+fn txn_history_from_one_client_is_serializable() {
+    let mut sim = Simulation::new(Config::default());
+    sim.add_node("n0", binary(env!("CARGO_BIN_EXE_txn")));
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    let txns = [
+        json!([["r", 1, null], ["w", 1, 1]]),
+        json!([["r", 1, null], ["w", 2, 1]]),
+        json!([["r", 2, null], ["w", 2, 2], ["r", 1, null]]),
+    ];
+    for txn in txns {
+        sim.call(
+            "c1",
+            "n0",
+            json!({"type": "txn", "txn": txn}),
+            Duration::from_secs(1),
+        )
+        .unwrap()
+        .expect("txn should be answered");
+    }
+    let history = History::from_journal(sim.journal());
+    assert_eq!(3, history.ops.len());
+    assert_eq!(
+        Ok(()),
+        txn::check_isolation(&history, IsolationLevel::Serializable)
+    );
+}
+
+#[test]
+fn txn_is_read_committed_and_available_under_partitions() {
+    let nemesis =
+        Nemesis::new().partition_every(Duration::from_millis(300), Duration::from_secs(1));
+    let mut sim = Simulation::new(paced()).with_nemesis(nemesis);
+    for node in ["n0", "n1"] {
+        // Replies from the node follow deliveries closely, no need to wait long for them
+        let node_binary = binary(env!("CARGO_BIN_EXE_txn"));
+        sim.add_node(node, node_binary);
+    }
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    // Concurrent txns over a few keys, every write with a value of its own
+    let mut next_value = 0;
+    let mut requests = Vec::new();
+    for _ in 0..10 {
+        for (client, node) in [("c1", "n0"), ("c2", "n0"), ("c3", "n1"), ("c4", "n1")] {
+            let body = random_txn(&mut sim, 3, 4, &mut next_value);
+            requests.push((client, sim.request(client, node, body).unwrap()));
+        }
+        sim.run_for(Duration::from_millis(100)).unwrap();
+    }
+    // Every txn was answered, partitioned or not
+    for (client, msg_id) in requests {
+        let reply = sim
+            .reply_to(client, msg_id)
+            .expect("txn should be answered");
+        assert_eq!("txn_ok", reply.body.typ);
+    }
+
+    // Once healed, gossip brings the nodes to the same values
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    let read_all = json!({"type": "txn", "txn": [["r", 0, null], ["r", 1, null], ["r", 2, null]]});
+    let timeout = Duration::from_millis(100);
+    let on_n0 = sim.call("c5", "n0", read_all.clone(), timeout).unwrap();
+    let on_n1 = sim.call("c5", "n1", read_all, timeout).unwrap();
+    assert_eq!(on_n0.unwrap().extra["txn"], on_n1.unwrap().extra["txn"]);
+
+    let history = History::from_journal(sim.journal());
+    assert_eq!(
+        Ok(()),
+        txn::check_isolation(&history, IsolationLevel::ReadCommitted)
+    );
+}
+
+#[test]
+fn txn_writes_are_only_seen_once_committed() {
+    let mut sim = Simulation::new(paced());
+    for node in ["n0", "n1"] {
+        sim.add_node(node, binary(env!("CARGO_BIN_EXE_txn")));
+    }
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    let timeout = Duration::from_secs(1);
+    // The txn sees its own writes, and nobody else sees the intermediate one
+    let txn = json!({"type": "txn", "txn": [["w", 1, 1], ["r", 1, null], ["w", 1, 2]]});
+    let reply = sim.call("c1", "n0", txn, timeout).unwrap().unwrap();
+    assert_eq!(
+        json!([["w", 1, 1], ["r", 1, 1], ["w", 1, 2]]),
+        reply.extra["txn"]
+    );
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    let txn = json!({"type": "txn", "txn": [["r", 1, null], ["r", 2, null]]});
+    let reply = sim.call("c2", "n1", txn, timeout).unwrap().unwrap();
+    assert_eq!(json!([["r", 1, 2], ["r", 2, null]]), reply.extra["txn"]);
+
+    // Only the last value of each key is gossiped
+    let gossiped: Vec<_> = sim
+        .journal()
+        .iter()
+        .filter(|d| d.message.body.typ == "gossip")
+        .flat_map(|d| {
+            d.message.body.extra["registers"]
+                .as_array()
+                .unwrap()
+                .clone()
+        })
+        .map(|register| register[1]["value"].clone())
+        .collect();
+    assert!(!gossiped.is_empty());
+    assert!(gossiped.iter().all(|value| *value == json!(2)));
+}
+
+#[test]
+fn single_node_txn_is_serializable() {
+    let mut sim = Simulation::new(Config::default());
+    let node_binary = binary(env!("CARGO_BIN_EXE_single-node-txn"));
+    sim.add_node("n0", node_binary);
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    // Interleaved txns from several clients, rewriting the same few keys
+    let mut next_value = 0;
+    let mut requests = Vec::new();
+    for _ in 0..10 {
+        for client in ["c1", "c2", "c3", "c4"] {
+            let body = random_txn(&mut sim, 3, 4, &mut next_value);
+            requests.push((client, sim.request(client, "n0", body).unwrap()));
+        }
+        sim.run_for(Duration::from_millis(20)).unwrap();
+    }
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    for (client, msg_id) in requests {
+        let reply = sim
+            .reply_to(client, msg_id)
+            .expect("txn should be answered");
+        assert_eq!("txn_ok", reply.body.typ);
+    }
+
+    // The last write wins
+    let timeout = Duration::from_secs(1);
+    let txn = json!({"type": "txn", "txn": [["w", 7, 1], ["w", 7, 2]]});
+    sim.call("c1", "n0", txn, timeout).unwrap().unwrap();
+    let txn = json!({"type": "txn", "txn": [["r", 7, null]]});
+    let reply = sim.call("c2", "n0", txn, timeout).unwrap().unwrap();
+    assert_eq!(json!([["r", 7, 2]]), reply.extra["txn"]);
+
+    let history = History::from_journal(sim.journal());
+    assert_eq!(
+        Ok(()),
+        txn::check_isolation(&history, IsolationLevel::Serializable)
+    );
+}
+
+#[test]
+fn txn_snapshot_isolation() {
+    // Both nodes run transactions on the same MVCC engine, the txn one across two nodes
+    let single = (env!("CARGO_BIN_EXE_single-node-txn"), &["n0"][..]);
+    let replicated = (env!("CARGO_BIN_EXE_txn"), &["n0", "n1"][..]);
+    for (bin, nodes) in [single, replicated] {
+        let mut sim = Simulation::new(Config::default());
+        for node in nodes {
+            let node_binary = binary_with_args(bin, &["--isolation", "snapshot"]);
+            sim.add_node(*node, node_binary);
+        }
+        sim.init().unwrap();
+        sim.run_for(Duration::from_millis(10)).unwrap();
+
+        // Two clients, on a node of their own if there are two, racing to write the same keys
+        let mut next_value = 0;
+        let mut requests = Vec::new();
+        for _ in 0..5 {
+            for (i, client) in ["c1", "c2"].into_iter().enumerate() {
+                let body = random_txn(&mut sim, 3, 4, &mut next_value);
+                let node = nodes[i % nodes.len()];
+                requests.push((client, sim.request(client, node, body).unwrap()));
+            }
+            sim.run_for(Duration::from_millis(50)).unwrap();
+        }
+        for (client, msg_id) in requests {
+            let reply = sim
+                .reply_to(client, msg_id)
+                .expect("txn should be answered");
+            let conflict = reply.body.typ == "error" && reply.body.extra["code"] == json!(30);
+            assert!(reply.body.typ == "txn_ok" || conflict);
+        }
+
+        // Txns writing the same key on both nodes at once, before gossip can tell either about
+        // the other: only the first to commit gets through
+        if nodes.len() > 1 {
+            let write = |value| json!({"type": "txn", "txn": [["r", 7, null], ["w", 7, value]]});
+            let racing = [
+                ("c4", sim.request("c4", "n0", write(2000)).unwrap()),
+                ("c5", sim.request("c5", "n1", write(2001)).unwrap()),
+            ];
+            sim.run_for(Duration::from_millis(50)).unwrap();
+            let committed = racing
+                .iter()
+                .filter(|(client, msg_id)| {
+                    let reply = sim
+                        .reply_to(client, *msg_id)
+                        .expect("txn should be answered");
+                    reply.body.typ == "txn_ok"
+                })
+                .count();
+            assert_eq!(1, committed);
+        }
+
+        // A txn's reads come from one snapshot, and see its own writes
+        let txn = json!({"type": "txn", "txn": [["w", 5, 1000], ["r", 5, null], ["r", 6, null]]});
+        let reply = sim
+            .call("c3", nodes[0], txn, Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(
+            json!([["w", 5, 1000], ["r", 5, 1000], ["r", 6, null]]),
+            reply.unwrap().extra["txn"]
+        );
+
+        let history = History::from_journal(sim.journal());
+        assert_eq!(
+            Ok(()),
+            txn::check_isolation(&history, IsolationLevel::SnapshotIsolation)
+        );
+    }
+}
+
+#[test]
+fn txn_two_phase_commit_is_serializable_under_partitions() {
+    // Prepares lost to a partition abort their txn, lost decisions leave the owner in doubt
+    // until it asks
+    let nemesis =
+        Nemesis::new().partition_every(Duration::from_millis(300), Duration::from_millis(1500));
+    let mut sim = Simulation::new(paced()).with_nemesis(nemesis);
+    let nodes = ["n0", "n1", "n2"];
+    for node in nodes {
+        let node_binary =
+            binary_with_args(env!("CARGO_BIN_EXE_txn"), &["--isolation", "serializable"]);
+        sim.add_node(node, node_binary);
+    }
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    // Concurrent txns over keys owned by all three nodes
+    let mut next_value = 0;
+    let mut requests = Vec::new();
+    for _ in 0..10 {
+        for (i, client) in ["c1", "c2", "c3", "c4"].into_iter().enumerate() {
+            let body = random_txn(&mut sim, 6, 3, &mut next_value);
+            let node = nodes[i % nodes.len()];
+            requests.push((client, sim.request(client, node, body).unwrap()));
+        }
+        sim.run_for(Duration::from_millis(150)).unwrap();
+    }
+    // Long enough for prepares to time out and owners to settle what they were in doubt about
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    let mut committed = 0;
+    for (client, msg_id) in requests {
+        let reply = sim
+            .reply_to(client, msg_id)
+            .expect("txn should be answered");
+        match reply.body.typ.as_str() {
+            "txn_ok" => committed += 1,
+            // Aborted: conflicts, or an owner out of reach
+            _ => assert!([json!(30), json!(11)].contains(&reply.body.extra["code"])),
+        }
+    }
+    assert!(committed > 0);
+
+    // Nothing is left locked, and every coordinator reads the same values off the owners
+    let timeout = Duration::from_millis(500);
+    let write_all = json!({"type": "txn", "txn": (0..6).map(|key| json!(["w", key, 1000])).collect::<Vec<_>>()});
+    let reply = sim.call("c5", "n0", write_all, timeout).unwrap().unwrap();
+    assert_eq!("txn_ok", reply.typ);
+    let read_all = json!({"type": "txn", "txn": (0..6).map(|key| json!(["r", key, null])).collect::<Vec<_>>()});
+    for node in nodes {
+        let reply = sim.call("c5", node, read_all.clone(), timeout).unwrap();
+        let expected: Vec<_> = (0..6).map(|key| json!(["r", key, 1000])).collect();
+        assert_eq!(json!(expected), reply.unwrap().extra["txn"]);
+    }
+
+    let history = History::from_journal(sim.journal());
+    assert_eq!(
+        Ok(()),
+        txn::check_isolation(&history, IsolationLevel::Serializable)
+    );
+}
+
+#[test]
+fn txn_in_doubt_prepares_are_aborted_by_their_coordinator() {
+    let mut sim = Simulation::new(paced());
+    for node in ["n0", "n1"] {
+        let node_binary =
+            binary_with_args(env!("CARGO_BIN_EXE_txn"), &["--isolation", "serializable"]);
+        sim.add_node(node, node_binary);
+    }
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    // A txn of n0's that prepared on n1, the owner of key 1, and was never decided
+    let timeout = Duration::from_millis(500);
+    let prepare = json!({"type": "prepare", "id": [1000, "n0"], "keys": [1], "writes": [[1, 10]]});
+    let reply = sim.call("c1", "n1", prepare, timeout).unwrap().unwrap();
+    assert_eq!(json!([[1, null]]), reply.extra["values"]);
+    let txn = json!({"type": "txn", "txn": [["r", 1, null]]});
+    let reply = sim.call("c2", "n0", txn.clone(), timeout).unwrap().unwrap();
+    assert_eq!(json!(30), reply.extra["code"]);
+
+    // Once in doubt, n1 asks n0, which never decided and so aborts it
+    sim.run_for(Duration::from_millis(1500)).unwrap();
+    let status = sim
+        .journal()
+        .iter()
+        .find(|d| d.message.body.typ == "status_ok")
+        .expect("n1 should ask n0 about the txn");
+    assert_eq!(json!("abort"), status.message.body.extra["decision"]);
+    let reply = sim.call("c2", "n0", txn, timeout).unwrap().unwrap();
+    assert_eq!(json!([["r", 1, null]]), reply.extra["txn"]);
+}
+
+#[test]
+fn bad_requests_get_error_replies() {
+    let mut sim = Simulation::new(Config::default());
+    sim.add_node("n0", binary(env!("CARGO_BIN_EXE_txn")));
+    sim.init().unwrap();
+    sim.run_for(Duration::from_millis(10)).unwrap();
+
+    let timeout = Duration::from_secs(1);
+    let write_without_value = json!({"type": "txn", "txn": [["w", 1, null]]});
+    let reply = sim.call("c1", "n0", write_without_value, timeout).unwrap();
+    let reply = reply.expect("malformed txn should be answered");
+    assert_eq!("error", reply.typ);
+    assert_eq!(json!(12), reply.extra["code"]);
+    let reply = sim
+        .call("c1", "n0", json!({"type": "echo"}), timeout)
+        .unwrap();
+    assert_eq!(json!(10), reply.unwrap().extra["code"]);
+
+    // The node is still up
+    let txn = json!({"type": "txn", "txn": [["w", 1, 2], ["r", 1, null]]});
+    let reply = sim.call("c1", "n0", txn, timeout).unwrap();
+    assert_eq!(
+        json!([["w", 1, 2], ["r", 1, 2]]),
+        reply.unwrap().extra["txn"]
+    );
+}